@@ -1,18 +1,55 @@
+use crate::disassembler;
 use crate::instructions::{Opcodes, Instruction};
+use crate::interrupts::Interrupt;
+use crate::error::ExecError;
+use crate::fuses::Fuses;
+use crate::history::Access;
+use crate::reset::{ResetCause, CLKPR};
+use crate::selfprog::{SelfProgramming, SPMCSR};
+use crate::fuses::FLASH_WORDS;
+use crate::sleep::{SleepMode, SleepState};
+use crate::usart::UDR0;
+use crate::watch::{WatchHit, Watchpoint};
 use std::collections::HashMap;
 
 // Status register
 #[allow(non_snake_case)]
 #[derive(Default, Debug)]
 pub struct SREG {
-    I: bool, // Global Interrupt Enable
-    T: bool, // Bit Copy Storage
-    H: bool, // Half Carry Flag
-    S: bool, // Sign Bit
-    V: bool, // Two's Compliment Overflow Flag
-    N: bool, // Negative Flag
-    Z: bool, // Zero Flag
-    C: bool, // Carry Flag
+    pub I: bool, // Global Interrupt Enable
+    pub T: bool, // Bit Copy Storage
+    pub H: bool, // Half Carry Flag
+    pub S: bool, // Sign Bit
+    pub V: bool, // Two's Compliment Overflow Flag
+    pub N: bool, // Negative Flag
+    pub Z: bool, // Zero Flag
+    pub C: bool, // Carry Flag
+}
+
+impl SREG {
+    pub fn to_byte(&self) -> u8 {
+        (self.I as u8) << 7
+            | (self.T as u8) << 6
+            | (self.H as u8) << 5
+            | (self.S as u8) << 4
+            | (self.V as u8) << 3
+            | (self.N as u8) << 2
+            | (self.Z as u8) << 1
+            | self.C as u8
+    }
+
+    pub fn from_byte(value: u8) -> SREG {
+        SREG {
+            I: value & 0x80 != 0,
+            T: value & 0x40 != 0,
+            H: value & 0x20 != 0,
+            S: value & 0x10 != 0,
+            V: value & 0x08 != 0,
+            N: value & 0x04 != 0,
+            Z: value & 0x02 != 0,
+            C: value & 0x01 != 0,
+        }
+    }
 }
 
 // Stack Pointer
@@ -24,27 +61,44 @@ pub struct StackPointer {
 }
 
 impl StackPointer {
-    pub fn getCurrentAddr(&self) -> u16 {
-        self.SPH as u16 | self.SPL as u16
+    pub fn current_addr(&self) -> u16 {
+        (self.SPH as u16) << 8 | self.SPL as u16
     }
 
     pub fn decrement(&mut self, n: u16) {
-        let mut currentAddr = self.SPH as u16 | self.SPL as u16;
-        currentAddr -= n;
+        let current_addr = self.current_addr().wrapping_sub(n);
 
-        self.SPL = (currentAddr & 0xFF) as u8;
-        self.SPH = (currentAddr >> 8) as u8;
+        self.SPL = (current_addr & 0xFF) as u8;
+        self.SPH = (current_addr >> 8) as u8;
     }
 
     pub fn increment(&mut self, n: u16) {
-        let mut currentAddr = self.SPH as u16 | self.SPL as u16;
-        currentAddr += n;
+        let current_addr = self.current_addr().wrapping_add(n);
 
-        self.SPL = (currentAddr & 0xFF) as u8;
-        self.SPH = (currentAddr >> 8) as u8;
+        self.SPL = (current_addr & 0xFF) as u8;
+        self.SPH = (current_addr >> 8) as u8;
     }
 }
 
+// Last address of internal SRAM
+pub const RAMEND: u16 = 0x08FF;
+
+// Bytes of EEPROM on the ATmega328P
+pub const EEPROM_SIZE: usize = 1024;
+
+// Extended Z-pointer register for ELPM (I/O address)
+pub const RAMPZ: usize = 0x3B;
+
+// Data space addresses of the core registers living in the I/O space
+const SPL_ADDR: usize = 0x5D;
+const SPH_ADDR: usize = 0x5E;
+const SREG_ADDR: usize = 0x5F;
+const SPMCSR_ADDR: usize = SPMCSR + 0x20;
+const UDR0_ADDR: usize = UDR0 as usize;
+
+// Cycles spent pushing the PC and jumping to the vector
+pub const INTERRUPT_RESPONSE_CYCLES: u64 = 4;
+
 pub struct Avrcore {
     // Registers
     pub sreg: SREG, // Status register
@@ -55,24 +109,446 @@ pub struct Avrcore {
     pub general: [u8; 32], // General purpose register file 0x0000 - 0x001F
     pub io: [u8; 64], // IO Registers 0x0020 - 0x005F
     pub extio: [u8; 160], // Extended IO 0x0060 - 0x00FF
-    pub sram: [u8; 2047], // Internal SRAM 0x0100 - 0x08FF
+    pub sram: [u8; 2048], // Internal SRAM 0x0100 - 0x08FF
 
     // Storage
     //pub flash: [u16; 16383], // 32Kbytes flash organized as 16K x 16
-    pub flash: HashMap<usize, Opcodes>
+    pub flash: HashMap<usize, Opcodes>,
+    pub progmem: Vec<u8>, // Raw flash contents, byte addressed
+    pub spm: SelfProgramming, // Page buffer and timing of SPM operations
+    pub eeprom: Vec<u8>, // 1Kbyte EEPROM
+
+    // Timing and interrupts
+    pub cycles: u64, // CPU clock cycles since reset
+    pub pending_interrupts: u32, // Bit n set when vector n is waiting to be serviced
+    pub interrupt_inhibit: bool, // Set by SEI and RETI, the next instruction runs before any interrupt
+    pub scheduled: Vec<(u64, Interrupt)>, // Interrupts raised by peripherals at a future cycle
+    pub sleep: Option<SleepState>,
+
+    // Peripherals
+    pub uart_tx: Vec<u8>, // Bytes written to UDR0 and not yet collected
+
+    // Debugging
+    pub watchpoints: Vec<Watchpoint>,
+    pub watch_hits: Vec<WatchHit>, // Hits of the last instruction, not yet collected
+    pub journal: Option<Vec<Access>>, // Accesses of the instruction being recorded for reverse execution
+
+    // Configuration
+    pub fuses: Fuses, // Fuse and lock bytes
+    pub external_clock_hz: u32, // Crystal or external clock fitted to the board
 }
 
 impl Avrcore {
-    pub fn execute(&mut self) {
-        let opcode = self.flash.get(&(self.pc as usize)).unwrap().clone();
+    // Power up a core with the given program
+    pub fn new(flash: HashMap<usize, Opcodes>) -> Avrcore {
+        let mut core = Avrcore {
+            sreg: SREG::default(),
+            sp: StackPointer::default(),
+            pc: 0,
+            general: [0; 32],
+            io: [0; 64],
+            extio: [0; 160],
+            sram: [0; 2048],
+            flash,
+            progmem: vec![0xFF; FLASH_WORDS as usize * 2],
+            spm: SelfProgramming::default(),
+            eeprom: vec![0xFF; EEPROM_SIZE],
+            cycles: 0,
+            pending_interrupts: 0,
+            interrupt_inhibit: false,
+            scheduled: Vec::new(),
+            sleep: None,
+            uart_tx: Vec::new(),
+            watchpoints: Vec::new(),
+            watch_hits: Vec::new(),
+            journal: None,
+            fuses: Fuses::default(),
+            external_clock_hz: 16_000_000,
+        };
+
+        core.reset(ResetCause::PowerOn);
+        core
+    }
+
+    // Power up a core configured by the given fuses
+    pub fn with_fuses(flash: HashMap<usize, Opcodes>, fuses: Fuses) -> Avrcore {
+        let mut core = Avrcore::new(flash);
+
+        core.fuses = fuses;
+        core.reset(ResetCause::PowerOn);
+        core
+    }
+
+    // Core running a raw flash image loaded at address 0
+    pub fn from_bytes(progmem: &[u8]) -> Avrcore {
+        let (dissasm, flash_idx) = disassembler::dissasm_bytes(progmem);
+        let mut core = Avrcore::new(flash_idx.into_iter().zip(dissasm).collect());
+
+        core.load_progmem(0, progmem);
+        core
+    }
+
+    // System clock frequency after the CLKPR prescaler, at least 1 Hz so it
+    // can divide cycle counts
+    pub fn clock_hz(&self) -> u32 {
+        let clkps = self.read_data(CLKPR) & 0x0F;
+
+        (self.fuses.oscillator_hz(self.external_clock_hz) >> clkps.min(8)).max(1)
+    }
+
+    pub fn execute(&mut self) -> Result<(), ExecError> {
+        if let Some(state) = self.sleep {
+            self.advance_sleep(state);
+            return Ok(())
+        }
+
+        self.service_interrupt();
+        self.interrupt_inhibit = false;
+
+        let opcode = match self.flash.get(&(self.pc as usize)) {
+            Some(opcode) => *opcode,
+            None => return Err(ExecError::NoInstruction { address: self.pc })
+        };
+
+        opcode.execute(self)?;
+        self.cycles += opcode.cycles();
+
+        self.update_self_programming();
+        self.deliver_scheduled();
+
+        Ok(())
+    }
+
+    // Read a byte from the unified data space
+    pub fn read_data(&self, addr: u16) -> u8 {
+        let addr = addr as usize;
+        match addr {
+            SPL_ADDR => self.sp.SPL,
+            SPH_ADDR => self.sp.SPH,
+            SREG_ADDR => self.sreg.to_byte(),
+            0x0000..=0x001F => self.general[addr],
+            0x0020..=0x005F => self.io[addr - 0x20],
+            0x0060..=0x00FF => self.extio[addr - 0x60],
+            0x0100..=0x08FF => self.sram[addr - 0x100],
+            _ => 0
+        }
+    }
+
+    // Write a byte to the unified data space. Writes outside the data space are dropped.
+    pub fn write_data(&mut self, addr: u16, value: u8) {
+        if self.journal.is_some() {
+            self.journal(Access::Write(addr, self.read_data(addr)));
+        }
+
+        let addr = addr as usize;
+        match addr {
+            SPL_ADDR => self.sp.SPL = value,
+            SPH_ADDR => self.sp.SPH = value,
+            SREG_ADDR => self.sreg = SREG::from_byte(value),
+            SPMCSR_ADDR => self.write_spmcsr(value),
+            UDR0_ADDR => self.write_udr0(value),
+            0x0000..=0x001F => self.general[addr] = value,
+            0x0020..=0x005F => self.io[addr - 0x20] = value,
+            0x0060..=0x00FF => self.extio[addr - 0x60] = value,
+            0x0100..=0x08FF => self.sram[addr - 0x100] = value,
+            _ => {}
+        }
+    }
+
+    // Byte address held in R31:R30
+    pub fn y_pointer(&self) -> u16 {
+        (self.general[29] as u16) << 8 | self.general[28] as u16
+    }
+
+    pub fn z_pointer(&self) -> u16 {
+        (self.general[31] as u16) << 8 | self.general[30] as u16
+    }
+
+    pub fn set_z_pointer(&mut self, value: u16) {
+        self.general[30] = (value & 0xFF) as u8;
+        self.general[31] = (value >> 8) as u8;
+    }
+
+    pub fn push(&mut self, value: u8) {
+        self.store(self.sp.current_addr(), value);
+        self.sp.decrement(1);
+    }
+
+    pub fn pop(&mut self) -> u8 {
+        self.sp.increment(1);
+        self.load(self.sp.current_addr())
+    }
+
+    // Flag an enabled interrupt as pending. It is serviced once the I flag allows it.
+    pub fn raise_interrupt(&mut self, irq: Interrupt) {
+        self.pending_interrupts |= 1 << irq.vector();
+    }
+
+    // Raise an interrupt once the cycle counter reaches `at`
+    pub fn schedule_interrupt(&mut self, at: u64, irq: Interrupt) {
+        self.scheduled.push((at, irq));
+    }
+
+    pub fn enter_sleep(&mut self, mode: SleepMode) {
+        self.sleep = Some(SleepState { mode, since: self.cycles });
+    }
+
+    // True when the core sleeps and nothing scheduled can ever wake it, e.g. after
+    // `cli; sleep`, or spins in `cli; rjmp .` as avr-libc's exit does
+    pub fn sleeping_forever(&self) -> bool {
+        match self.sleep {
+            Some(state) => {
+                !self.sreg.I
+                    || (!self.pending_can_wake(state.mode) && self.next_wake_event(state.mode).is_none())
+            },
+            None => !self.sreg.I && matches!(self.flash.get(&(self.pc as usize)), Some(Opcodes::RJMP(rjmp)) if rjmp.k == -2)
+        }
+    }
+
+    // Interrupt the next step services before executing an instruction
+    pub fn next_interrupt(&self) -> Option<Interrupt> {
+        if self.sleep.is_some() || !self.sreg.I || self.interrupt_inhibit {
+            return None
+        }
+
+        // Lower vectors have higher priority
+        (0..32u8)
+            .filter(|vector| self.pending_interrupts & (1 << vector) != 0)
+            .find_map(Interrupt::from_vector)
+    }
+
+    // Address of the instruction the next step executes, after an interrupt
+    // redirected execution to its vector
+    pub fn next_pc(&self) -> u16 {
+        self.next_interrupt().map_or(self.pc, |irq| irq.address())
+    }
+
+    fn service_interrupt(&mut self) {
+        let irq = match self.next_interrupt() {
+            Some(irq) => irq,
+            None => return
+        };
+        self.pending_interrupts &= !(1 << irq.vector());
+
+        let pc = self.pc;
+        self.push((pc & 0xFF) as u8);
+        self.push((pc >> 8) as u8);
+
+        self.sreg.I = false;
+        self.pc = irq.address();
+        self.cycles += INTERRUPT_RESPONSE_CYCLES;
+    }
+
+    fn deliver_scheduled(&mut self) {
+        let now = self.cycles;
+        let mut due = Vec::new();
+
+        self.scheduled.retain(|&(at, irq)| {
+            if at <= now {
+                due.push(irq);
+                false
+            } else {
+                true
+            }
+        });
+
+        for irq in due {
+            self.raise_interrupt(irq);
+        }
+    }
+
+    fn pending_can_wake(&self, mode: SleepMode) -> bool {
+        (0..32u8)
+            .filter(|vector| self.pending_interrupts & (1 << vector) != 0)
+            .filter_map(Interrupt::from_vector)
+            .any(|irq| mode.can_wake(irq.wake_source(self)))
+    }
+
+    // Index of the earliest scheduled interrupt whose peripheral keeps running in `mode`
+    fn next_wake_event(&self, mode: SleepMode) -> Option<usize> {
+        self.scheduled.iter()
+            .enumerate()
+            .filter(|(_, (_, irq))| irq.clock_domain(self).is_none_or(|domain| mode.clock_running(domain)))
+            .min_by_key(|(_, (at, _))| *at)
+            .map(|(idx, _)| idx)
+    }
+
+    // Skip straight to the next event that can occur while asleep instead of
+    // stepping through idle cycles.
+    fn advance_sleep(&mut self, state: SleepState) {
+        if !self.sreg.I {
+            // Nothing can wake us without global interrupts
+            return
+        }
+
+        if self.pending_can_wake(state.mode) {
+            self.wake(state);
+            return
+        }
+
+        if let Some(idx) = self.next_wake_event(state.mode) {
+            let (at, irq) = self.scheduled.remove(idx);
+            self.cycles = self.cycles.max(at);
+            self.raise_interrupt(irq);
+
+            if state.mode.can_wake(irq.wake_source(self)) {
+                self.wake(state);
+            }
+        }
+    }
+
+    fn wake(&mut self, state: SleepState) {
+        let slept = self.cycles - state.since;
+
+        // Peripherals with a stopped clock did not advance while we slept
+        let mut frozen = Vec::new();
+        for (idx, (_, irq)) in self.scheduled.iter().enumerate() {
+            if let Some(domain) = irq.clock_domain(self) {
+                if !state.mode.clock_running(domain) {
+                    frozen.push(idx);
+                }
+            }
+        }
+        for idx in frozen {
+            self.scheduled[idx].0 += slept;
+        }
+
+        self.sleep = None;
+        self.cycles += state.mode.wakeup_cycles(self.fuses.startup_cycles());
+    }
+}
+
+// All registers, one line for the core registers followed by the register file
+pub fn register_dump(core: &Avrcore) -> String {
+    let flags: String = "ITHSVNZC".chars()
+        .zip(format!("{:08b}", core.sreg.to_byte()).chars())
+        .map(|(flag, bit)| if bit == '1' { flag } else { flag.to_ascii_lowercase() })
+        .collect();
 
-        opcode.execute(self)
+    let mut dump = format!("PC {:#06x}  SP {:#06x}  SREG {}  Cycles {}\n",
+                           core.pc, core.sp.current_addr(), flags, core.cycles);
+
+    for (idx, registers) in core.general.chunks(8).enumerate() {
+        let line: Vec<String> = registers.iter().enumerate()
+            .map(|(offset, value)| format!("r{:<2} {:02x}", idx * 8 + offset, value))
+            .collect();
+        dump += &line.join("  ");
+        dump += "\n";
     }
+
+    dump
 }
 
 pub fn print_core(core: &Avrcore) {
     println!("Registers:");
     println!("\t{:?}", core.sreg);
     println!("\t{:?}", core.sp);
-    println!("\tPC {:?}", core.pc)
-}
\ No newline at end of file
+    println!("\tPC {:?}", core.pc);
+    println!("\tCycles {}", core.cycles)
+}
+
+// Tests
+#[cfg(test)]
+mod tests {
+    use crate::avrcore::{Avrcore, RAMEND};
+    use crate::fuses::Fuses;
+    use crate::interrupts::Interrupt;
+    use crate::reset::CLKPR;
+    use crate::sleep::{SleepMode, SMCR};
+    use std::collections::HashMap;
+
+    // sei, then `second`, eor r1, r1 up to the TIMER0 OVF vector and reti there
+    fn core_with(second: [u8; 2]) -> Avrcore {
+        let mut progmem = [0x11, 0x24].repeat(0x22);
+        progmem[0..2].copy_from_slice(&[0x78, 0x94]);
+        progmem[2..4].copy_from_slice(&second);
+        progmem[0x40..0x42].copy_from_slice(&[0x18, 0x95]);
+
+        Avrcore::from_bytes(&progmem)
+    }
+
+    #[test]
+    fn sei_and_reti_delay_interrupts() {
+        let mut core = core_with([0x11, 0x24]);
+        core.raise_interrupt(Interrupt::Timer0Ovf);
+
+        // The instruction after SEI runs first
+        core.execute().unwrap();
+        assert_eq!((core.next_interrupt(), core.next_pc()), (None, 2));
+        core.execute().unwrap();
+        assert_eq!((core.pc, core.sp.current_addr()), (4, RAMEND));
+        assert_eq!((core.next_interrupt(), core.next_pc()), (Some(Interrupt::Timer0Ovf), 0x40));
+
+        // Then the handler is entered and returns
+        core.execute().unwrap();
+        assert_eq!((core.pc, core.sp.current_addr()), (4, RAMEND));
+
+        // One instruction after RETI runs before the handler is entered again
+        core.raise_interrupt(Interrupt::Timer0Ovf);
+        core.execute().unwrap();
+        assert_eq!(core.pc, 6);
+        core.execute().unwrap();
+        assert_eq!((core.pc, core.cycles), (6, 1 + 1 + 4 + 4 + 1 + 4 + 4));
+    }
+
+    #[test]
+    fn sleep_until_scheduled_interrupt() {
+        let mut core = core_with([0x88, 0x95]);
+        core.io[SMCR] = SleepMode::PowerDown.to_smcr();
+
+        // TIMER0 is stopped in power-down, only INT0 at its low level can wake the core
+        core.schedule_interrupt(1000, Interrupt::Timer0Ovf);
+        core.schedule_interrupt(2000, Interrupt::Int0);
+        core.execute().unwrap();
+        core.execute().unwrap();
+        let state = core.sleep.unwrap();
+        assert_eq!(state.mode, SleepMode::PowerDown);
+
+        // Asleep, time jumps to INT0 and the timer resumes where it stopped
+        core.execute().unwrap();
+        assert!(core.sleep.is_none());
+        assert_eq!(core.cycles, 2000 + SleepMode::PowerDown.wakeup_cycles(core.fuses.startup_cycles()));
+        assert_eq!(core.scheduled, vec![(1000 + 2000 - state.since, Interrupt::Timer0Ovf)]);
+        assert_eq!(core.pending_interrupts, 1 << Interrupt::Int0.vector());
+
+        // The handler runs next, returning to the instruction after SLEEP
+        core.execute().unwrap();
+        assert_eq!((core.pc, core.pending_interrupts), (Interrupt::Int0.address() + 2, 0));
+    }
+
+    #[test]
+    fn clock_prescaler() {
+        // Internal 8 MHz RC divided by 8 through CKDIV8
+        let mut core = Avrcore::with_fuses(HashMap::new(), Fuses::default());
+        assert_eq!(core.clock_hz(), 1_000_000);
+        core.write_data(CLKPR, 0x00);
+        assert_eq!(core.clock_hz(), 8_000_000);
+        core.write_data(CLKPR, 0x0F);
+        assert_eq!(core.clock_hz(), 8_000_000 / 256);
+
+        // External clock, never slower than 1 Hz
+        core.fuses.low = 0xE0;
+        core.external_clock_hz = 16_000_000;
+        core.write_data(CLKPR, 0x01);
+        assert_eq!(core.clock_hz(), 8_000_000);
+        core.external_clock_hz = 1;
+        core.write_data(CLKPR, 0x08);
+        assert_eq!(core.clock_hz(), 1);
+    }
+
+    #[test]
+    fn add_with_carry() {
+        // add r24, r18; adc r25, r19 on 0x00FF + 0x0001
+        let mut core = Avrcore::from_bytes(&[0x82, 0x0F, 0x93, 0x1F]);
+        core.general[24] = 0xFF;
+        core.general[18] = 0x01;
+
+        core.execute().unwrap();
+        assert_eq!(core.general[24], 0x00);
+        assert_eq!(core.sreg.to_byte(), 0b0010_0011);
+
+        core.execute().unwrap();
+        assert_eq!(core.general[25], 0x01);
+        assert_eq!(core.sreg.to_byte(), 0b0000_0000);
+    }
+}
@@ -0,0 +1,143 @@
+use crate::avrcore::Avrcore;
+use crate::instructions::Opcodes;
+use crate::interrupts::Interrupt;
+
+// Bytes CALL and RCALL push for the return address
+pub const RETURN_ADDRESS_SIZE: u16 = 2;
+
+// One activation on the shadow call stack
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct CallFrame {
+    pub function: u32, // Entry address
+    pub call_site: Option<u32>, // The call, or the instruction an interrupt preempted
+    pub interrupt: Option<Interrupt>,
+    pub sp: Option<u16>, // SP before the frame was entered, None for the bottom frame
+}
+
+// State before one step of an awake core
+#[derive(Debug, Copy, Clone)]
+pub struct CallState {
+    pub pc: u16, // Instruction executed, after an interrupt redirected execution
+    pub preempted: u16,
+    pub interrupt: Option<Interrupt>,
+    pub call: bool,
+    pub sp: u16,
+}
+
+impl CallState {
+    // None while the core sleeps
+    pub fn capture(core: &Avrcore) -> Option<CallState> {
+        if core.sleep.is_some() {
+            return None
+        }
+
+        let pc = core.next_pc();
+        Some(CallState {
+            pc,
+            preempted: core.pc,
+            interrupt: core.next_interrupt(),
+            call: matches!(core.flash.get(&(pc as usize)), Some(Opcodes::CALL(_)) | Some(Opcodes::RCALL(_))),
+            sp: core.sp.current_addr(),
+        })
+    }
+}
+
+// Shadow call stack following calls, returns and interrupts. A frame is left
+// when SP rises back to where it was before the call, which also covers RETI
+// and code that unwinds the stack itself.
+#[derive(Debug, Clone)]
+pub struct CallStack {
+    frames: Vec<CallFrame>,
+}
+
+impl CallStack {
+    // Start in the function the core is currently executing
+    pub fn new(core: &Avrcore) -> CallStack {
+        CallStack {
+            frames: vec![CallFrame { function: core.pc as u32, call_site: None, interrupt: None, sp: None }],
+        }
+    }
+
+    // Frames from the bottom up
+    pub fn frames(&self) -> &[CallFrame] {
+        &self.frames
+    }
+
+    pub fn top(&self) -> Option<&CallFrame> {
+        self.frames.last()
+    }
+
+    // Follow the step executed since `state` was captured
+    pub fn after(&mut self, state: &CallState, core: &Avrcore) {
+        self.interrupt(state);
+        self.transfer(state, core);
+    }
+
+    // Enter the frame of an interrupt taken before the instruction, if any
+    pub fn interrupt(&mut self, state: &CallState) {
+        if let Some(irq) = state.interrupt {
+            self.frames.push(CallFrame {
+                function: irq.address() as u32,
+                call_site: Some(state.preempted as u32),
+                interrupt: Some(irq),
+                sp: Some(state.sp),
+            });
+        }
+    }
+
+    // Enter the callee of a call, or leave the frames a return unwound
+    pub fn transfer(&mut self, state: &CallState, core: &Avrcore) {
+        let sp = core.sp.current_addr();
+
+        if state.call {
+            self.frames.push(CallFrame {
+                function: core.pc as u32,
+                call_site: Some(state.pc as u32),
+                interrupt: None,
+                sp: Some(sp.wrapping_add(RETURN_ADDRESS_SIZE)),
+            });
+        } else {
+            while self.frames.last().is_some_and(|frame| frame.sp.is_some_and(|entry_sp| sp >= entry_sp)) {
+                self.frames.pop();
+            }
+        }
+    }
+}
+
+// Tests
+#[cfg(test)]
+mod tests {
+    use crate::avrcore::{Avrcore, RAMEND};
+    use crate::callstack::{CallStack, CallState};
+    use crate::interrupts::Interrupt;
+
+    #[test]
+    fn calls_and_interrupts() {
+        let mut progmem = [0x11, 0x24].repeat(0x12);
+        // jmp main; INT0: reti
+        progmem[..6].copy_from_slice(&[0x0C, 0x94, 0x08, 0x00, 0x18, 0x95]);
+        // main: sei; call f; f: eor r1, r1
+        progmem[0x10..0x16].copy_from_slice(&[0x78, 0x94, 0x0E, 0x94, 0x10, 0x00]);
+
+        let mut core = Avrcore::from_bytes(&progmem);
+        let mut calls = CallStack::new(&core);
+
+        for _ in 0..3 {
+            let state = CallState::capture(&core).unwrap();
+            core.execute().unwrap();
+            calls.after(&state, &core);
+        }
+        let f = *calls.top().unwrap();
+        assert_eq!((calls.frames().len(), f.function, f.call_site, f.sp), (2, 0x20, Some(0x12), Some(RAMEND)));
+
+        // The handler returns in the step that enters it
+        core.raise_interrupt(Interrupt::Int0);
+        let state = CallState::capture(&core).unwrap();
+        core.execute().unwrap();
+        calls.interrupt(&state);
+        let isr = *calls.top().unwrap();
+        assert_eq!((isr.interrupt, isr.call_site, isr.sp), (Some(Interrupt::Int0), Some(0x20), Some(RAMEND - 2)));
+        calls.transfer(&state, &core);
+        assert_eq!(calls.top(), Some(&f));
+    }
+}
@@ -0,0 +1,491 @@
+use avrsim::disasm::{DisasmMode, OutputFormat};
+use avrsim::simulator;
+use avrsim::trace::TraceFormat;
+use avrsim::vcd::Signal;
+use avrsim::watch::WatchKind;
+use avrsim::{Fuses, ImageFormat};
+use std::convert::TryFrom;
+
+pub const USAGE: &str = "\
+Usage: avrsim [OPTIONS] <IMAGE>
+       avrsim disasm [OPTIONS] <IMAGE>
+
+Runs an AVR program until it sleeps with no way to wake up, a limit is
+reached or an exit condition holds.
+
+Image:
+  -f, --format <FORMAT>     auto, elf, ihex, srec or binary (default: auto)
+      --base <ADDR>         Load address of a binary image (default: 0)
+  -m, --mcu <MCU>           Device model (default: atmega328p)
+  -c, --clock <FREQ>        External clock or crystal, e.g. 16MHz (default: 16MHz)
+      --fuse <NAME=VALUE>   Set lfuse, hfuse, efuse or lock
+      --fuse-hex <FILE>     Load the fuse bytes from an Intel HEX file
+      --lock-hex <FILE>     Load the lock byte from an Intel HEX file
+      --load-snapshot <FILE>
+                            Start from a machine state saved with --save-snapshot
+
+Limits:
+      --max-cycles <N>      Stop after N clock cycles
+      --max-instructions <N>
+                            Stop after N instructions
+      --max-time <TIME>     Stop after TIME of simulated time, e.g. 20ms
+
+Exit conditions:
+      --exit-at <ADDR|SYMBOL>
+                            Stop when execution reaches an address or ELF symbol
+      --exit-on-sleep       Stop when the core enters any sleep mode
+      --watch <ADDR|SYMBOL[:LEN]>
+                            Stop when the program writes to a data space range
+      --rwatch <ADDR|SYMBOL[:LEN]>
+                            Stop when the program reads from a data space range
+      --awatch <ADDR|SYMBOL[:LEN]>
+                            Stop when the program reads or writes a data space range
+      --stack-guard <ADDR|SYMBOL|auto>
+                            Stop with a backtrace when the stack grows below a data
+                            space address, auto takes __heap_end, __bss_end or _end
+
+Debugging:
+      --gdb <PORT>          Wait for avr-gdb on a local TCP port and run under its
+                            control, e.g. `target remote :1234`
+      --monitor             Start an interactive monitor to step, set breakpoints
+                            and inspect registers, memory and peripherals
+      --history <N>         Instructions recorded for reverse execution under
+                            --gdb and --monitor, 0 disables it (default: 1000000)
+
+Output:
+      --dump-registers      Print the registers when the run ends
+      --stack-report        Print the lowest SP and the stack used by each function
+                            when the run ends
+      --trace               Print every executed instruction with its register and
+                            memory changes to stderr
+      --trace-file <FILE>   Write the trace to a file instead
+      --trace-format <FORMAT>
+                            text or binary, binary needs --trace-file (default: text)
+      --trace-filter <START-END|SYMBOL>
+                            Only trace instructions in a flash address range or
+                            function, can be given several times
+      --vcd <FILE>          Write a VCD waveform of pins, registers, SREG flags and
+                            interrupt lines against simulated time
+      --vcd-signal <SIGNAL[,SIGNAL...]>
+                            Record a pin (PB5), register (TCNT0 or a data address),
+                            flag (SREG.I) or pending interrupt (TIMER0_OVF), can be
+                            given several times (default: all pins and SREG flags)
+      --profile <FILE>      Write a flat profile and call graph of the cycles spent
+                            in each function when the run ends
+      --profile-folded <FILE>
+                            Write the profiled call stacks in the folded format of
+                            flamegraph tools
+      --coverage <FILE>     Write lcov coverage of the executed lines and branches
+                            when the run ends, or a per-address report for images
+                            without DWARF line information
+      --uart <BACKEND>      Where USART0 output goes: stdout, stderr, null or a file
+                            (default: stdout)
+      --dump-flash <FILE>   Write flash as Intel HEX when the run ends
+      --dump-eeprom <FILE>  Write EEPROM as Intel HEX when the run ends
+      --save-snapshot <FILE>
+                            Save the complete machine state when the run ends
+      --listing             Print an avr-objdump style disassembly and exit
+      --symbols <FILE>      Take listing labels from avr-nm output
+  -h, --help                Print this help
+
+Exit status:
+  0  The program stopped normally, an exit condition held or a watchpoint hit
+  1  The simulated program executed something unsupported
+  2  Invalid command line
+  3  The image could not be loaded or decoded
+  4  A cycle, instruction or time limit was reached
+  5  The stack grew below the --stack-guard address
+";
+
+pub const DISASM_USAGE: &str = "\
+Usage: avrsim disasm [OPTIONS] <IMAGE>
+
+Disassembles flash. Data regions and words that do not decode are shown as
+.word directives.
+
+Options:
+  -f, --format <FORMAT>     auto, elf, ihex, srec or binary (default: auto)
+      --base <ADDR>         Load address of a binary image (default: 0)
+      --start <ADDR>        First byte address to disassemble (default: 0)
+      --end <ADDR>          Byte address to stop at (default: end of image)
+      --recursive           Only decode code reachable from the reset and interrupt
+                            vectors, everything else is shown as data
+  -o, --output <OUTPUT>     text or json (default: text)
+      --symbols <FILE>      Take labels from avr-nm output
+  -h, --help                Print this help
+";
+
+pub const EXIT_OK: i32 = 0;
+pub const EXIT_EXEC_ERROR: i32 = 1;
+pub const EXIT_USAGE: i32 = 2;
+pub const EXIT_LOAD_ERROR: i32 = 3;
+pub const EXIT_LIMIT: i32 = 4;
+pub const EXIT_STACK_OVERFLOW: i32 = 5;
+
+// Instructions kept for reverse execution when debugging
+const DEFAULT_HISTORY: usize = 1_000_000;
+
+// Devices sharing the ATmega328P core and memory map
+const SUPPORTED_MCUS: [&str; 2] = ["atmega328p", "atmega328"];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum UartBackend {
+    Stdout,
+    Stderr,
+    Null,
+    File(String),
+}
+
+// A fuse setting from the command line. They are applied in order over the
+// image's own fuses once it is loaded.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FuseSetting {
+    Flag(String), // name=value, checked while parsing
+    FuseHex(String),
+    LockHex(String),
+}
+
+#[derive(Debug)]
+pub struct Options {
+    pub image: String,
+    pub format: Option<ImageFormat>, // None detects the format from the contents
+    pub binary_base: u32,
+    pub clock_hz: u32,
+    pub fuses: Vec<FuseSetting>,
+    pub max_cycles: Option<u64>,
+    pub max_instructions: Option<u64>,
+    pub max_time: Option<f64>, // Seconds
+    pub exit_at: Option<String>,
+    pub exit_on_sleep: bool,
+    pub watches: Vec<(WatchKind, String)>,
+    pub stack_guard: Option<String>,
+    pub gdb_port: Option<u16>,
+    pub monitor: bool,
+    pub history: usize,
+    pub dump_registers: bool,
+    pub stack_report: bool,
+    pub trace: bool,
+    pub trace_file: Option<String>,
+    pub trace_format: TraceFormat,
+    pub trace_filters: Vec<String>,
+    pub vcd: Option<String>,
+    pub vcd_signals: Vec<Signal>,
+    pub profile: Option<String>,
+    pub profile_folded: Option<String>,
+    pub coverage: Option<String>,
+    pub uart: UartBackend,
+    pub dump_flash: Option<String>,
+    pub dump_eeprom: Option<String>,
+    pub load_snapshot: Option<String>,
+    pub save_snapshot: Option<String>,
+    pub listing: bool,
+    pub symbols: Option<String>,
+    pub help: bool,
+}
+
+impl Default for Options {
+    fn default() -> Options {
+        Options {
+            image: String::new(),
+            format: None,
+            binary_base: 0,
+            clock_hz: 16_000_000,
+            fuses: Vec::new(),
+            max_cycles: None,
+            max_instructions: None,
+            max_time: None,
+            exit_at: None,
+            exit_on_sleep: false,
+            watches: Vec::new(),
+            stack_guard: None,
+            gdb_port: None,
+            monitor: false,
+            history: DEFAULT_HISTORY,
+            dump_registers: false,
+            stack_report: false,
+            trace: false,
+            trace_file: None,
+            trace_format: TraceFormat::Text,
+            trace_filters: Vec::new(),
+            vcd: None,
+            vcd_signals: Vec::new(),
+            profile: None,
+            profile_folded: None,
+            coverage: None,
+            uart: UartBackend::Stdout,
+            dump_flash: None,
+            dump_eeprom: None,
+            load_snapshot: None,
+            save_snapshot: None,
+            listing: false,
+            symbols: None,
+            help: false,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct DisasmOptions {
+    pub image: String,
+    pub format: Option<ImageFormat>,
+    pub binary_base: u32,
+    pub start: usize,
+    pub end: Option<usize>,
+    pub mode: DisasmMode,
+    pub output: OutputFormat,
+    pub symbols: Option<String>,
+    pub help: bool,
+}
+
+fn parse_format(name: &str) -> Result<Option<ImageFormat>, String> {
+    match name {
+        "auto" => Ok(None),
+        name => ImageFormat::from_name(name).map(Some).ok_or(format!("Unknown image format: {}", name))
+    }
+}
+
+// Decimal or 0x prefixed hexadecimal
+pub fn parse_number(value: &str) -> Result<u64, String> {
+    simulator::parse_number(value).ok_or(format!("Invalid number: {}", value))
+}
+
+// A number that has to fit the narrower type of its option
+fn parse_int<T: TryFrom<u64>>(value: &str) -> Result<T, String> {
+    T::try_from(parse_number(value)?).map_err(|_| format!("Number out of range: {}", value))
+}
+
+// Split a value such as `16MHz` or `20ms` into its number and unit
+fn split_unit(value: &str) -> Result<(f64, &str), String> {
+    let idx = value.find(|c: char| !(c.is_ascii_digit() || c == '.')).unwrap_or(value.len());
+    let number = value[..idx].parse::<f64>().map_err(|_| format!("Invalid number: {}", value))?;
+
+    Ok((number, &value[idx..]))
+}
+
+// Frequency in Hz from e.g. `16000000`, `16M`, `16MHz` or `32.768kHz`
+pub fn parse_frequency(value: &str) -> Result<u32, String> {
+    let (number, unit) = split_unit(value)?;
+    let scale = match unit.to_ascii_lowercase().trim_end_matches("hz") {
+        "" => 1.0,
+        "k" => 1e3,
+        "m" => 1e6,
+        _ => return Err(format!("Invalid frequency: {}", value))
+    };
+
+    let hz = (number * scale).round();
+    if hz < 1.0 || hz > u32::MAX as f64 {
+        return Err(format!("Frequency out of range: {}", value));
+    }
+
+    Ok(hz as u32)
+}
+
+// Duration in seconds from e.g. `2`, `2s`, `20ms` or `500us`
+pub fn parse_duration(value: &str) -> Result<f64, String> {
+    let (number, unit) = split_unit(value)?;
+    let scale = match unit {
+        "" | "s" => 1.0,
+        "ms" => 1e-3,
+        "us" => 1e-6,
+        "ns" => 1e-9,
+        _ => return Err(format!("Invalid duration: {}", value))
+    };
+
+    Ok(number * scale)
+}
+
+pub fn parse_args<I: Iterator<Item = String>>(mut args: I) -> Result<Options, String> {
+    let mut options = Options::default();
+    let mut image = None;
+
+    while let Some(arg) = args.next() {
+        let mut value = |name: &str| args.next().ok_or(format!("{} needs a value", name));
+
+        match arg.as_str() {
+            "-h" | "--help" => options.help = true,
+            "-f" | "--format" => options.format = parse_format(&value(&arg)?)?,
+            "--base" => options.binary_base = parse_int(&value(&arg)?)?,
+            "-m" | "--mcu" => {
+                let mcu = value(&arg)?.to_ascii_lowercase();
+                if !SUPPORTED_MCUS.contains(&mcu.as_str()) {
+                    return Err(format!("Unsupported MCU: {}, supported are {}", mcu, SUPPORTED_MCUS.join(", ")));
+                }
+            },
+            "-c" | "--clock" => options.clock_hz = parse_frequency(&value(&arg)?)?,
+            "--fuse" => {
+                let flag = value(&arg)?;
+                Fuses::default().set_from_flag(&flag)?;
+                options.fuses.push(FuseSetting::Flag(flag));
+            },
+            "--fuse-hex" => options.fuses.push(FuseSetting::FuseHex(value(&arg)?)),
+            "--lock-hex" => options.fuses.push(FuseSetting::LockHex(value(&arg)?)),
+            "--max-cycles" => options.max_cycles = Some(parse_number(&value(&arg)?)?),
+            "--max-instructions" => options.max_instructions = Some(parse_number(&value(&arg)?)?),
+            "--max-time" => options.max_time = Some(parse_duration(&value(&arg)?)?),
+            "--exit-at" => options.exit_at = Some(value(&arg)?),
+            "--exit-on-sleep" => options.exit_on_sleep = true,
+            "--watch" => options.watches.push((WatchKind::Write, value(&arg)?)),
+            "--rwatch" => options.watches.push((WatchKind::Read, value(&arg)?)),
+            "--awatch" => options.watches.push((WatchKind::Access, value(&arg)?)),
+            "--stack-guard" => options.stack_guard = Some(value(&arg)?),
+            "--gdb" => {
+                let port = value(&arg)?;
+                options.gdb_port = Some(port.parse().map_err(|_| format!("Invalid port: {}", port))?);
+            },
+            "--monitor" => options.monitor = true,
+            "--history" => options.history = parse_int(&value(&arg)?)?,
+            "--dump-registers" => options.dump_registers = true,
+            "--stack-report" => options.stack_report = true,
+            "--trace" => options.trace = true,
+            "--trace-file" => {
+                options.trace = true;
+                options.trace_file = Some(value(&arg)?);
+            },
+            "--trace-format" => {
+                let name = value(&arg)?;
+                options.trace_format = TraceFormat::from_name(&name).ok_or(format!("Unknown trace format: {}", name))?;
+            },
+            "--trace-filter" => {
+                options.trace = true;
+                options.trace_filters.push(value(&arg)?);
+            },
+            "--vcd" => options.vcd = Some(value(&arg)?),
+            "--vcd-signal" => {
+                for spec in value(&arg)?.split(',') {
+                    options.vcd_signals.push(Signal::parse(spec).ok_or(format!("Unknown VCD signal: {}", spec))?);
+                }
+            },
+            "--profile" => options.profile = Some(value(&arg)?),
+            "--profile-folded" => options.profile_folded = Some(value(&arg)?),
+            "--coverage" => options.coverage = Some(value(&arg)?),
+            "--uart" => {
+                options.uart = match value(&arg)?.as_str() {
+                    "stdout" => UartBackend::Stdout,
+                    "stderr" => UartBackend::Stderr,
+                    "null" => UartBackend::Null,
+                    path => UartBackend::File(path.to_string())
+                };
+            },
+            "--dump-flash" => options.dump_flash = Some(value(&arg)?),
+            "--dump-eeprom" => options.dump_eeprom = Some(value(&arg)?),
+            "--load-snapshot" => options.load_snapshot = Some(value(&arg)?),
+            "--save-snapshot" => options.save_snapshot = Some(value(&arg)?),
+            "--listing" => options.listing = true,
+            "--symbols" => options.symbols = Some(value(&arg)?),
+            _ if arg.starts_with('-') => return Err(format!("Unknown option: {}", arg)),
+            _ if image.is_some() => return Err(format!("Unexpected argument: {}", arg)),
+            _ => image = Some(arg)
+        }
+    }
+
+    match image {
+        Some(image) => options.image = image,
+        None if options.help => (),
+        None => return Err(String::from("No image file given"))
+    }
+
+    if options.trace_format == TraceFormat::Binary && options.trace_file.is_none() {
+        return Err(String::from("A binary trace needs --trace-file"))
+    }
+
+    Ok(options)
+}
+
+// Arguments following `avrsim disasm`
+pub fn parse_disasm_args<I: Iterator<Item = String>>(mut args: I) -> Result<DisasmOptions, String> {
+    let mut options = DisasmOptions {
+        image: String::new(),
+        format: None,
+        binary_base: 0,
+        start: 0,
+        end: None,
+        mode: DisasmMode::Sweep,
+        output: OutputFormat::Text,
+        symbols: None,
+        help: false,
+    };
+    let mut image = None;
+
+    while let Some(arg) = args.next() {
+        let mut value = |name: &str| args.next().ok_or(format!("{} needs a value", name));
+
+        match arg.as_str() {
+            "-h" | "--help" => options.help = true,
+            "-f" | "--format" => options.format = parse_format(&value(&arg)?)?,
+            "--base" => options.binary_base = parse_int(&value(&arg)?)?,
+            "--start" => options.start = parse_int(&value(&arg)?)?,
+            "--end" => options.end = Some(parse_int(&value(&arg)?)?),
+            "--recursive" => options.mode = DisasmMode::Recursive,
+            "-o" | "--output" => {
+                options.output = match value(&arg)?.as_str() {
+                    "text" => OutputFormat::Text,
+                    "json" => OutputFormat::Json,
+                    output => return Err(format!("Unknown output format: {}", output))
+                };
+            },
+            "--symbols" => options.symbols = Some(value(&arg)?),
+            _ if arg.starts_with('-') => return Err(format!("Unknown option: {}", arg)),
+            _ if image.is_some() => return Err(format!("Unexpected argument: {}", arg)),
+            _ => image = Some(arg)
+        }
+    }
+
+    match image {
+        Some(image) => options.image = image,
+        None if options.help => (),
+        None => return Err(String::from("No image file given"))
+    }
+
+    Ok(options)
+}
+
+// Tests
+#[cfg(test)]
+mod tests {
+    use crate::cli::{parse_args, parse_disasm_args, parse_duration, parse_frequency, FuseSetting, UartBackend};
+    use avrsim::watch::WatchKind;
+    use avrsim::ImageFormat;
+
+    fn args(line: &str) -> impl Iterator<Item = String> + '_ {
+        line.split_whitespace().map(String::from)
+    }
+
+    #[test]
+    fn options() {
+        let options = parse_args(args("-f srec -c 8MHz --max-time 20ms --exit-at main --rwatch buffer:4 --uart out.txt image.s19")).unwrap();
+
+        assert_eq!(options.image, "image.s19");
+        assert_eq!(options.format, Some(ImageFormat::Srec));
+        assert_eq!(options.clock_hz, 8_000_000);
+        assert_eq!(options.max_time, Some(0.02));
+        assert_eq!(options.exit_at.as_deref(), Some("main"));
+        assert_eq!(options.watches, vec![(WatchKind::Read, String::from("buffer:4"))]);
+        assert_eq!(options.uart, UartBackend::File(String::from("out.txt")));
+
+        assert_eq!(parse_frequency("32.768kHz"), Ok(32_768));
+        assert_eq!(parse_duration("500us"), Ok(0.0005));
+
+        assert!(parse_args(args("--mcu atmega2560 image.hex")).is_err());
+        assert!(parse_args(args("--max-cycles")).is_err());
+        assert!(parse_args(args("--dump-registers")).is_err());
+        assert!(parse_args(args("--trace-format binary image.hex")).is_err());
+
+        let options = parse_args(args("--vcd out.vcd --vcd-signal PB5,SREG.I --vcd-signal TCNT0 image.hex")).unwrap();
+        assert_eq!(options.vcd.as_deref(), Some("out.vcd"));
+        assert_eq!(options.vcd_signals.len(), 3);
+        assert!(parse_args(args("--vcd-signal PE1 image.hex")).is_err());
+
+        let options = parse_args(args("--stack-guard __bss_end --stack-report image.hex")).unwrap();
+        assert_eq!((options.stack_guard.as_deref(), options.stack_report), (Some("__bss_end"), true));
+
+        // Fuse files are only read once the image is loaded
+        let options = parse_args(args("--fuse-hex missing.hex --fuse hfuse=0xDE image.hex")).unwrap();
+        assert_eq!(options.fuses, vec![FuseSetting::FuseHex(String::from("missing.hex")), FuseSetting::Flag(String::from("hfuse=0xDE"))]);
+        assert!(parse_args(args("--fuse hfuse=0xXY image.hex")).is_err());
+
+        // Values too large for the option are reported instead of truncated
+        assert_eq!(parse_args(args("--base 0x100000000 image.bin")).unwrap_err(), "Number out of range: 0x100000000");
+        assert_eq!(parse_disasm_args(args("--base 0x1000 image.bin")).unwrap().binary_base, 0x1000);
+        assert!(parse_disasm_args(args("--base 4294967296 image.bin")).is_err());
+    }
+}
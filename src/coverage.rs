@@ -0,0 +1,233 @@
+use crate::avrcore::Avrcore;
+use crate::dwarf::LineTable;
+use crate::elfreader::{Symbol, SymbolKind};
+use crate::instructions::{Instruction, Opcodes};
+use crate::listing::SymbolMap;
+use std::collections::{BTreeMap, HashMap};
+
+// Target of a conditional branch, which can either be taken or fall through.
+// Calls also name a target but always transfer control.
+fn conditional_target(opcode: &Opcodes, addr: u32) -> Option<u32> {
+    match opcode {
+        Opcodes::CALL(_) | Opcodes::RCALL(_) => None,
+        _ if opcode.falls_through() => opcode.branch_target(addr),
+        _ => None
+    }
+}
+
+// Which instructions executed and which way conditional branches went
+#[derive(Debug, Default)]
+pub struct Coverage {
+    hits: HashMap<u32, u64>, // Executions per instruction byte address
+    branches: HashMap<u32, (u64, u64)>, // Times taken and not taken per branch address
+}
+
+// Per line counts gathered for one source file of an lcov record
+#[derive(Default)]
+struct SourceFile {
+    lines: BTreeMap<u32, u64>,
+    branches: BTreeMap<u32, Vec<Option<(u64, u64)>>>, // None for a branch that never executed
+    functions: Vec<(u32, String, u64)>,
+}
+
+impl Coverage {
+    pub fn new() -> Coverage {
+        Coverage::default()
+    }
+
+    // Address of the instruction the next step executes, None while asleep
+    pub fn before(&self, core: &Avrcore) -> Option<u16> {
+        if core.sleep.is_some() {
+            return None
+        }
+
+        Some(core.next_pc())
+    }
+
+    // Count the instruction at `pc` and, for a branch, where it went
+    pub fn after(&mut self, pc: Option<u16>, core: &Avrcore) {
+        let pc = match pc {
+            Some(pc) => pc as u32,
+            None => return
+        };
+        *self.hits.entry(pc).or_insert(0) += 1;
+
+        if let Some(opcode) = core.flash.get(&(pc as usize)) {
+            if let Some(target) = conditional_target(opcode, pc) {
+                let counts = self.branches.entry(pc).or_insert((0, 0));
+                if core.pc as u32 == target {
+                    counts.0 += 1;
+                } else {
+                    counts.1 += 1;
+                }
+            }
+        }
+    }
+
+    pub fn hits(&self, address: u32) -> u64 {
+        self.hits.get(&address).copied().unwrap_or(0)
+    }
+
+    pub fn branch(&self, address: u32) -> Option<(u64, u64)> {
+        self.branches.get(&address).copied()
+    }
+
+    // Decoded instructions of the program in address order
+    fn instructions(core: &Avrcore) -> Vec<(u32, Opcodes)> {
+        let mut instructions: Vec<(u32, Opcodes)> = core.flash.iter().map(|(addr, opcode)| (*addr as u32, *opcode)).collect();
+        instructions.sort_by_key(|(addr, _)| *addr);
+        instructions
+    }
+
+    // gcov style listing of every instruction with its count, for images
+    // without line information
+    pub fn report(&self, core: &Avrcore, symbols: &SymbolMap) -> String {
+        let instructions = Coverage::instructions(core);
+        let executed = instructions.iter().filter(|(addr, _)| self.hits(*addr) > 0).count();
+        let branches = instructions.iter().filter(|(addr, opcode)| conditional_target(opcode, *addr).is_some()).count();
+        let outcomes: usize = self.branches.values().map(|(taken, not)| (*taken > 0) as usize + (*not > 0) as usize).sum();
+
+        let mut report = format!("{} of {} instructions executed, {} of {} branch outcomes taken\n",
+                                 executed, instructions.len(), outcomes, branches * 2);
+
+        for (addr, opcode) in instructions.iter() {
+            if let Some(label) = symbols.label_at(*addr) {
+                report += &format!("\n{:08x} <{}>:\n", addr, label);
+            }
+
+            let count = match self.hits(*addr) {
+                0 => String::from("#####"),
+                hits => hits.to_string()
+            };
+            let (mnemonic, operands, _) = opcode.objdump();
+            report += &format!("{:>10}: {:>4x}:\t{}\t{}", count, addr, mnemonic, operands);
+
+            if conditional_target(opcode, *addr).is_some() {
+                let (taken, not) = self.branch(*addr).unwrap_or((0, 0));
+                report += &format!("\t; taken {}, not taken {}", taken, not);
+            }
+            report += "\n";
+        }
+
+        report
+    }
+
+    // lcov tracefile with line, branch and function counts per source file.
+    // A line counts as often as its most executed instruction.
+    pub fn lcov(&self, core: &Avrcore, lines: &LineTable, symbols: &[Symbol]) -> String {
+        let mut files: BTreeMap<&str, SourceFile> = BTreeMap::new();
+
+        for (addr, opcode) in Coverage::instructions(core) {
+            let (path, line) = match lines.lookup(addr) {
+                Some(location) => location,
+                None => continue
+            };
+            let file = files.entry(path).or_default();
+            let count = file.lines.entry(line).or_insert(0);
+            *count = (*count).max(self.hits(addr));
+
+            if conditional_target(&opcode, addr).is_some() {
+                let counts = match self.hits(addr) {
+                    0 => None,
+                    _ => Some(self.branch(addr).unwrap_or((0, 0)))
+                };
+                file.branches.entry(line).or_default().push(counts);
+            }
+        }
+
+        for symbol in symbols.iter().filter(|symbol| symbol.kind == SymbolKind::Function && !symbol.absolute) {
+            if let Some((path, line)) = lines.lookup(symbol.value) {
+                if let Some(file) = files.get_mut(path) {
+                    file.functions.push((line, symbol.name.clone(), self.hits(symbol.value)));
+                }
+            }
+        }
+
+        let mut info = String::new();
+        for (path, file) in files.iter_mut() {
+            info += &format!("TN:\nSF:{}\n", path);
+
+            file.functions.sort();
+            for (line, name, _) in file.functions.iter() {
+                info += &format!("FN:{},{}\n", line, name);
+            }
+            for (_, name, hits) in file.functions.iter() {
+                info += &format!("FNDA:{},{}\n", hits, name);
+            }
+            info += &format!("FNF:{}\nFNH:{}\n", file.functions.len(), file.functions.iter().filter(|(_, _, hits)| *hits > 0).count());
+
+            let (mut found, mut hit) = (0, 0);
+            for (line, branches) in file.branches.iter() {
+                for (block, counts) in branches.iter().enumerate() {
+                    for branch in 0..2 {
+                        let count = counts.map(|(taken, not)| if branch == 0 { taken } else { not });
+                        info += &format!("BRDA:{},{},{},{}\n", line, block, branch, count.map_or(String::from("-"), |count| count.to_string()));
+                        found += 1;
+                        hit += count.is_some_and(|count| count > 0) as usize;
+                    }
+                }
+            }
+            info += &format!("BRF:{}\nBRH:{}\n", found, hit);
+
+            for (line, count) in file.lines.iter() {
+                info += &format!("DA:{},{}\n", line, count);
+            }
+            info += &format!("LF:{}\nLH:{}\nend_of_record\n", file.lines.len(), file.lines.values().filter(|count| **count > 0).count());
+        }
+
+        info
+    }
+}
+
+// Tests
+#[cfg(test)]
+mod tests {
+    use crate::avrcore::Avrcore;
+    use crate::coverage::Coverage;
+    use crate::dwarf::{LineRow, LineTable};
+    use crate::elfreader::{Symbol, SymbolBinding, SymbolKind};
+    use crate::listing::SymbolMap;
+
+    #[test]
+    fn lcov_and_report() {
+        // main: ldi r16, 1; breq .+4; jmp main; ldi r17, 2
+        let progmem = [0x01, 0xE0, 0x11, 0xF0, 0x0C, 0x94, 0x00, 0x00, 0x12, 0xE0];
+        let mut core = Avrcore::from_bytes(&progmem);
+        let mut coverage = Coverage::new();
+
+        // The branch falls through the first time and is taken the second
+        for step in 0..6 {
+            core.sreg.Z = step >= 3;
+            let pc = coverage.before(&core);
+            core.execute().unwrap();
+            coverage.after(pc, &core);
+        }
+        assert_eq!((coverage.hits(0), coverage.hits(2), coverage.hits(4), coverage.hits(8)), (2, 2, 1, 1));
+        assert_eq!(coverage.branch(2), Some((1, 1)));
+        assert_eq!(core.cycles, 1 + 1 + 3 + 1 + 2 + 1);
+
+        let lines = LineTable {
+            files: vec![String::from("main.c")],
+            rows: vec![
+                LineRow { address: 0, file: 0, line: 3, end_sequence: false },
+                LineRow { address: 8, file: 0, line: 5, end_sequence: false },
+                LineRow { address: 10, file: 0, line: 0, end_sequence: true },
+            ],
+        };
+        let main = Symbol {
+            name: String::from("main"),
+            value: 0,
+            size: 10,
+            kind: SymbolKind::Function,
+            binding: SymbolBinding::Global,
+            absolute: false,
+        };
+        assert_eq!(coverage.lcov(&core, &lines, &[main]),
+                   "TN:\nSF:main.c\nFN:3,main\nFNDA:2,main\nFNF:1\nFNH:1\nBRDA:3,0,0,1\nBRDA:3,0,1,1\nBRF:2\nBRH:2\nDA:3,2\nDA:5,1\nLF:2\nLH:2\nend_of_record\n");
+
+        let report = coverage.report(&core, &SymbolMap::parse_nm("00000000 T main\n"));
+        assert!(report.starts_with("4 of 4 instructions executed, 2 of 2 branch outcomes taken\n\n00000000 <main>:\n"));
+        assert!(report.contains("         2:    2:\tbreq\t.+4      \t; taken 1, not taken 1\n"));
+        assert!(report.ends_with("         1:    8:\tldi\tr17, 0x02\n"));
+    }
+}
@@ -0,0 +1,216 @@
+use crate::disassembler::{self, Decoded};
+use crate::elfreader::{SymbolKind, DATA_OFFSET};
+use crate::instructions::{Instruction, Opcodes};
+use crate::interrupts::{Interrupt, VECTOR_COUNT};
+use crate::listing::{self, SymbolMap};
+use crate::memimage::Program;
+use std::ops::Range;
+
+const VECTOR_SIZE: usize = 4;
+
+// How code is told apart from data
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum DisasmMode {
+    Sweep, // Decode everything outside known data regions
+    Recursive, // Follow control flow from the vectors
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum OutputFormat {
+    Text,
+    Json,
+}
+
+// One disassembled instruction or data word
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DisasmLine {
+    pub address: usize,
+    pub bytes: Vec<u8>,
+    pub label: Option<String>, // Symbol starting at this address
+    pub mnemonic: String, // `.word` for data
+    pub operands: String,
+    pub comment: Option<String>,
+    pub data: bool,
+}
+
+// Flash ranges holding data rather than code: PROGMEM objects and the
+// initialisers of `.data`. Only ELF images describe these.
+pub fn data_regions(program: &Program) -> Vec<Range<usize>> {
+    let elf = match &program.elf {
+        Some(elf) => elf,
+        None => return Vec::new()
+    };
+
+    let objects = elf.flash_symbols().into_iter()
+        .filter(|symbol| symbol.kind == SymbolKind::Object && symbol.size > 0)
+        .map(|symbol| symbol.value as usize..(symbol.value + symbol.size) as usize);
+
+    // Sections that run from RAM but are stored in flash
+    let initialisers = elf.sections.iter()
+        .filter(|section| section.vma >= DATA_OFFSET && section.lma < DATA_OFFSET)
+        .map(|section| section.lma as usize..section.lma as usize + section.data.len());
+
+    objects.chain(initialisers).collect()
+}
+
+// Whether the image starts with an interrupt vector table of jumps
+fn has_vector_table(flash: &[u8]) -> bool {
+    matches!(disassembler::decode_at(flash, 0), Some(Opcodes::JMP(_)) | Some(Opcodes::RJMP(_)))
+}
+
+// Name of the vector table entry at `addr`
+pub fn vector_name(addr: usize) -> Option<&'static str> {
+    if !addr.is_multiple_of(VECTOR_SIZE) || addr >= VECTOR_COUNT as usize * VECTOR_SIZE {
+        return None
+    }
+
+    match addr / VECTOR_SIZE {
+        0 => Some("RESET"),
+        vector => Interrupt::from_vector(vector as u8).map(|irq| irq.name())
+    }
+}
+
+// Where execution can start: the reset and interrupt vectors and the ELF entry point
+pub fn entry_points(program: &Program) -> Vec<usize> {
+    let mut entries = vec![0];
+    if has_vector_table(&program.flash) {
+        entries = (0..VECTOR_COUNT as usize).map(|vector| vector * VECTOR_SIZE).collect();
+    }
+    if let Some(elf) = &program.elf {
+        entries.push(elf.entry as usize);
+    }
+    entries
+}
+
+// Disassemble the flash bytes in `range`, never stopping at undecodable words
+pub fn disassemble(program: &Program, symbols: &SymbolMap, range: Range<usize>, mode: DisasmMode) -> Vec<DisasmLine> {
+    let flash = &program.flash;
+    let vectors = has_vector_table(flash);
+
+    let decoded = match mode {
+        DisasmMode::Sweep => disassembler::sweep(flash, range.start, range.end, &data_regions(program)),
+        DisasmMode::Recursive => {
+            let code = disassembler::reachable(flash, &entry_points(program));
+            disassembler::descend(flash, range.start, range.end, &code)
+        }
+    };
+
+    decoded.into_iter()
+        .map(|(address, decoded)| {
+            let bytes = flash[address..address + decoded.size()].to_vec();
+            let label = symbols.label_at(address as u32).map(String::from);
+
+            let (mnemonic, operands, comment, data) = match decoded {
+                Decoded::Instruction(opcode) => {
+                    let (mnemonic, operands, _) = opcode.objdump();
+                    let mut comment = listing::objdump_comment(address, &opcode, symbols);
+                    if let Some(name) = vector_name(address).filter(|_| vectors) {
+                        comment = Some(format!("{} {}", comment.unwrap_or_default(), name));
+                    }
+                    (mnemonic, operands.trim_end().to_string(), comment, false)
+                },
+                Decoded::Data(word) => (String::from(".word"), format!("{:#06x}", word), None, true)
+            };
+
+            DisasmLine { address, bytes, label, mnemonic, operands, comment, data }
+        })
+        .collect()
+}
+
+pub fn render_text(lines: &[DisasmLine]) -> String {
+    let mut text = String::new();
+
+    for line in lines {
+        if let Some(label) = &line.label {
+            text += &format!("\n{:08x} <{}>:\n", line.address, label);
+        }
+
+        text += &listing::format_line(line.address, &line.bytes, &line.mnemonic, &line.operands, line.comment.as_deref());
+        text += "\n";
+    }
+
+    text
+}
+
+pub fn json_string(value: &str) -> String {
+    let mut escaped = String::from("\"");
+    for c in value.chars() {
+        match c {
+            '"' => escaped += "\\\"",
+            '\\' => escaped += "\\\\",
+            '\n' => escaped += "\\n",
+            '\t' => escaped += "\\t",
+            c if (c as u32) < 0x20 => escaped += &format!("\\u{:04x}", c as u32),
+            c => escaped.push(c)
+        }
+    }
+    escaped.push('"');
+    escaped
+}
+
+fn json_option(value: &Option<String>) -> String {
+    match value {
+        Some(value) => json_string(value.trim_start()),
+        None => String::from("null")
+    }
+}
+
+// A JSON array with one object per line
+pub fn render_json(lines: &[DisasmLine]) -> String {
+    let objects: Vec<String> = lines.iter()
+        .map(|line| {
+            let bytes: String = line.bytes.iter().map(|byte| format!("{:02x}", byte)).collect();
+            format!("  {{\"address\": {}, \"bytes\": \"{}\", \"kind\": \"{}\", \"label\": {}, \"mnemonic\": {}, \"operands\": {}, \"comment\": {}}}",
+                    line.address,
+                    bytes,
+                    if line.data { "data" } else { "instruction" },
+                    json_option(&line.label),
+                    json_string(&line.mnemonic),
+                    json_string(&line.operands),
+                    json_option(&line.comment))
+        })
+        .collect();
+
+    format!("[\n{}\n]\n", objects.join(",\n"))
+}
+
+// Tests
+#[cfg(test)]
+mod tests {
+    use crate::disasm::{disassemble, render_json, vector_name, DisasmMode};
+    use crate::listing::SymbolMap;
+    use crate::memimage::Program;
+
+    #[test]
+    fn data_and_vectors() {
+        // jmp 0x68, then a word that does not decode and an out instruction
+        let program = Program::from_bytes(&[0x0C, 0x94, 0x34, 0x00, 0xFF, 0xFF, 0x1F, 0xBE], 0).unwrap();
+        let lines = disassemble(&program, &SymbolMap::default(), 0..8, DisasmMode::Sweep);
+
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0].comment.as_deref(), Some("  0x68 RESET"));
+        assert!(lines[1].data);
+        assert_eq!(lines[1].operands, "0xffff");
+        assert_eq!(lines[2].mnemonic, "out");
+        assert_eq!(vector_name(0x04), Some("INT0"));
+
+        let json = render_json(&lines[1..2]);
+        assert_eq!(json, "[\n  {\"address\": 4, \"bytes\": \"ffff\", \"kind\": \"data\", \"label\": null, \"mnemonic\": \".word\", \"operands\": \"0xffff\", \"comment\": null}\n]\n");
+    }
+
+    #[test]
+    fn elf_range() {
+        let program = Program::load("testprogram.bin", 0).unwrap();
+        let symbols = SymbolMap::from_elf(program.elf.as_ref().unwrap());
+        let lines = disassemble(&program, &symbols, 0x80..0x90, DisasmMode::Sweep);
+
+        assert_eq!(lines[0].label.as_deref(), Some("main"));
+        assert_eq!(lines[0].mnemonic, "push");
+        assert!(lines.iter().all(|line| !line.data));
+
+        // Everything in the test program is reachable from the vectors
+        let end = program.flash.len();
+        assert_eq!(disassemble(&program, &symbols, 0..end, DisasmMode::Recursive),
+                   disassemble(&program, &symbols, 0..end, DisasmMode::Sweep));
+    }
+}
@@ -1,37 +1,162 @@
 use crate::instructions::*;
+use crate::error::DecodeError;
 use crate::hexreader::IhexDump;
+use std::collections::BTreeSet;
+use std::ops::Range;
 
 
 
 enum Status {
-    EOF,
-    DissasmError(String)
+    Eof,
+    DissasmError(String) // Reserved or not yet supported encoding
 }
 
+// Words that do not decode become `.word` entries, which only fault if executed
 pub fn dissasm_ihex(mut ihex: IhexDump) -> (Vec<Opcodes>, Vec<usize>) {
     let mut dissasm: Vec<Opcodes> = Vec::new();
     let mut flash_index: Vec<usize> = Vec::new();
 
     loop {
-        flash_index.push(ihex.getIndex());
+        let address = ihex.get_index();
         match match_and_decode(&mut ihex) {
-            Ok(decoded) => {
-                dissasm.push(decoded);
-            },
-            Err(err) => {
-                match err {
-                    Status::EOF => break,
-                    Status::DissasmError(msg) => panic!("Failed to decode ihex: {}", msg)
-                }
+            Ok(decoded) => dissasm.push(decoded),
+            Err(Status::Eof) => break,
+            Err(Status::DissasmError(_)) => {
+                // Decoding resumes at the following word
+                let word = ihex.word_at(address).unwrap_or(0xFFFF);
+                dissasm.push(Opcodes::WORD(WORDInstruction { word }));
             }
         }
+        flash_index.push(address);
     }
 
-    flash_index.remove(flash_index.len()-1);
-
     (dissasm, flash_index)
 }
 
+// Disassemble a little endian byte image
+pub fn dissasm_bytes(image: &[u8]) -> (Vec<Opcodes>, Vec<usize>) {
+    let words = image.chunks(2)
+        .map(|bytes| (*bytes.get(1).unwrap_or(&0xFF) as u16) << 8 | bytes[0] as u16)
+        .collect();
+
+    dissasm_ihex(IhexDump::from_words(words))
+}
+
+// Like `dissasm_bytes`, but the first word that does not decode is an error
+pub fn dissasm_strict(image: &[u8]) -> Result<(Vec<Opcodes>, Vec<usize>), DecodeError> {
+    let (dissasm, flash_index) = dissasm_bytes(image);
+
+    for (opcode, address) in dissasm.iter().zip(flash_index.iter()) {
+        if let Opcodes::WORD(_) = opcode {
+            decode_strict(image, *address)?;
+        }
+    }
+
+    Ok((dissasm, flash_index))
+}
+
+// Decode the instruction starting at byte address `addr` of a raw program memory image
+pub fn decode_at(progmem: &[u8], addr: usize) -> Option<Opcodes> {
+    decode_strict(progmem, addr).ok()
+}
+
+// Like `decode_at`, with the reason a word does not decode
+pub fn decode_strict(progmem: &[u8], addr: usize) -> Result<Opcodes, DecodeError> {
+    let end = (addr + 4).min(progmem.len());
+    let words: Vec<u16> = progmem[addr.min(end)..end]
+        .chunks(2)
+        .filter(|bytes| bytes.len() == 2)
+        .map(|bytes| (bytes[1] as u16) << 8 | bytes[0] as u16)
+        .collect();
+    let word = words.first().copied().unwrap_or(0xFFFF);
+
+    match match_and_decode(&mut IhexDump::from_words(words)) {
+        Ok(opcode) => Ok(opcode),
+        Err(Status::Eof) => Err(DecodeError { address: addr, word, reason: String::from("past the end of program memory") }),
+        Err(Status::DissasmError(reason)) => Err(DecodeError { address: addr, word, reason })
+    }
+}
+
+// One location of a linear sweep
+#[derive(Debug, Copy, Clone)]
+pub enum Decoded {
+    Instruction(Opcodes),
+    Data(u16), // A word inside a data region, or one that does not decode
+}
+
+impl Decoded {
+    // Size in bytes
+    pub fn size(&self) -> usize {
+        match self {
+            Decoded::Instruction(opcode) => opcode.size() as usize,
+            Decoded::Data(_) => 2
+        }
+    }
+}
+
+// Decode `progmem[start..end]` one location after the other. Words in `data`
+// and words that do not decode are returned as data rather than ending the sweep.
+pub fn sweep(progmem: &[u8], start: usize, end: usize, data: &[Range<usize>]) -> Vec<(usize, Decoded)> {
+    let in_data = |addr: usize| data.iter().any(|range| range.contains(&addr));
+
+    // A two word instruction must not run into data either
+    decode_range(progmem, start, end, |addr, size| !(addr..addr + size).step_by(2).any(in_data))
+}
+
+// Like `sweep`, but only the instructions found by `reachable` are decoded
+pub fn descend(progmem: &[u8], start: usize, end: usize, code: &BTreeSet<usize>) -> Vec<(usize, Decoded)> {
+    decode_range(progmem, start, end, |addr, _| code.contains(&addr))
+}
+
+fn decode_range<F: Fn(usize, usize) -> bool>(progmem: &[u8], start: usize, end: usize, is_code: F) -> Vec<(usize, Decoded)> {
+    let end = end.min(progmem.len());
+    let mut decoded = Vec::new();
+    let mut addr = start & !1;
+
+    while addr + 2 <= end {
+        let word = (progmem[addr + 1] as u16) << 8 | progmem[addr] as u16;
+
+        let item = match decode_at(progmem, addr) {
+            Some(opcode) if addr + opcode.size() as usize <= end
+                && is_code(addr, opcode.size() as usize) => Decoded::Instruction(opcode),
+            _ => Decoded::Data(word)
+        };
+
+        decoded.push((addr, item));
+        addr += item.size();
+    }
+
+    decoded
+}
+
+// Byte addresses of the instructions reachable from `entries` by following
+// jumps, calls and fall through. Returns and indirect jumps end a path.
+pub fn reachable(progmem: &[u8], entries: &[usize]) -> BTreeSet<usize> {
+    let mut code = BTreeSet::new();
+    let mut pending = entries.to_vec();
+
+    while let Some(addr) = pending.pop() {
+        if addr % 2 != 0 || addr >= progmem.len() || code.contains(&addr) {
+            continue
+        }
+
+        let opcode = match decode_at(progmem, addr) {
+            Some(opcode) => opcode,
+            None => continue
+        };
+        code.insert(addr);
+
+        if let Some(target) = opcode.branch_target(addr as u32) {
+            pending.push(target as usize);
+        }
+        if opcode.falls_through() {
+            pending.push(addr + opcode.size() as usize);
+        }
+    }
+
+    code
+}
+
 /*
 pub fn disassm_next(core: &mut Avrcore) -> Opcodes {
     let decoded = match_and_decode(core).unwrap();
@@ -41,21 +166,24 @@ pub fn disassm_next(core: &mut Avrcore) -> Opcodes {
  */
 
 fn match_and_decode(ihex: &mut IhexDump) -> Result<Opcodes, Status> {
-    let raw_opcode: u16;
-
-    match ihex.get_next_word() {
-        Ok(word) => raw_opcode = word,
-        Err(_) => return Err(Status::EOF)
+    let raw_opcode = match ihex.get_next_word() {
+        Ok(word) => word,
+        Err(_) => return Err(Status::Eof)
     };
 
     //let raw_opcode = ihex.get_next_word();
-    
+
+    // NOP
+    if raw_opcode == 0x0000 {
+        Ok(Opcodes::NOP(NOPInstruction { }))
+    }
+
     // JMP
-    if bitpat!(1 0 0 1 0 1 0 _ _ _ _ _ 1 1 0 _)(raw_opcode) {
-        let word2:u16;
-        match ihex.get_next_word() {
-            Ok(word) => word2 = word,
-            Err(_) => return Err(Status::EOF)
+    else if bitpat!(1 0 0 1 0 1 0 _ _ _ _ _ 1 1 0 _)(raw_opcode) {
+        // A two word instruction cut off by the end of the image
+        let word2 = match ihex.get_next_word() {
+            Ok(word) => word,
+            Err(_) => return Err(Status::DissasmError(String::from("JMP cut off by the end of the image")))
         };
 
         Ok( Opcodes::JMP(decode_jmp(vec![raw_opcode, word2])))
@@ -79,10 +207,10 @@ fn match_and_decode(ihex: &mut IhexDump) -> Result<Opcodes, Status> {
 
     // CALL
     else if bitpat!(1 0 0 1 0 1 0 _ _ _ _ _ 1 1 1 _)(raw_opcode){
-        let word2: u16;
-        match ihex.get_next_word() {
-            Ok(word) => word2 = word,
-            Err(_) => return Err(Status::EOF)
+        // A two word instruction cut off by the end of the image
+        let word2 = match ihex.get_next_word() {
+            Ok(word) => word,
+            Err(_) => return Err(Status::DissasmError(String::from("CALL cut off by the end of the image")))
         };
 
         Ok( Opcodes::CALL(decode_call(vec![raw_opcode, word2])))
@@ -105,58 +233,55 @@ fn match_and_decode(ihex: &mut IhexDump) -> Result<Opcodes, Status> {
         Ok( Opcodes::IN(decode_in(raw_opcode)))
     }
 
-    // STD Y Unchanged
-    // TODO: IMPLEMENT ME
-    else if bitpat!(1 0 0 0 0 0 1 _ _ _ _ _ 1 0 0 0)(raw_opcode){
-        let error_str = format!("STD Y unchanged UNIMPLEMENTED: {:#x}", raw_opcode);
-        Err(Status::DissasmError(error_str))
-    }
-    
-    // STD Y Post incremented
-    // TODO: IMPLEMENT ME
-    else if bitpat!(1 0 0 1 0 0 1 _ _ _ _ _ 1 0 0 1)(raw_opcode){
-        let error_str = format!("STD Y Post incremented UNIMPLEMENTED: {:#x}", raw_opcode);
-        Err(Status::DissasmError(error_str))
+    // LPM, implied R0
+    else if bitpat!(1 0 0 1 0 1 0 1 1 1 0 0 1 0 0 0)(raw_opcode){
+        Ok(Opcodes::LPM(LPMInstruction { rd: 0, post_increment: false, implied: true }))
     }
 
-    // STD Y Pre decremented
-    // TODO: IMPLEMENT ME
-    else if bitpat!(1 0 0 1 0 0 1 _ _ _ _ _ 1 0 1 0)(raw_opcode){
-        let error_str = format!("STD Y Pre decremented UNIMPLEMENTED: {:#x}", raw_opcode);
-        Err(Status::DissasmError(error_str))
+    // ELPM, implied R0
+    else if bitpat!(1 0 0 1 0 1 0 1 1 1 0 1 1 0 0 0)(raw_opcode){
+        Ok(Opcodes::ELPM(ELPMInstruction { rd: 0, post_increment: false, implied: true }))
     }
 
-    // STD Y Unchanged, q: Displacement
-    else if bitpat!(1 0 _ 0 _ _ 1 _ _ _ _ _ 1 _ _ _)(raw_opcode){
-        Ok(Opcodes::STDy(decode_stdy(raw_opcode)))
+    // LPM Rd, Z and LPM Rd, Z+
+    else if bitpat!(1 0 0 1 0 0 0 _ _ _ _ _ 0 1 0 _)(raw_opcode){
+        Ok(Opcodes::LPM(decode_lpm(raw_opcode)))
     }
 
-    else if bitpat!(1 0 0 0 0 0 0 _ _ _ _ _ 1 0 0 0)(raw_opcode){
-        panic!("TODO: implement LDD Y: Unchanged")
+    // ELPM Rd, Z and ELPM Rd, Z+
+    else if bitpat!(1 0 0 1 0 0 0 _ _ _ _ _ 0 1 1 _)(raw_opcode){
+        Ok(Opcodes::ELPM(decode_elpm(raw_opcode)))
     }
 
-    else if bitpat!(1 0 0 1 0 0 0 _ _ _ _ _ 1 0 0 1)(raw_opcode){
-        panic!("TODO: implement LDD Y: Post incremented")
+    // STD Y and LDD Y unchanged, post incremented and pre decremented
+    else if bitpat!(1 0 0 0 0 0 1 _ _ _ _ _ 1 0 0 0)(raw_opcode)
+        || bitpat!(1 0 0 1 0 0 1 _ _ _ _ _ 1 0 0 1)(raw_opcode)
+        || bitpat!(1 0 0 1 0 0 1 _ _ _ _ _ 1 0 1 0)(raw_opcode)
+        || bitpat!(1 0 0 0 0 0 0 _ _ _ _ _ 1 0 0 0)(raw_opcode)
+        || bitpat!(1 0 0 1 0 0 0 _ _ _ _ _ 1 0 0 1)(raw_opcode)
+        || bitpat!(1 0 0 1 0 0 0 _ _ _ _ _ 1 0 1 0)(raw_opcode) {
+        Err(Status::DissasmError(format!("STD/LDD Y without displacement is not supported: {:#x}", raw_opcode)))
     }
 
-    else if bitpat!(1 0 0 1 0 0 0 _ _ _ _ _ 1 0 1 0)(raw_opcode){
-        panic!("TODO: implement LDD Y: Pre decremented")
+    // STD Y Unchanged, q: Displacement
+    else if bitpat!(1 0 _ 0 _ _ 1 _ _ _ _ _ 1 _ _ _)(raw_opcode){
+        Ok(Opcodes::STDy(decode_stdy(raw_opcode)?))
     }
 
     else if bitpat!(1 0 _ 0 _ _ 0 _ _ _ _ _ 1 _ _ _)(raw_opcode) {
-        Ok(Opcodes::LDDy(decode_lddy(raw_opcode)))
+        Ok(Opcodes::LDDy(decode_lddy(raw_opcode)?))
     }
 
     else if bitpat!(0 0 0 0 1 1 _ _ _ _ _ _ _ _ _ _)(raw_opcode) {
-        Ok(Opcodes::ADD(decode_add(raw_opcode)))
+        Ok(Opcodes::ADD(decode_add(raw_opcode)?))
     }
 
     else if bitpat!(0 0 0 1 1 1 _ _ _ _ _ _ _ _ _ _)(raw_opcode) {
-        Ok(Opcodes::ADC(decode_adc(raw_opcode)))
+        Ok(Opcodes::ADC(decode_adc(raw_opcode)?))
     }
 
     else if bitpat!(1 0 0 1 0 0 0 _ _ _ _ _ 1 1 1 1)(raw_opcode) {
-        Ok(Opcodes::POP(decode_pop(raw_opcode)))
+        Ok(Opcodes::POP(decode_pop(raw_opcode)?))
     }
 
     else if bitpat!(1 0 0 1 0 1 0 1 0 0 0 0 1 0 0 0)(raw_opcode) {
@@ -167,16 +292,38 @@ fn match_and_decode(ihex: &mut IhexDump) -> Result<Opcodes, Status> {
         Ok(Opcodes::CLI(CLIInstruction { }))
     }
 
+    else if bitpat!(1 0 0 1 0 1 0 0 0 1 1 1 1 0 0 0)(raw_opcode) {
+        Ok(Opcodes::SEI(SEIInstruction { }))
+    }
+
+    else if bitpat!(1 0 0 1 0 1 0 1 0 0 0 1 1 0 0 0)(raw_opcode) {
+        Ok(Opcodes::RETI(RETIInstruction { }))
+    }
+
+    else if bitpat!(1 0 0 1 0 1 0 1 1 0 0 0 1 0 0 0)(raw_opcode) {
+        Ok(Opcodes::SLEEP(SLEEPInstruction { }))
+    }
+
+    else if bitpat!(1 0 0 1 0 1 0 1 1 1 1 0 1 0 0 0)(raw_opcode) {
+        Ok(Opcodes::SPM(SPMInstruction { }))
+    }
+
     else if bitpat!(1 1 0 0 _ _ _ _ _ _ _ _ _ _ _ _)(raw_opcode) {
         Ok(Opcodes::RJMP(decode_rjmp(raw_opcode)))
     }
 
-    else if raw_opcode == 0x0 {
-        Err(Status::EOF)
+    else if bitpat!(1 1 1 1 0 0 _ _ _ _ _ _ _ _ _ _)(raw_opcode) {
+        let (s, k) = decode_branch(raw_opcode);
+        Ok(Opcodes::BRBS(BRBSInstruction { s, k }))
     }
+
+    else if bitpat!(1 1 1 1 0 1 _ _ _ _ _ _ _ _ _ _)(raw_opcode) {
+        let (s, k) = decode_branch(raw_opcode);
+        Ok(Opcodes::BRBC(BRBCInstruction { s, k }))
+    }
+
     else {
-        let error_str = format!("unknown opcode signature: {:#x}", raw_opcode);
-        Err(Status::DissasmError(error_str))
+        Err(Status::DissasmError(format!("unknown opcode signature: {:#x}", raw_opcode)))
         //println!("{:x} - unimplemented opcode", raw_opcode)
     }
 }
@@ -301,7 +448,7 @@ fn decode_in(opcode_word: u16) -> INInstruction {
     }
 }
 
-fn decode_stdy(opcode_word: u16) -> STDyInstruction {
+fn decode_stdy(opcode_word: u16) -> Result<STDyInstruction, Status> {
     // Extract q
     let mask = 0b10110000000111u16;
     let masked = mask & opcode_word;
@@ -317,19 +464,19 @@ fn decode_stdy(opcode_word: u16) -> STDyInstruction {
     // Sanity check
     // 0 ≤ r ≤ 31, 0 ≤ q ≤ 63
     if rr > 31 {
-        panic!("Rr is out of range for STD Y+q, Rr. Value was: {}. Aborting!", rr)
+        return Err(Status::DissasmError(format!("Rr is out of range for STD Y+q, Rr. Value was: {}", rr)))
     }
     if q > 63 {
-        panic!("q is out of range for STD Y+q, Rr. Value was: {}. Aborting!", q)
+        return Err(Status::DissasmError(format!("q is out of range for STD Y+q, Rr. Value was: {}", q)))
     }
 
-    STDyInstruction {
+    Ok(STDyInstruction {
         rr: rr as u8,
         q: q as u8
-    }
+    })
 }
 
-fn decode_lddy(opcode_word: u16) -> LDDyInstruction {
+fn decode_lddy(opcode_word: u16) -> Result<LDDyInstruction, Status> {
     // Extract q
     let mask = 0b0010110000000111u16;
     let masked = mask & opcode_word;
@@ -345,19 +492,19 @@ fn decode_lddy(opcode_word: u16) -> LDDyInstruction {
     // Sanity check
     // 0 ≤ d ≤ 31, 0 ≤ q ≤ 63
     if rd > 31 {
-        panic!("Rd is out of range for LDD Rd, Y+q. Value was: {}. Aborting!", rd)
+        return Err(Status::DissasmError(format!("Rd is out of range for LDD Rd, Y+q. Value was: {}", rd)))
     }
     if q > 63 {
-        panic!("q is out of range for LDD Rd, Y+q. Value was {}. Aborting!", q)
+        return Err(Status::DissasmError(format!("q is out of range for LDD Rd, Y+q. Value was: {}", q)))
     }
 
-    LDDyInstruction {
+    Ok(LDDyInstruction {
         rd: rd as u8,
         q: q as u8
-    }
+    })
 }
 
-fn decode_add(opcode_word: u16) -> ADDInstruction {
+fn decode_add(opcode_word: u16) -> Result<ADDInstruction, Status> {
     // Extract Rr
     let mask = 0b1000001111u16;
     let masked = mask & opcode_word;
@@ -371,20 +518,20 @@ fn decode_add(opcode_word: u16) -> ADDInstruction {
     // Sanity checks
     // 0 ≤ d ≤ 31, 0 ≤ r ≤ 31
     if rd > 31 {
-        panic!("Rd is out of range for ADD Rd,Rr. Value was: {}", rd)
+        return Err(Status::DissasmError(format!("Rd is out of range for ADD Rd,Rr. Value was: {}", rd)))
     }
     if rr > 31 {
-        panic!("Rr is out of range for ADD Rd,Rr. Value was: {}", rr)
+        return Err(Status::DissasmError(format!("Rr is out of range for ADD Rd,Rr. Value was: {}", rr)))
     }
 
-    ADDInstruction {
+    Ok(ADDInstruction {
         rd: rd as u8,
         rr: rr as u8
-    }
+    })
 }
 
 
-fn decode_adc(opcode_word: u16) -> ADCInstruction {
+fn decode_adc(opcode_word: u16) -> Result<ADCInstruction, Status> {
     // Extract Rr
     let mask = 0b1000001111u16;
     let masked = mask & opcode_word;
@@ -398,19 +545,19 @@ fn decode_adc(opcode_word: u16) -> ADCInstruction {
     // Sanity checks
     // 0 ≤ d ≤ 31, 0 ≤ r ≤ 31
     if rd > 31 {
-        panic!("Rd is out of range for ADC Rd,Rr. Value was: {}", rd)
+        return Err(Status::DissasmError(format!("Rd is out of range for ADC Rd,Rr. Value was: {}", rd)))
     }
     if rr > 31 {
-        panic!("Rr is out of range for ADC Rd,Rr. Value was: {}", rr)
+        return Err(Status::DissasmError(format!("Rr is out of range for ADC Rd,Rr. Value was: {}", rr)))
     }
 
-    ADCInstruction {
+    Ok(ADCInstruction {
         rd: rd as u8,
         rr: rr as u8
-    }
+    })
 }
 
-fn decode_pop(opcode_word: u16) -> POPInstruction {
+fn decode_pop(opcode_word: u16) -> Result<POPInstruction, Status> {
     // Extract Rd
     let mask = 0b111110000u16;
     let masked = mask & opcode_word;
@@ -419,11 +566,35 @@ fn decode_pop(opcode_word: u16) -> POPInstruction {
 
     // Sanity check
     if rd > 31 {
-        panic!("Rd is out of range for POP Rd. Value was: {}", rd)
+        return Err(Status::DissasmError(format!("Rd is out of range for POP Rd. Value was: {}", rd)))
     }
 
-    POPInstruction {
+    Ok(POPInstruction {
         rd: rd as u8
+    })
+}
+
+fn decode_lpm(opcode_word: u16) -> LPMInstruction {
+    // Extract Rd
+    let mask = 0b111110000u16;
+    let rd = (mask & opcode_word) >> 4;
+
+    LPMInstruction {
+        rd: rd as u8,
+        post_increment: opcode_word & 0b1 != 0,
+        implied: false
+    }
+}
+
+fn decode_elpm(opcode_word: u16) -> ELPMInstruction {
+    // Extract Rd
+    let mask = 0b111110000u16;
+    let rd = (mask & opcode_word) >> 4;
+
+    ELPMInstruction {
+        rd: rd as u8,
+        post_increment: opcode_word & 0b1 != 0,
+        implied: false
     }
 }
 
@@ -438,12 +609,8 @@ fn decode_rjmp(opcode_word: u16) -> RJMPInstruction {
      */
     // This is stolen from https://github.com/buserror/simavr/blob/a56b550872906a971ac128002772d90c9e30377d/simavr/sim/sim_core.c#L449
     // TODO: Why does this work?
-    let k = (((opcode_word << 4) & 0xffff) as i16) >> 3;
-
-    // Sanity check
-    if k <= -2000 || k >= 2000 {
-        panic!("k is out of range for RJMP. Value was: {}", k)
-    }
+    // Sign extended 12 bit word offset in bytes, -4096 to 4094
+    let k = ((opcode_word << 4) as i16) >> 3;
 
     RJMPInstruction {
         k
@@ -451,11 +618,22 @@ fn decode_rjmp(opcode_word: u16) -> RJMPInstruction {
 
 }
 
+// SREG bit and sign extended 7 bit word offset in bytes of BRBS and BRBC
+fn decode_branch(opcode_word: u16) -> (u8, i16) {
+    let s = (opcode_word & 0b111) as u8;
+    let k = ((opcode_word << 6) as i16) >> 8 & !1;
+
+    (s, k)
+}
+
 // Tests
 #[cfg(test)]
 mod tests {
-    use crate::disassembler::{Opcodes, decode_eor, EORInstruction};
-
+    use crate::avrcore::Avrcore;
+    use crate::disassembler::{decode_eor, dissasm_bytes, dissasm_strict, reachable, EORInstruction};
+    use crate::error::DecodeError;
+    use crate::instructions::{Instruction, Opcodes};
+    
     #[test]
     fn eor() {
         // The following contains all possible operator combinations for EOR.
@@ -475,4 +653,62 @@ mod tests {
             assert_eq!(decoded.rr, expected.rr)
         }
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn undecodable_words() {
+        // eor r1, r1, the reserved word 0xFFFF, nop and a jmp cut off by the end of the image
+        let (dissasm, flash_idx) = dissasm_bytes(&[0x11, 0x24, 0xFF, 0xFF, 0x00, 0x00, 0x0C, 0x94]);
+
+        assert_eq!(flash_idx, vec![0, 2, 4, 6]);
+        assert!(matches!(dissasm[1], Opcodes::WORD(_)));
+        assert_eq!(dissasm[1].objdump(), (String::from(".word"), String::from("0xffff"), Some(String::from("????"))));
+        assert!(matches!(dissasm[2], Opcodes::NOP(_)));
+        assert_eq!(dissasm[2].objdump().0, "nop");
+        let mut core = Avrcore::from_bytes(&[0x00, 0x00]);
+        core.execute().unwrap();
+        assert_eq!((core.pc, core.cycles), (2, 1));
+        assert!(matches!(dissasm[3], Opcodes::WORD(_)));
+    }
+
+    #[test]
+    fn decode_error() {
+        // eor r1, r1 followed by the reserved word 0xFFFF
+        let err = dissasm_strict(&[0x11, 0x24, 0xFF, 0xFF]).unwrap_err();
+        assert!(dissasm_strict(&[0x00, 0x00, 0x11, 0x24]).is_ok());
+
+        assert_eq!(err, DecodeError { address: 2, word: 0xFFFF, reason: String::from("unknown opcode signature: 0xffff") });
+    }
+
+    #[test]
+    fn rjmp_range() {
+        // rjmp .+4094 and rjmp .-4096, the two ends of the range
+        let mut progmem = vec![0xFF; 0x2002];
+        progmem[0..2].copy_from_slice(&[0xFF, 0xC7]);
+        progmem[0x2000..].copy_from_slice(&[0x00, 0xC8]);
+        let (dissasm, flash_idx) = dissasm_bytes(&progmem);
+
+        assert_eq!(dissasm[0].branch_target(0), Some(0x1000));
+        let last = flash_idx.iter().position(|addr| *addr == 0x2000).unwrap();
+        assert_eq!(dissasm[last].branch_target(0x2000), Some(0x1002));
+    }
+
+    #[test]
+    fn conditional_branches() {
+        // brne .-2 and brcs .+126
+        let (dissasm, _) = dissasm_bytes(&[0xF9, 0xF7, 0xF8, 0xF1]);
+
+        assert_eq!(dissasm[0].objdump().0, "brne");
+        assert_eq!((dissasm[0].branch_target(0), dissasm[0].falls_through()), (Some(0), true));
+        assert_eq!(dissasm[1].objdump().0, "brcs");
+        assert_eq!(dissasm[1].branch_target(2), Some(130));
+    }
+
+    #[test]
+    fn reachable_code() {
+        // rjmp .+2, a data word, out 0x3f, r1 and ret, followed by unreached code
+        let progmem = [0x01, 0xC0, 0x48, 0x69, 0x1F, 0xBE, 0x08, 0x95, 0x1F, 0xBE];
+        let code = reachable(&progmem, &[0]);
+
+        assert_eq!(code.into_iter().collect::<Vec<_>>(), vec![0, 4, 6]);
+    }
+}
@@ -0,0 +1,400 @@
+use crate::elfreader::ElfImage;
+use crate::error::LoadError;
+use crate::snapshot::Reader;
+
+// Standard opcodes of the line number program. The others only set registers
+// that are not kept and are skipped using the lengths in the header.
+const DW_LNS_COPY: u8 = 1;
+const DW_LNS_ADVANCE_PC: u8 = 2;
+const DW_LNS_ADVANCE_LINE: u8 = 3;
+const DW_LNS_SET_FILE: u8 = 4;
+const DW_LNS_CONST_ADD_PC: u8 = 8;
+const DW_LNS_FIXED_ADVANCE_PC: u8 = 9;
+
+const DW_LNE_END_SEQUENCE: u8 = 1;
+const DW_LNE_SET_ADDRESS: u8 = 2;
+const DW_LNE_DEFINE_FILE: u8 = 3;
+
+// DWARF 5 directory and file entry contents and the forms they are stored in
+const DW_LNCT_PATH: u64 = 1;
+const DW_LNCT_DIRECTORY_INDEX: u64 = 2;
+
+const DW_FORM_DATA2: u64 = 0x05;
+const DW_FORM_DATA4: u64 = 0x06;
+const DW_FORM_DATA8: u64 = 0x07;
+const DW_FORM_STRING: u64 = 0x08;
+const DW_FORM_BLOCK: u64 = 0x09;
+const DW_FORM_DATA1: u64 = 0x0B;
+const DW_FORM_STRP: u64 = 0x0E;
+const DW_FORM_UDATA: u64 = 0x0F;
+const DW_FORM_DATA16: u64 = 0x1E;
+const DW_FORM_LINE_STRP: u64 = 0x1F;
+
+const KIND: &str = "DWARF line table";
+
+// One row of the line table. An end of sequence row is the first address after
+// the code of a sequence.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct LineRow {
+    pub address: u32,
+    pub file: usize, // Index into `LineTable::files`
+    pub line: u32,
+    pub end_sequence: bool,
+}
+
+// Source lines of the code in flash, decoded from .debug_line
+#[derive(Debug, Default)]
+pub struct LineTable {
+    pub files: Vec<String>,
+    pub rows: Vec<LineRow>, // Sorted by address
+}
+
+// The string sections .debug_line may refer to
+struct Strings<'a> {
+    debug_str: &'a [u8],
+    line_str: &'a [u8],
+}
+
+fn uleb(reader: &mut Reader) -> Result<u64, LoadError> {
+    let (mut value, mut shift) = (0u64, 0);
+    loop {
+        let byte = reader.u8()?;
+        if shift < 64 {
+            value |= ((byte & 0x7F) as u64) << shift;
+        }
+        shift += 7;
+        if byte & 0x80 == 0 {
+            return Ok(value)
+        }
+    }
+}
+
+fn sleb(reader: &mut Reader) -> Result<i64, LoadError> {
+    let (mut value, mut shift) = (0i64, 0);
+    loop {
+        let byte = reader.u8()?;
+        if shift < 64 {
+            value |= ((byte & 0x7F) as i64) << shift;
+        }
+        shift += 7;
+        if byte & 0x80 == 0 {
+            if shift < 64 && byte & 0x40 != 0 {
+                value |= !0 << shift;
+            }
+            return Ok(value)
+        }
+    }
+}
+
+fn cstring(reader: &mut Reader) -> Result<String, LoadError> {
+    let mut bytes = Vec::new();
+    loop {
+        match reader.u8()? {
+            0 => return Ok(String::from_utf8_lossy(&bytes).into_owned()),
+            byte => bytes.push(byte)
+        }
+    }
+}
+
+fn string_at(section: &[u8], offset: u64) -> Result<String, LoadError> {
+    let mut reader = Reader::new(section.get(offset as usize..).unwrap_or(&[]), KIND);
+    cstring(&mut reader)
+}
+
+fn join(dir: &str, name: &str) -> String {
+    if dir.is_empty() || name.starts_with('/') {
+        name.to_string()
+    } else {
+        format!("{}/{}", dir, name)
+    }
+}
+
+// A DWARF 5 attribute value, which is either a string or a number
+fn form(reader: &mut Reader, form: u64, strings: &Strings) -> Result<(Option<String>, u64), LoadError> {
+    Ok(match form {
+        DW_FORM_STRING => (Some(cstring(reader)?), 0),
+        DW_FORM_STRP => (Some(string_at(strings.debug_str, reader.u32()? as u64)?), 0),
+        DW_FORM_LINE_STRP => (Some(string_at(strings.line_str, reader.u32()? as u64)?), 0),
+        DW_FORM_DATA1 => (None, reader.u8()? as u64),
+        DW_FORM_DATA2 => (None, reader.u16()? as u64),
+        DW_FORM_DATA4 => (None, reader.u32()? as u64),
+        DW_FORM_DATA8 => (None, reader.u64()?),
+        DW_FORM_UDATA => (None, uleb(reader)?),
+        DW_FORM_DATA16 => {
+            reader.take(16)?;
+            (None, 0)
+        },
+        DW_FORM_BLOCK => {
+            let len = uleb(reader)? as usize;
+            reader.take(len)?;
+            (None, 0)
+        },
+        _ => return Err(LoadError::Format(format!("Unsupported form {:#x} in the {}", form, KIND)))
+    })
+}
+
+// A DWARF 5 directory or file name table as paths and directory indices
+fn entry_table(reader: &mut Reader, strings: &Strings) -> Result<Vec<(String, usize)>, LoadError> {
+    let format_count = reader.u8()?;
+    let mut formats = Vec::new();
+    for _ in 0..format_count {
+        formats.push((uleb(reader)?, uleb(reader)?));
+    }
+
+    let mut entries = Vec::new();
+    for _ in 0..uleb(reader)? {
+        let (mut path, mut dir) = (String::new(), 0);
+        for &(content, kind) in formats.iter() {
+            let (text, number) = form(reader, kind, strings)?;
+            match content {
+                DW_LNCT_PATH => path = text.unwrap_or_default(),
+                DW_LNCT_DIRECTORY_INDEX => dir = number as usize,
+                _ => ()
+            }
+        }
+        entries.push((path, dir));
+    }
+
+    Ok(entries)
+}
+
+impl LineTable {
+    // Line table of an ELF image, empty when it was built without debug info
+    pub fn from_elf(elf: &ElfImage) -> Result<LineTable, LoadError> {
+        let strings = Strings {
+            debug_str: elf.debug_section(".debug_str").unwrap_or(&[]),
+            line_str: elf.debug_section(".debug_line_str").unwrap_or(&[]),
+        };
+
+        match elf.debug_section(".debug_line") {
+            Some(debug_line) => LineTable::parse(debug_line, &strings),
+            None => Ok(LineTable::default())
+        }
+    }
+
+    fn parse(debug_line: &[u8], strings: &Strings) -> Result<LineTable, LoadError> {
+        let mut table = LineTable::default();
+        let mut reader = Reader::new(debug_line, KIND);
+
+        while !reader.at_end() {
+            let length = reader.u32()?;
+            if length == 0xFFFF_FFFF {
+                return Err(LoadError::Format(String::from("64 bit DWARF is not supported")))
+            }
+            table.parse_unit(reader.take(length as usize)?, strings)?;
+        }
+
+        // Where one sequence ends and the next starts, the start is the row that applies
+        table.rows.sort_by_key(|row| (row.address, !row.end_sequence));
+        Ok(table)
+    }
+
+    fn intern(&mut self, path: String) -> usize {
+        match self.files.iter().position(|file| *file == path) {
+            Some(idx) => idx,
+            None => {
+                self.files.push(path);
+                self.files.len() - 1
+            }
+        }
+    }
+
+    fn parse_unit(&mut self, unit: &[u8], strings: &Strings) -> Result<(), LoadError> {
+        let mut reader = Reader::new(unit, KIND);
+        let version = reader.u16()?;
+        if !(2..=5).contains(&version) {
+            return Err(LoadError::Format(format!("Unsupported DWARF version {} in the {}", version, KIND)))
+        }
+
+        if version >= 5 {
+            reader.u8()?; // Address size, set_address carries its own length
+            reader.u8()?; // Segment selector size
+        }
+        let header_length = reader.u32()? as usize;
+        let program = reader.position() + header_length;
+        let min_inst_length = reader.u8()? as u64;
+        if version >= 4 {
+            reader.u8()?; // Maximum operations per instruction, always 1 outside VLIW
+        }
+        reader.u8()?; // Default is_stmt
+        let line_base = reader.u8()? as i8 as i64;
+        let line_range = reader.u8()?.max(1);
+        let opcode_base = reader.u8()?;
+        let lengths = reader.take(opcode_base.saturating_sub(1) as usize)?.to_vec();
+
+        // File numbers count from 1 before DWARF 5 and from 0 since
+        let mut files = Vec::new();
+        if version >= 5 {
+            let dirs = entry_table(&mut reader, strings)?;
+            for (name, dir) in entry_table(&mut reader, strings)? {
+                let dir = dirs.get(dir).map_or("", |(path, _)| path.as_str());
+                files.push(Some(self.intern(join(dir, &name))));
+            }
+        } else {
+            let mut dirs = vec![String::new()];
+            loop {
+                match cstring(&mut reader)? {
+                    dir if dir.is_empty() => break,
+                    dir => dirs.push(dir)
+                }
+            }
+
+            files.push(None);
+            loop {
+                let name = cstring(&mut reader)?;
+                if name.is_empty() {
+                    break
+                }
+                let dir = uleb(&mut reader)? as usize;
+                uleb(&mut reader)?; // Modification time
+                uleb(&mut reader)?; // Length
+                files.push(Some(self.intern(join(dirs.get(dir).map_or("", |dir| dir.as_str()), &name))));
+            }
+        }
+
+        let mut reader = Reader::new(unit.get(program..).ok_or(LoadError::Format(format!("{} is truncated", KIND)))?, KIND);
+        let (mut address, mut file, mut line) = (0u64, 1usize, 1i64);
+
+        while !reader.at_end() {
+            let opcode = reader.u8()?;
+            let mut row = false;
+
+            if opcode >= opcode_base {
+                let adjusted = opcode - opcode_base;
+                address = address.wrapping_add((adjusted / line_range) as u64 * min_inst_length);
+                line = line.wrapping_add(line_base + (adjusted % line_range) as i64);
+                row = true;
+            } else {
+                match opcode {
+                    0 => {
+                        let len = uleb(&mut reader)? as usize;
+                        let extended = reader.take(len)?;
+                        let mut operands = Reader::new(extended.get(1..).unwrap_or(&[]), KIND);
+
+                        match extended.first() {
+                            Some(&DW_LNE_END_SEQUENCE) => {
+                                self.rows.push(LineRow { address: address as u32, file: 0, line: 0, end_sequence: true });
+                                address = 0;
+                                file = 1;
+                                line = 1;
+                            },
+                            Some(&DW_LNE_SET_ADDRESS) => {
+                                // AVR targets use 2 byte addresses, others 4
+                                let bytes = extended.get(1..).unwrap_or(&[]);
+                                address = bytes.iter().take(8).rev().fold(0, |value, byte| value << 8 | *byte as u64);
+                            },
+                            Some(&DW_LNE_DEFINE_FILE) => {
+                                let name = cstring(&mut operands)?;
+                                files.push(Some(self.intern(name)));
+                            },
+                            _ => ()
+                        }
+                    },
+                    DW_LNS_COPY => row = true,
+                    DW_LNS_ADVANCE_PC => address = address.wrapping_add(uleb(&mut reader)?.wrapping_mul(min_inst_length)),
+                    DW_LNS_ADVANCE_LINE => line = line.wrapping_add(sleb(&mut reader)?),
+                    DW_LNS_SET_FILE => file = uleb(&mut reader)? as usize,
+                    DW_LNS_CONST_ADD_PC => address = address.wrapping_add(((255 - opcode_base) / line_range) as u64 * min_inst_length),
+                    DW_LNS_FIXED_ADVANCE_PC => address = address.wrapping_add(reader.u16()? as u64),
+                    _ => {
+                        for _ in 0..lengths[opcode as usize - 1] {
+                            uleb(&mut reader)?;
+                        }
+                    }
+                }
+            }
+
+            if let (true, Some(Some(file))) = (row, files.get(file)) {
+                self.rows.push(LineRow { address: address as u32, file: *file, line: line.max(0) as u32, end_sequence: false });
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rows.is_empty()
+    }
+
+    // Source file and line of the code at `address`
+    pub fn lookup(&self, address: u32) -> Option<(&str, u32)> {
+        let idx = self.rows.partition_point(|row| row.address <= address).checked_sub(1)?;
+        let row = &self.rows[idx];
+
+        match row.end_sequence {
+            true => None,
+            false => Some((self.files[row.file].as_str(), row.line))
+        }
+    }
+}
+
+// Tests
+#[cfg(test)]
+mod tests {
+    use crate::coverage::Coverage;
+    use crate::dwarf::{LineTable, Strings};
+    use crate::simulator::Simulator;
+
+    #[test]
+    fn line_program() {
+        // DWARF 2 header: 2 byte instructions, line base -5, line range 14, opcode base 13
+        let mut header = vec![2, 1, 0xFB, 14, 13, 0, 1, 1, 1, 1, 0, 0, 0, 1, 0, 0, 1];
+        header.extend_from_slice(b"src\0\0main.c\0\x01\0\0\0");
+        let program = [
+            0x00, 5, 0x02, 0x80, 0x00, 0x00, 0x00, // set_address 0x80
+            0x03, 2, 0x01, // advance_line 2; copy
+            13 + 34, // special: 2 instructions and one line on
+            0x02, 3, // advance_pc 3 instructions
+            0x00, 1, 0x01, // end_sequence
+        ];
+
+        let section = |program: &[u8]| {
+            let mut unit = vec![2, 0];
+            unit.extend_from_slice(&(header.len() as u32).to_le_bytes());
+            unit.extend_from_slice(&header);
+            unit.extend_from_slice(program);
+            let mut debug_line = (unit.len() as u32).to_le_bytes().to_vec();
+            debug_line.extend_from_slice(&unit);
+            debug_line
+        };
+
+        let table = LineTable::parse(&section(&program), &Strings { debug_str: &[], line_str: &[] }).unwrap();
+        assert_eq!(table.files, vec![String::from("src/main.c")]);
+        assert_eq!(table.lookup(0x7E), None);
+        assert_eq!(table.lookup(0x82), Some(("src/main.c", 3)));
+        assert_eq!(table.lookup(0x88), Some(("src/main.c", 4)));
+        assert_eq!(table.lookup(0x8A), None);
+
+        assert!(LineTable::parse(&[4, 0, 0, 0, 9, 0, 0, 0], &Strings { debug_str: &[], line_str: &[] }).is_err());
+
+        // Huge advances wrap instead of overflowing
+        let mut program = vec![0x02];
+        program.extend_from_slice(&[0xFF; 9]);
+        program.extend_from_slice(&[0x01, 0x03]);
+        program.extend_from_slice(&[0x80; 9]);
+        program.extend_from_slice(&[0x7F, 0x03, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x7F, 255, 0x08, 0x09, 0xFF, 0xFF, 0x01]);
+        assert!(LineTable::parse(&section(&program), &Strings { debug_str: &[], line_str: &[] }).is_ok());
+    }
+
+    #[test]
+    fn testprogram_lines() {
+        let mut sim = Simulator::builder().image_file("testprogram_g.elf").build().unwrap();
+        let lines = LineTable::from_elf(sim.program.elf.as_ref().unwrap()).unwrap();
+        assert_eq!(lines.files, vec![String::from("testprogram.s")]);
+        assert_eq!(lines.lookup(0x00), Some(("testprogram.s", 11)));
+        assert_eq!(lines.lookup(0x0C), Some(("testprogram.s", 21)));
+        assert_eq!(lines.lookup(0x38), Some(("testprogram.s", 44)));
+        assert_eq!(lines.lookup(0x3A), None);
+
+        // Only the pushes, llvm-mc assembles the `rcall .` after them as a call to itself
+        sim.probes.coverage = Some(Coverage::new());
+        sim.step().unwrap();
+        sim.step().unwrap();
+
+        let elf = sim.program.elf.as_ref().unwrap();
+        let lcov = sim.probes.coverage.as_ref().unwrap().lcov(&sim.core, &lines, &elf.symbols);
+        assert!(lcov.starts_with("TN:\nSF:testprogram.s\nFN:11,main\nFNDA:1,main\n"));
+        assert!(lcov.contains("DA:11,1\nDA:12,1\nDA:13,0\n"));
+        assert!(lcov.ends_with("DA:44,0\nLF:29\nLH:2\nend_of_record\n"));
+    }
+}
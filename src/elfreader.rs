@@ -0,0 +1,388 @@
+use crate::error::LoadError;
+use crate::fuses::FLASH_WORDS;
+use std::fs;
+
+// avr-gcc places each memory in its own region of the ELF address space
+pub const DATA_OFFSET: u32 = 0x800000;
+pub const EEPROM_OFFSET: u32 = 0x810000;
+pub const FUSE_OFFSET: u32 = 0x820000;
+pub const LOCK_OFFSET: u32 = 0x830000;
+pub const SIGNATURE_OFFSET: u32 = 0x840000;
+
+const ELF_MAGIC: [u8; 4] = [0x7F, b'E', b'L', b'F'];
+const ELF_HEADER_SIZE: usize = 52;
+const ELFCLASS32: u8 = 1;
+const ELFDATA2LSB: u8 = 1;
+const EM_AVR: u16 = 83;
+
+const PT_LOAD: u32 = 1;
+
+const SHT_PROGBITS: u32 = 1;
+const SHT_SYMTAB: u32 = 2;
+const SHF_ALLOC: u32 = 0x2;
+
+const STT_OBJECT: u8 = 1;
+const STT_FUNC: u8 = 2;
+const STT_SECTION: u8 = 3;
+const STT_FILE: u8 = 4;
+const SHN_ABS: u16 = 0xFFF1;
+
+const STB_GLOBAL: u8 = 1;
+const STB_WEAK: u8 = 2;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum SymbolKind {
+    Function,
+    Object,
+    Other,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum SymbolBinding {
+    Local,
+    Weak,
+    Global,
+}
+
+#[derive(Debug, Clone)]
+pub struct Symbol {
+    pub name: String,
+    pub value: u32,
+    pub size: u32,
+    pub kind: SymbolKind,
+    pub binding: SymbolBinding,
+    pub absolute: bool, // Defined with SHN_ABS rather than in a section
+}
+
+#[derive(Debug, Clone)]
+pub struct ElfSection {
+    pub name: String,
+    pub vma: u32, // Address the section runs at
+    pub lma: u32, // Address the section is stored at
+    pub data: Vec<u8>,
+}
+
+#[derive(Debug, Default)]
+pub struct ElfImage {
+    pub entry: u32,
+    pub flash: Vec<u8>, // .text and the .data initialisers, indexed by load address
+    pub eeprom: Vec<u8>,
+    pub fuse: Vec<u8>,
+    pub lock: Vec<u8>,
+    pub signature: Vec<u8>,
+    pub sections: Vec<ElfSection>,
+    pub debug_sections: Vec<ElfSection>, // .debug_* sections, not loaded into any memory
+    pub symbols: Vec<Symbol>,
+}
+
+impl ElfImage {
+    pub fn symbol(&self, name: &str) -> Option<&Symbol> {
+        self.symbols.iter().find(|symbol| symbol.name == name)
+    }
+
+    pub fn debug_section(&self, name: &str) -> Option<&[u8]> {
+        self.debug_sections.iter().find(|section| section.name == name).map(|section| section.data.as_slice())
+    }
+
+    // Function and object symbols located in flash, sorted by address
+    pub fn flash_symbols(&self) -> Vec<&Symbol> {
+        let mut symbols: Vec<&Symbol> = self.symbols.iter()
+            .filter(|symbol| !symbol.absolute && symbol.value < DATA_OFFSET)
+            .collect();
+
+        symbols.sort_by_key(|symbol| symbol.value);
+        symbols
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn u8(&self, offset: usize) -> u8 {
+        self.bytes[offset]
+    }
+
+    fn u16(&self, offset: usize) -> u16 {
+        u16::from_le_bytes([self.bytes[offset], self.bytes[offset + 1]])
+    }
+
+    fn u32(&self, offset: usize) -> u32 {
+        u32::from_le_bytes([
+            self.bytes[offset],
+            self.bytes[offset + 1],
+            self.bytes[offset + 2],
+            self.bytes[offset + 3],
+        ])
+    }
+
+    // Whether `size` bytes at `offset` lie within the file
+    fn fits(&self, offset: usize, size: usize) -> bool {
+        offset.checked_add(size).is_some_and(|end| end <= self.bytes.len())
+    }
+
+    fn slice(&self, offset: u32, size: u32) -> &'a [u8] {
+        &self.bytes[offset as usize..(offset + size) as usize]
+    }
+
+    fn string(&self, offset: usize) -> String {
+        let bytes = self.bytes.get(offset..).unwrap_or(&[]);
+        let end = bytes.iter().position(|&b| b == 0).unwrap_or(0);
+        String::from_utf8_lossy(&bytes[..end]).into_owned()
+    }
+}
+
+struct SectionHeader {
+    name: u32,
+    kind: u32,
+    flags: u32,
+    addr: u32,
+    offset: u32,
+    size: u32,
+    link: u32,
+    entsize: u32,
+}
+
+struct ProgramHeader {
+    kind: u32,
+    offset: u32,
+    paddr: u32,
+    filesz: u32,
+}
+
+pub fn is_elf(bytes: &[u8]) -> bool {
+    bytes.starts_with(&ELF_MAGIC)
+}
+
+pub fn elf_to_image(path: &str) -> Result<ElfImage, LoadError> {
+    let bytes = fs::read(path).map_err(|err| LoadError::io(path, err))?;
+
+    parse_elf(&bytes)
+}
+
+fn truncated() -> LoadError {
+    LoadError::Format(String::from("ELF file is truncated"))
+}
+
+pub fn parse_elf(bytes: &[u8]) -> Result<ElfImage, LoadError> {
+    if !is_elf(bytes) {
+        return Err(LoadError::Format(String::from("Not an ELF file")));
+    }
+
+    let elf = Reader { bytes };
+
+    if !elf.fits(0, ELF_HEADER_SIZE) {
+        return Err(truncated());
+    }
+    if elf.u8(4) != ELFCLASS32 || elf.u8(5) != ELFDATA2LSB {
+        return Err(LoadError::Format(String::from("Only 32 bit little endian ELF files are supported")));
+    }
+    if elf.u16(18) != EM_AVR {
+        return Err(LoadError::Format(format!("ELF file is not built for AVR, e_machine was: {}", elf.u16(18))));
+    }
+
+    let entry = elf.u32(24);
+    let phoff = elf.u32(28) as usize;
+    let shoff = elf.u32(32) as usize;
+    let phentsize = elf.u16(42) as usize;
+    let phnum = elf.u16(44) as usize;
+    let shentsize = elf.u16(46) as usize;
+    let shnum = elf.u16(48) as usize;
+    let shstrndx = elf.u16(50) as usize;
+
+    if !elf.fits(phoff, phnum * phentsize.max(32)) || !elf.fits(shoff, shnum * shentsize.max(40)) {
+        return Err(truncated());
+    }
+
+    let program_headers: Vec<ProgramHeader> = (0..phnum)
+        .map(|i| {
+            let base = phoff + i * phentsize;
+            ProgramHeader {
+                kind: elf.u32(base),
+                offset: elf.u32(base + 4),
+                paddr: elf.u32(base + 12),
+                filesz: elf.u32(base + 16),
+            }
+        })
+        .collect();
+
+    let section_headers: Vec<SectionHeader> = (0..shnum)
+        .map(|i| {
+            let base = shoff + i * shentsize;
+            SectionHeader {
+                name: elf.u32(base),
+                kind: elf.u32(base + 4),
+                flags: elf.u32(base + 8),
+                addr: elf.u32(base + 12),
+                offset: elf.u32(base + 16),
+                size: elf.u32(base + 20),
+                link: elf.u32(base + 24),
+                entsize: elf.u32(base + 36),
+            }
+        })
+        .collect();
+
+    let shstrtab = section_headers.get(shstrndx).map_or(0, |sh| sh.offset as usize);
+
+    let mut image = ElfImage {
+        entry,
+        ..ElfImage::default()
+    };
+
+    for sh in &section_headers {
+        if sh.kind != SHT_PROGBITS || sh.size == 0 {
+            continue
+        }
+        if sh.flags & SHF_ALLOC == 0 {
+            let name = elf.string(shstrtab + sh.name as usize);
+            if name.starts_with(".debug_") && elf.fits(sh.offset as usize, sh.size as usize) {
+                let data = elf.slice(sh.offset, sh.size).to_vec();
+                image.debug_sections.push(ElfSection { name, vma: 0, lma: 0, data });
+            }
+            continue
+        }
+        if !elf.fits(sh.offset as usize, sh.size as usize) {
+            return Err(truncated());
+        }
+
+        let name = elf.string(shstrtab + sh.name as usize);
+
+        // The load address comes from the segment the section was placed in
+        let lma = program_headers.iter()
+            .find(|ph| ph.kind == PT_LOAD && sh.offset >= ph.offset && sh.offset.saturating_add(sh.size) <= ph.offset.saturating_add(ph.filesz))
+            .map_or(Some(sh.addr), |ph| ph.paddr.checked_add(sh.offset - ph.offset))
+            .ok_or(LoadError::Format(format!("Load address of section {} overflows", name)))?;
+
+        let section = ElfSection {
+            name,
+            vma: sh.addr,
+            lma,
+            data: elf.slice(sh.offset, sh.size).to_vec(),
+        };
+
+        // Each memory ends where the next one's address range starts
+        let (memory, base, limit, region) = if sh.addr >= SIGNATURE_OFFSET {
+            (&mut image.signature, SIGNATURE_OFFSET, SIGNATURE_OFFSET + 0x10000, "the signature")
+        } else if sh.addr >= LOCK_OFFSET {
+            (&mut image.lock, LOCK_OFFSET, SIGNATURE_OFFSET, "the lock bits")
+        } else if sh.addr >= FUSE_OFFSET {
+            (&mut image.fuse, FUSE_OFFSET, LOCK_OFFSET, "the fuses")
+        } else if sh.addr >= EEPROM_OFFSET {
+            (&mut image.eeprom, EEPROM_OFFSET, FUSE_OFFSET, "EEPROM")
+        } else {
+            // .text runs from flash, .data is copied from flash by the startup code
+            (&mut image.flash, 0, FLASH_WORDS as u32 * 2, "flash")
+        };
+
+        let start = if sh.addr >= EEPROM_OFFSET { sh.addr } else { lma } - base;
+        let end = start as u64 + section.data.len() as u64;
+        if end > (limit - base) as u64 {
+            return Err(LoadError::Format(format!("Section {} ends at {:#x}, past the end of {}", section.name, base as u64 + end, region)))
+        }
+
+        let (start, end) = (start as usize, end as usize);
+        if memory.len() < end {
+            memory.resize(end, 0xFF);
+        }
+        memory[start..end].copy_from_slice(&section.data);
+
+        image.sections.push(section);
+    }
+
+    for sh in section_headers.iter().filter(|sh| sh.kind == SHT_SYMTAB) {
+        let strtab = match section_headers.get(sh.link as usize) {
+            Some(strtab) => strtab.offset as usize,
+            None => return Err(LoadError::Format(format!("Symbol table links to missing section {}", sh.link)))
+        };
+        let entsize = if sh.entsize == 0 { 16 } else { sh.entsize.max(16) };
+        if !elf.fits(sh.offset as usize, sh.size as usize) {
+            return Err(truncated());
+        }
+
+        for i in 1..(sh.size / entsize) {
+            let base = (sh.offset + i * entsize) as usize;
+            let info = elf.u8(base + 12);
+            let shndx = elf.u16(base + 14);
+
+            let kind = match info & 0xF {
+                STT_FUNC => SymbolKind::Function,
+                STT_OBJECT => SymbolKind::Object,
+                STT_SECTION | STT_FILE => continue,
+                _ => SymbolKind::Other
+            };
+
+            let binding = match info >> 4 {
+                STB_GLOBAL => SymbolBinding::Global,
+                STB_WEAK => SymbolBinding::Weak,
+                _ => SymbolBinding::Local
+            };
+
+            let name = elf.string(strtab + elf.u32(base) as usize);
+            if name.is_empty() {
+                continue
+            }
+
+            image.symbols.push(Symbol {
+                name,
+                value: elf.u32(base + 4),
+                size: elf.u32(base + 8),
+                kind,
+                binding,
+                absolute: shndx == SHN_ABS,
+            });
+        }
+    }
+
+    Ok(image)
+}
+
+// Tests
+#[cfg(test)]
+mod tests {
+    use crate::elfreader::{elf_to_image, parse_elf, SymbolKind};
+    use crate::error::LoadError;
+    use crate::hexreader;
+    use std::fs;
+
+    #[test]
+    fn testprogram() {
+        let image = elf_to_image("testprogram.bin").unwrap();
+
+        assert_eq!(image.entry, 0);
+        assert_eq!(image.flash.len(), 0xBE);
+        assert_eq!(&image.flash[0..4], &[0x0C, 0x94, 0x34, 0x00]);
+
+        // The ELF and the Intel HEX built from it hold the same program
+        assert_eq!(image.flash, hexreader::ihex_to_bytes("testprogram.hex").unwrap());
+
+        let main = image.symbol("main").unwrap();
+        assert_eq!(main.value, 0x80);
+        assert_eq!(main.size, 58);
+        assert_eq!(main.kind, SymbolKind::Function);
+        assert!(image.symbol("__stack").unwrap().absolute);
+        assert_eq!(image.debug_section(".debug_line").map(|data| data.len()), Some(0x1D));
+    }
+
+    #[test]
+    fn bad_load_address() {
+        let bytes = fs::read("testprogram.bin").unwrap();
+        let phoff = u32::from_le_bytes([bytes[28], bytes[29], bytes[30], bytes[31]]) as usize;
+        let patch = |field: usize, value: u32| {
+            let mut bytes = bytes.clone();
+            bytes[phoff + field..phoff + field + 4].copy_from_slice(&value.to_le_bytes());
+            bytes
+        };
+
+        // The first segment holds .text at file offset 0x74, place it past
+        // the end of flash
+        let far = parse_elf(&patch(12, 0x0010_0000));
+        assert!(matches!(far, Err(LoadError::Format(message)) if message.contains("past the end of flash")));
+
+        // Start the segment 4 bytes earlier so the section's address overflows
+        let mut bytes = patch(12, 0xFFFF_FFFE);
+        bytes[phoff + 4..phoff + 8].copy_from_slice(&0x70u32.to_le_bytes());
+        bytes[phoff + 16..phoff + 20].copy_from_slice(&0xC2u32.to_le_bytes());
+        assert!(matches!(parse_elf(&bytes), Err(LoadError::Format(message)) if message.contains("overflows")));
+    }
+}
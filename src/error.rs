@@ -0,0 +1,124 @@
+use std::error;
+use std::fmt;
+use std::io;
+
+// Failure to read or parse an image file
+#[derive(Debug)]
+pub enum LoadError {
+    Io { path: String, source: io::Error },
+    Record { line: usize, message: String }, // A malformed line of a text format
+    Format(String), // The file as a whole is invalid
+}
+
+impl LoadError {
+    pub fn io(path: &str, source: io::Error) -> LoadError {
+        LoadError::Io { path: path.to_string(), source }
+    }
+
+    pub fn record(line: usize, message: String) -> LoadError {
+        LoadError::Record { line, message }
+    }
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LoadError::Io { path, source } => write!(f, "Cannot read {}: {}", path, source),
+            LoadError::Record { line, message } => write!(f, "{} on line {}", message, line),
+            LoadError::Format(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl error::Error for LoadError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            LoadError::Io { source, .. } => Some(source),
+            _ => None
+        }
+    }
+}
+
+// A program memory word that does not decode to a supported instruction
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DecodeError {
+    pub address: usize, // Byte address of the word
+    pub word: u16,
+    pub reason: String,
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Cannot decode {:#06x} at {:#x}: {}", self.word, self.address, self.reason)
+    }
+}
+
+impl error::Error for DecodeError {}
+
+// The simulated program reached something the simulator cannot execute
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ExecError {
+    NoInstruction { address: u16 }, // PC points outside the decoded program
+    Undecodable { address: u16, word: u16 }, // PC points at data or a reserved encoding
+    Unimplemented { address: u16, mnemonic: String },
+}
+
+impl fmt::Display for ExecError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ExecError::NoInstruction { address } => write!(f, "No instruction at {:#x}", address),
+            ExecError::Undecodable { address, word } =>
+                write!(f, "Cannot execute {:#06x} at {:#x}, it does not decode to an instruction", word, address),
+            ExecError::Unimplemented { address, mnemonic } =>
+                write!(f, "Execution of {} at {:#x} is not implemented", mnemonic, address),
+        }
+    }
+}
+
+impl error::Error for ExecError {}
+
+// Any failure while loading or running a program
+#[derive(Debug)]
+pub enum Error {
+    Load(LoadError),
+    Decode(DecodeError),
+    Exec(ExecError),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Load(err) => err.fmt(f),
+            Error::Decode(err) => err.fmt(f),
+            Error::Exec(err) => err.fmt(f),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            Error::Load(err) => Some(err),
+            Error::Decode(err) => Some(err),
+            Error::Exec(err) => Some(err),
+        }
+    }
+}
+
+impl From<LoadError> for Error {
+    fn from(err: LoadError) -> Error {
+        Error::Load(err)
+    }
+}
+
+impl From<DecodeError> for Error {
+    fn from(err: DecodeError) -> Error {
+        Error::Decode(err)
+    }
+}
+
+impl From<ExecError> for Error {
+    fn from(err: ExecError) -> Error {
+        Error::Exec(err)
+    }
+}
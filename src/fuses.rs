@@ -0,0 +1,334 @@
+use crate::error::LoadError;
+use crate::hexreader;
+
+// Low fuse
+const LFUSE_CKDIV8: u8 = 7;
+const LFUSE_CKOUT: u8 = 6;
+const LFUSE_SUT: u8 = 4;
+const LFUSE_CKSEL: u8 = 0;
+
+// High fuse
+const HFUSE_BOOTRST: u8 = 0;
+const HFUSE_BOOTSZ: u8 = 1;
+const HFUSE_EESAVE: u8 = 3;
+const HFUSE_WDTON: u8 = 4;
+
+// Extended fuse
+const EFUSE_BODLEVEL: u8 = 0;
+
+// Lock bits
+const LOCK_LB: u8 = 0;
+const LOCK_BLB0: u8 = 2;
+const LOCK_BLB1: u8 = 4;
+
+// Words of flash on the ATmega328P
+pub const FLASH_WORDS: u16 = 0x4000;
+
+const INTERNAL_RC_HZ: u32 = 8_000_000;
+const INTERNAL_128K_HZ: u32 = 128_000;
+const WATCH_CRYSTAL_HZ: u32 = 32_768;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ClockSource {
+    ExternalClock,
+    InternalRc,
+    Internal128k,
+    LowFrequencyCrystal,
+    FullSwingCrystal,
+    LowPowerCrystal,
+    Reserved,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum BrownOutLevel {
+    Disabled,
+    V1_8,
+    V2_7,
+    V4_3,
+}
+
+// Restriction applied by a boot lock bit pair, BLB0 for the application
+// section and BLB1 for the boot loader section
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum SectionLock {
+    Unlocked,
+    SpmWriteLocked,
+    FullyLocked,
+    LpmReadLocked,
+}
+
+impl SectionLock {
+    fn from_bits(bits: u8) -> SectionLock {
+        match bits & 0b11 {
+            0b11 => SectionLock::Unlocked,
+            0b10 => SectionLock::SpmWriteLocked,
+            0b00 => SectionLock::FullyLocked,
+            _ => SectionLock::LpmReadLocked
+        }
+    }
+
+    fn spm_allowed(&self) -> bool {
+        matches!(self, SectionLock::Unlocked | SectionLock::LpmReadLocked)
+    }
+
+    fn lpm_allowed(&self) -> bool {
+        matches!(self, SectionLock::Unlocked | SectionLock::SpmWriteLocked)
+    }
+}
+
+// Fuse and lock bytes. As on the device a programmed bit reads as 0.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Fuses {
+    pub low: u8,
+    pub high: u8,
+    pub extended: u8,
+    pub lock: u8,
+}
+
+impl Default for Fuses {
+    // Factory defaults: internal 8 MHz RC divided by 8, no boot reset, no locks
+    fn default() -> Fuses {
+        Fuses {
+            low: 0x62,
+            high: 0xD9,
+            extended: 0xFF,
+            lock: 0xFF,
+        }
+    }
+}
+
+fn programmed(byte: u8, bit: u8) -> bool {
+    byte & (1 << bit) == 0
+}
+
+impl Fuses {
+    // Contents of a `.fuse` section: low, high and extended fuse in that order
+    pub fn load_section(&mut self, data: &[u8]) {
+        if let Some(&low) = data.first() {
+            self.low = low;
+        }
+        if let Some(&high) = data.get(1) {
+            self.high = high;
+        }
+        if let Some(&extended) = data.get(2) {
+            self.extended = extended;
+        }
+    }
+
+    // Contents of a `.lock` section
+    pub fn load_lock_section(&mut self, data: &[u8]) {
+        if let Some(&lock) = data.first() {
+            self.lock = lock;
+        }
+    }
+
+    pub fn load_fuse_hex(&mut self, path: &str) -> Result<(), LoadError> {
+        self.load_section(&hexreader::ihex_to_image(path)?.contiguous());
+
+        Ok(())
+    }
+
+    pub fn load_lock_hex(&mut self, path: &str) -> Result<(), LoadError> {
+        self.load_lock_section(&hexreader::ihex_to_image(path)?.contiguous());
+
+        Ok(())
+    }
+
+    // Set a single byte from a command line style `name=value` pair, e.g. `hfuse=0xDE`
+    pub fn set_from_flag(&mut self, flag: &str) -> Result<(), String> {
+        let (name, value) = match flag.split_once('=') {
+            Some(pair) => pair,
+            None => return Err(format!("Expected name=value, got: {}", flag))
+        };
+
+        let digits = value.trim_start_matches("0x").trim_start_matches("0X");
+        let value = u8::from_str_radix(digits, 16)
+            .map_err(|_| format!("Invalid fuse value: {}", value))?;
+
+        match name {
+            "lfuse" => self.low = value,
+            "hfuse" => self.high = value,
+            "efuse" => self.extended = value,
+            "lock" => self.lock = value,
+            _ => return Err(format!("Unknown fuse: {}", name))
+        }
+
+        Ok(())
+    }
+
+    pub fn clock_source(&self) -> ClockSource {
+        match (self.low >> LFUSE_CKSEL) & 0b1111 {
+            0b0000 => ClockSource::ExternalClock,
+            0b0010 => ClockSource::InternalRc,
+            0b0011 => ClockSource::Internal128k,
+            0b0100 | 0b0101 => ClockSource::LowFrequencyCrystal,
+            0b0110 | 0b0111 => ClockSource::FullSwingCrystal,
+            0b1000..=0b1111 => ClockSource::LowPowerCrystal,
+            _ => ClockSource::Reserved
+        }
+    }
+
+    // Oscillator frequency before the system clock prescaler. `external_hz` is
+    // the crystal or external clock fitted to the board.
+    pub fn oscillator_hz(&self, external_hz: u32) -> u32 {
+        match self.clock_source() {
+            ClockSource::InternalRc => INTERNAL_RC_HZ,
+            ClockSource::Internal128k => INTERNAL_128K_HZ,
+            ClockSource::LowFrequencyCrystal => WATCH_CRYSTAL_HZ,
+            _ => external_hz
+        }
+    }
+
+    pub fn ckdiv8(&self) -> bool {
+        programmed(self.low, LFUSE_CKDIV8)
+    }
+
+    pub fn ckout(&self) -> bool {
+        programmed(self.low, LFUSE_CKOUT)
+    }
+
+    // Oscillator start-up time in clock cycles when waking from power-down or power-save
+    pub fn startup_cycles(&self) -> u64 {
+        let sut = (self.low >> LFUSE_SUT) & 0b11;
+        let cksel0 = self.low & 0b1 != 0;
+
+        match self.clock_source() {
+            ClockSource::LowPowerCrystal | ClockSource::FullSwingCrystal => match (cksel0, sut) {
+                (false, 0b00) | (false, 0b01) => 258,
+                (false, _) | (true, 0b00) => 1024,
+                (true, _) => 16 * 1024
+            },
+            ClockSource::LowFrequencyCrystal => {
+                if sut == 0b10 { 32 * 1024 } else { 1024 }
+            },
+            _ => 6
+        }
+    }
+
+    pub fn bootrst(&self) -> bool {
+        programmed(self.high, HFUSE_BOOTRST)
+    }
+
+    pub fn eesave(&self) -> bool {
+        programmed(self.high, HFUSE_EESAVE)
+    }
+
+    pub fn wdton(&self) -> bool {
+        programmed(self.high, HFUSE_WDTON)
+    }
+
+    pub fn boot_size_words(&self) -> u16 {
+        match (self.high >> HFUSE_BOOTSZ) & 0b11 {
+            0b11 => 256,
+            0b10 => 512,
+            0b01 => 1024,
+            _ => 2048
+        }
+    }
+
+    // Byte address of the first word of the boot loader section
+    pub fn boot_start(&self) -> u16 {
+        (FLASH_WORDS - self.boot_size_words()) * 2
+    }
+
+    pub fn in_boot_section(&self, byte_addr: u32) -> bool {
+        byte_addr >= self.boot_start() as u32
+    }
+
+    // Byte address execution starts from after reset
+    pub fn reset_vector(&self) -> u16 {
+        if self.bootrst() {
+            self.boot_start()
+        } else {
+            0
+        }
+    }
+
+    pub fn brown_out_level(&self) -> BrownOutLevel {
+        match (self.extended >> EFUSE_BODLEVEL) & 0b111 {
+            0b110 => BrownOutLevel::V1_8,
+            0b101 => BrownOutLevel::V2_7,
+            0b100 => BrownOutLevel::V4_3,
+            _ => BrownOutLevel::Disabled
+        }
+    }
+
+    // LB mode 2 and 3 disable further programming from an external programmer
+    pub fn programming_locked(&self) -> bool {
+        (self.lock >> LOCK_LB) & 0b11 != 0b11
+    }
+
+    pub fn application_lock(&self) -> SectionLock {
+        SectionLock::from_bits(self.lock >> LOCK_BLB0)
+    }
+
+    pub fn boot_lock(&self) -> SectionLock {
+        SectionLock::from_bits(self.lock >> LOCK_BLB1)
+    }
+
+    // Whether SPM may erase or write the page containing `target`
+    pub fn spm_allowed(&self, target: u32) -> bool {
+        if self.in_boot_section(target) {
+            self.boot_lock().spm_allowed()
+        } else {
+            self.application_lock().spm_allowed()
+        }
+    }
+
+    // Whether LPM executed at `pc` may read `target`. Each section can always read itself.
+    pub fn lpm_allowed(&self, pc: u32, target: u32) -> bool {
+        match (self.in_boot_section(pc), self.in_boot_section(target)) {
+            (true, false) => self.application_lock().lpm_allowed(),
+            (false, true) => self.boot_lock().lpm_allowed(),
+            _ => true
+        }
+    }
+}
+
+// Tests
+#[cfg(test)]
+mod tests {
+    use crate::fuses::{Fuses, ClockSource, BrownOutLevel};
+
+    #[test]
+    fn defaults() {
+        let mut fuses = Fuses::default();
+
+        assert_eq!(fuses.clock_source(), ClockSource::InternalRc);
+        assert_eq!(fuses.oscillator_hz(16_000_000), 8_000_000);
+        assert!(fuses.ckdiv8());
+
+        assert!(!fuses.bootrst());
+        assert_eq!(fuses.reset_vector(), 0);
+        assert_eq!(fuses.boot_start(), 0x7000);
+        assert_eq!(fuses.brown_out_level(), BrownOutLevel::Disabled);
+
+        // CKSEL 0011 and 0100
+        fuses.low = 0x63;
+        assert_eq!(fuses.oscillator_hz(16_000_000), 128_000);
+        fuses.low = 0x64;
+        assert_eq!(fuses.oscillator_hz(16_000_000), 32_768);
+    }
+
+    #[test]
+    fn optiboot() {
+        // Arduino Uno fuses
+        let mut fuses = Fuses::default();
+        fuses.load_section(&[0xFF, 0xDE, 0xFD]);
+        fuses.set_from_flag("lock=0x0F").unwrap();
+
+        assert_eq!(fuses.clock_source(), ClockSource::LowPowerCrystal);
+        assert_eq!(fuses.oscillator_hz(16_000_000), 16_000_000);
+        assert!(!fuses.ckdiv8());
+        assert_eq!(fuses.startup_cycles(), 16 * 1024);
+        assert_eq!(fuses.reset_vector(), 0x7E00);
+        assert_eq!(fuses.brown_out_level(), BrownOutLevel::V2_7);
+
+        // The boot section cannot be rewritten and the application cannot read it
+        assert!(!fuses.spm_allowed(0x7E00));
+        assert!(fuses.spm_allowed(0x0100));
+        assert!(!fuses.lpm_allowed(0x0100, 0x7E10));
+        assert!(fuses.lpm_allowed(0x7E00, 0x0100));
+        assert!(fuses.lpm_allowed(0x7E00, 0x7E10));
+    }
+}
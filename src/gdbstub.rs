@@ -0,0 +1,544 @@
+use crate::avrcore::{RAMEND, SREG};
+use crate::elfreader::{DATA_OFFSET, EEPROM_OFFSET};
+use crate::simulator::Simulator;
+use crate::watch::{WatchHit, WatchKind};
+use std::collections::{BTreeSet, HashMap};
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::TcpStream;
+
+// avr-gdb register numbers after r0 - r31
+const SREG_REGNUM: usize = 32;
+const SP_REGNUM: usize = 33;
+const PC_REGNUM: usize = 34;
+
+// Signals reported in stop replies
+const SIGINT: u8 = 2;
+const SIGILL: u8 = 4;
+const SIGTRAP: u8 = 5;
+
+// Instructions executed between checks for a Ctrl-C from the debugger
+const INTERRUPT_POLL: u64 = 1024;
+
+// What the server does after handling a packet
+#[derive(Debug, PartialEq, Eq)]
+enum Action {
+    Reply(String),
+    Continue,
+    Step,
+    ReverseContinue,
+    ReverseStep,
+    Detach, // Reply OK and close the connection
+    Kill,
+}
+
+// Memory spaces as avr-gdb addresses them
+enum Space {
+    Flash(usize),
+    Data(usize),
+    Eeprom(usize),
+}
+
+impl Space {
+    fn from_gdb(addr: u32) -> Space {
+        if addr >= EEPROM_OFFSET {
+            Space::Eeprom((addr - EEPROM_OFFSET) as usize)
+        } else if addr >= DATA_OFFSET {
+            Space::Data((addr - DATA_OFFSET) as usize)
+        } else {
+            Space::Flash(addr as usize)
+        }
+    }
+}
+
+fn checksum(payload: &[u8]) -> u8 {
+    payload.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte))
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None
+    }
+
+    (0..hex.len()).step_by(2)
+        .map(|idx| hex.get(idx..idx + 2).and_then(|byte| u8::from_str_radix(byte, 16).ok()))
+        .collect()
+}
+
+fn parse_hex(value: &str) -> Option<u32> {
+    u32::from_str_radix(value, 16).ok()
+}
+
+fn stop_reply(signal: u8) -> String {
+    format!("S{:02x}", signal)
+}
+
+// Stop reply naming the watchpoint and the data address that triggered it
+fn watch_reply(hit: &WatchHit) -> String {
+    let kind = match hit.kind {
+        WatchKind::Write => "watch",
+        WatchKind::Read => "rwatch",
+        WatchKind::Access => "awatch",
+    };
+
+    format!("T{:02x}{}:{:x};", SIGTRAP, kind, DATA_OFFSET + hit.address as u32)
+}
+
+// Frame a payload as `$payload#checksum`
+fn packet(payload: &str) -> Vec<u8> {
+    format!("${}#{:02x}", payload, checksum(payload.as_bytes())).into_bytes()
+}
+
+// Read the next packet and acknowledge it. None when the debugger hung up.
+fn read_packet<S: Read + Write>(stream: &mut S) -> io::Result<Option<String>> {
+    let mut byte = [0u8; 1];
+
+    loop {
+        // Skip acknowledgements and stray Ctrl-C until a packet starts
+        loop {
+            if stream.read(&mut byte)? == 0 {
+                return Ok(None)
+            }
+            if byte[0] == b'$' {
+                break
+            }
+        }
+
+        let mut payload = Vec::new();
+        loop {
+            if stream.read(&mut byte)? == 0 {
+                return Ok(None)
+            }
+            if byte[0] == b'#' {
+                break
+            }
+            payload.push(byte[0]);
+        }
+
+        let mut sum = [0u8; 2];
+        stream.read_exact(&mut sum)?;
+        let expected = std::str::from_utf8(&sum).ok().and_then(|sum| u8::from_str_radix(sum, 16).ok());
+
+        if expected == Some(checksum(&payload)) {
+            stream.write_all(b"+")?;
+            return Ok(Some(String::from_utf8_lossy(&payload).into_owned()))
+        }
+        stream.write_all(b"-")?;
+    }
+}
+
+// Send a packet, resending it until the debugger acknowledges it
+fn write_packet<S: Read + Write>(stream: &mut S, payload: &str) -> io::Result<()> {
+    let mut ack = [0u8; 1];
+
+    loop {
+        stream.write_all(&packet(payload))?;
+        stream.flush()?;
+
+        loop {
+            if stream.read(&mut ack)? == 0 {
+                return Err(io::Error::new(ErrorKind::UnexpectedEof, "debugger disconnected"))
+            }
+            match ack[0] {
+                b'+' => return Ok(()),
+                b'-' => break,
+                _ => ()
+            }
+        }
+    }
+}
+
+// True when the debugger sent a Ctrl-C or hung up, without blocking
+fn poll_interrupt(stream: &mut TcpStream) -> bool {
+    let mut byte = [0u8; 1];
+    if stream.set_nonblocking(true).is_err() {
+        return false
+    }
+
+    let interrupted = match stream.read(&mut byte) {
+        Ok(0) => true,
+        Ok(_) => byte[0] == 0x03,
+        Err(_) => false
+    };
+
+    let _ = stream.set_nonblocking(false);
+    interrupted
+}
+
+// Serves one avr-gdb connection for a simulator
+pub struct GdbStub<'a> {
+    pub sim: &'a mut Simulator,
+    pub breakpoints: BTreeSet<u32>, // Byte addresses in flash
+    watchpoints: HashMap<(WatchKind, u32, u32), u32>, // Simulator watchpoint ids by type, address and length
+    last_stop: String,
+}
+
+impl<'a> GdbStub<'a> {
+    pub fn new(sim: &'a mut Simulator) -> GdbStub<'a> {
+        GdbStub {
+            sim,
+            breakpoints: BTreeSet::new(),
+            watchpoints: HashMap::new(),
+            last_stop: stop_reply(SIGTRAP),
+        }
+    }
+
+    // Handle packets until the debugger detaches, kills the target or hangs up
+    pub fn serve(&mut self, mut stream: TcpStream) -> io::Result<()> {
+        stream.set_nodelay(true)?;
+
+        while let Some(payload) = read_packet(&mut stream)? {
+            let reply = match self.handle(&payload) {
+                Action::Reply(reply) => reply,
+                Action::Continue => self.resume(false, || poll_interrupt(&mut stream)),
+                Action::Step => self.resume(true, || false),
+                Action::ReverseContinue => self.reverse(false, || poll_interrupt(&mut stream)),
+                Action::ReverseStep => self.reverse(true, || false),
+                Action::Detach => {
+                    write_packet(&mut stream, "OK")?;
+                    return Ok(())
+                },
+                Action::Kill => return Ok(())
+            };
+            write_packet(&mut stream, &reply)?;
+        }
+
+        Ok(())
+    }
+
+    fn handle(&mut self, payload: &str) -> Action {
+        // Every supported packet is ASCII, bytes that are not came through lossy
+        if !payload.is_ascii() {
+            return Action::Reply(String::from("E01"))
+        }
+        let (command, args) = payload.split_at(payload.len().min(1));
+
+        let reply = match command {
+            "?" => self.last_stop.clone(),
+            "g" => to_hex(&self.registers()),
+            "G" => match from_hex(args) {
+                Some(bytes) if bytes.len() == 39 => {
+                    self.set_registers(&bytes);
+                    String::from("OK")
+                },
+                _ => String::from("E01")
+            },
+            "p" => match parse_hex(args).and_then(|regnum| self.register(regnum as usize)) {
+                Some(bytes) => to_hex(&bytes),
+                None => String::from("E01")
+            },
+            "P" => {
+                let parsed = args.split_once('=')
+                    .and_then(|(regnum, value)| Some((parse_hex(regnum)? as usize, from_hex(value)?)));
+                match parsed {
+                    Some((regnum, bytes)) if self.set_register(regnum, &bytes) => String::from("OK"),
+                    _ => String::from("E01")
+                }
+            },
+            "m" => {
+                let parsed = args.split_once(',').and_then(|(addr, len)| Some((parse_hex(addr)?, parse_hex(len)?)));
+                match parsed.map(|(addr, len)| self.read_memory(addr, len as usize)) {
+                    Some(bytes) if !bytes.is_empty() => to_hex(&bytes),
+                    _ => String::from("E01")
+                }
+            },
+            "M" => {
+                let parsed = args.split_once(':')
+                    .and_then(|(range, data)| Some((parse_hex(range.split(',').next()?)?, from_hex(data)?)));
+                match parsed {
+                    Some((addr, bytes)) if self.write_memory(addr, &bytes) => String::from("OK"),
+                    _ => String::from("E01")
+                }
+            },
+            "c" | "s" => {
+                if let Some(addr) = parse_hex(args) {
+                    self.sim.set_pc(addr as u16);
+                }
+                return if command == "c" { Action::Continue } else { Action::Step }
+            },
+            "b" if args == "c" => return Action::ReverseContinue,
+            "b" if args == "s" => return Action::ReverseStep,
+            "Z" | "z" => self.breakpoint(command == "Z", args),
+            "D" => return Action::Detach,
+            "k" => return Action::Kill,
+            "H" => String::from("OK"),
+            "q" if args == "Attached" => String::from("1"),
+            "q" if args.starts_with("Supported") && self.sim.recording() => String::from("PacketSize=1000;ReverseStep+;ReverseContinue+"),
+            "q" if args.starts_with("Supported") => String::from("PacketSize=1000"),
+            _ => String::new()
+        };
+
+        Action::Reply(reply)
+    }
+
+    // r0 - r31, SREG, SP and PC in avr-gdb's `g` packet layout
+    fn registers(&self) -> Vec<u8> {
+        (0..=PC_REGNUM).flat_map(|regnum| self.register(regnum).unwrap_or_default()).collect()
+    }
+
+    fn set_registers(&mut self, bytes: &[u8]) {
+        let mut offset = 0;
+        for regnum in 0..=PC_REGNUM {
+            let size = self.register(regnum).map_or(0, |value| value.len());
+            self.set_register(regnum, &bytes[offset..offset + size]);
+            offset += size;
+        }
+    }
+
+    // Little endian value of a register, sized as avr-gdb expects
+    fn register(&self, regnum: usize) -> Option<Vec<u8>> {
+        match regnum {
+            0..=31 => Some(vec![self.sim.register(regnum)]),
+            SREG_REGNUM => Some(vec![self.sim.sreg().to_byte()]),
+            SP_REGNUM => Some(self.sim.sp().to_le_bytes().to_vec()),
+            PC_REGNUM => Some((self.sim.pc() as u32).to_le_bytes().to_vec()),
+            _ => None
+        }
+    }
+
+    fn set_register(&mut self, regnum: usize, bytes: &[u8]) -> bool {
+        let value = bytes.iter().rev().fold(0u32, |value, byte| value << 8 | *byte as u32);
+
+        match regnum {
+            0..=31 => self.sim.set_register(regnum, value as u8),
+            SREG_REGNUM => self.sim.core.sreg = SREG::from_byte(value as u8),
+            SP_REGNUM => {
+                self.sim.core.sp.SPL = value as u8;
+                self.sim.core.sp.SPH = (value >> 8) as u8;
+            },
+            PC_REGNUM => self.sim.set_pc(value as u16),
+            _ => return false
+        }
+
+        true
+    }
+
+    // Reads stop at the end of the addressed memory
+    fn read_memory(&self, addr: u32, len: usize) -> Vec<u8> {
+        match Space::from_gdb(addr) {
+            Space::Flash(addr) => self.sim.flash().iter().skip(addr).take(len).copied().collect(),
+            Space::Data(addr) => (addr..(addr + len).min(RAMEND as usize + 1))
+                .map(|addr| self.sim.read_data(addr as u16))
+                .collect(),
+            Space::Eeprom(addr) => self.sim.eeprom().iter().skip(addr).take(len).copied().collect(),
+        }
+    }
+
+    fn write_memory(&mut self, addr: u32, bytes: &[u8]) -> bool {
+        match Space::from_gdb(addr) {
+            Space::Flash(addr) if addr + bytes.len() <= self.sim.flash().len() => {
+                self.sim.core.write_progmem(addr, bytes);
+            },
+            Space::Data(addr) if addr + bytes.len() <= RAMEND as usize + 1 => {
+                for (offset, byte) in bytes.iter().enumerate() {
+                    self.sim.write_data((addr + offset) as u16, *byte);
+                }
+            },
+            Space::Eeprom(addr) if addr + bytes.len() <= self.sim.eeprom().len() => {
+                self.sim.core.eeprom[addr..addr + bytes.len()].copy_from_slice(bytes);
+            },
+            _ => return false
+        }
+
+        true
+    }
+
+    // Z0/z0 software and Z1/z1 hardware breakpoints, both kept in `breakpoints`,
+    // and Z2 - Z4 write, read and access watchpoints on the data space
+    fn breakpoint(&mut self, insert: bool, args: &str) -> String {
+        let mut fields = args.split(',');
+        let (kind, addr, len) = match (fields.next(), fields.next().and_then(parse_hex), fields.next().and_then(parse_hex)) {
+            (Some(kind), Some(addr), Some(len)) => (kind, addr, len),
+            _ => return String::from("E01")
+        };
+
+        let watch_kind = match kind {
+            "0" | "1" if insert => {
+                self.breakpoints.insert(addr);
+                return String::from("OK")
+            },
+            "0" | "1" => {
+                self.breakpoints.remove(&addr);
+                return String::from("OK")
+            },
+            "2" => WatchKind::Write,
+            "3" => WatchKind::Read,
+            "4" => WatchKind::Access,
+            _ => return String::new()
+        };
+
+        let start = match Space::from_gdb(addr) {
+            Space::Data(start) if start + len as usize <= RAMEND as usize + 1 => start as u16,
+            _ => return String::from("E01")
+        };
+
+        let key = (watch_kind, addr, len);
+        if insert {
+            let id = self.sim.watch(start..start + len as u16, watch_kind);
+            if let Some(previous) = self.watchpoints.insert(key, id) {
+                self.sim.unwatch(previous);
+            }
+        } else if let Some(id) = self.watchpoints.remove(&key) {
+            self.sim.unwatch(id);
+        }
+
+        String::from("OK")
+    }
+
+    // Execute one instruction, or run until a breakpoint, a watchpoint, a fault,
+    // the core sleeping forever or `interrupted` returning true. Returns the stop reply.
+    fn resume<F: FnMut() -> bool>(&mut self, step: bool, mut interrupted: F) -> String {
+        let mut executed: u64 = 0;
+
+        let reply = loop {
+            if self.sim.step().is_err() {
+                break stop_reply(SIGILL)
+            }
+            executed += 1;
+
+            if let Some(hit) = self.sim.take_watch_stops().first() {
+                break watch_reply(hit)
+            }
+
+            let awake = self.sim.core.sleep.is_none();
+            if step || self.sim.core.sleeping_forever() {
+                break stop_reply(SIGTRAP)
+            }
+            if awake && self.breakpoints.contains(&(self.sim.pc() as u32)) {
+                break stop_reply(SIGTRAP)
+            }
+            if executed.is_multiple_of(INTERRUPT_POLL) && interrupted() {
+                break stop_reply(SIGINT)
+            }
+        };
+
+        self.last_stop = reply;
+        self.last_stop.clone()
+    }
+
+    // Undo one instruction, or go back until a breakpoint, a watchpoint, the
+    // start of the recorded history or `interrupted` returning true
+    fn reverse<F: FnMut() -> bool>(&mut self, step: bool, mut interrupted: F) -> String {
+        let mut undone: u64 = 0;
+
+        let reply = loop {
+            if !self.sim.step_back() {
+                break format!("T{:02x}replaylog:begin;", SIGTRAP)
+            }
+            undone += 1;
+
+            if let Some(hit) = self.sim.take_watch_stops().first() {
+                break watch_reply(hit)
+            }
+
+            let awake = self.sim.core.sleep.is_none();
+            if step || (awake && self.breakpoints.contains(&(self.sim.pc() as u32))) {
+                break stop_reply(SIGTRAP)
+            }
+            if undone.is_multiple_of(INTERRUPT_POLL) && interrupted() {
+                break stop_reply(SIGINT)
+            }
+        };
+
+        self.last_stop = reply;
+        self.last_stop.clone()
+    }
+}
+
+// Tests
+#[cfg(test)]
+mod tests {
+    use crate::gdbstub::{packet, read_packet, Action, GdbStub};
+    use crate::simulator::Simulator;
+    use std::io::{self, Read, Write};
+
+    // Reads from a canned input and collects what is written
+    struct Loopback {
+        input: io::Cursor<Vec<u8>>,
+        output: Vec<u8>,
+    }
+
+    impl Read for Loopback {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+
+    impl Write for Loopback {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.output.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn framing() {
+        assert_eq!(packet("OK"), b"$OK#9a");
+
+        // A corrupted packet is rejected and the retransmission accepted
+        let mut stream = Loopback { input: io::Cursor::new(b"+$g#00$g#67".to_vec()), output: Vec::new() };
+        assert_eq!(read_packet(&mut stream).unwrap().as_deref(), Some("g"));
+        assert_eq!(stream.output, b"-+");
+
+        // A valid checksum over a non-ASCII command
+        let mut stream = Loopback { input: io::Cursor::new(b"$\xff#ff".to_vec()), output: Vec::new() };
+        let payload = read_packet(&mut stream).unwrap().unwrap();
+        let mut sim = Simulator::builder().build().unwrap();
+        assert_eq!(GdbStub::new(&mut sim).handle(&payload), Action::Reply(String::from("E01")));
+    }
+
+    #[test]
+    fn registers_memory_and_breakpoints() {
+        let mut sim = Simulator::builder().image_file("testprogram.bin").build().unwrap();
+        let main = sim.symbol("main").unwrap();
+        let mut stub = GdbStub::new(&mut sim);
+
+        let reply = |stub: &mut GdbStub, payload: &str| match stub.handle(payload) {
+            Action::Reply(reply) => reply,
+            action => panic!("Unexpected {:?}", action)
+        };
+
+        assert_eq!(reply(&mut stub, "g").len(), 39 * 2);
+        assert_eq!(reply(&mut stub, "m0,4"), "0c943400");
+        assert_eq!(reply(&mut stub, "M800100,2:abcd"), "OK");
+        assert_eq!(reply(&mut stub, "m800100,2"), "abcd");
+        assert_eq!(reply(&mut stub, "P1=2a"), "OK");
+        assert_eq!(reply(&mut stub, "p1"), "2a");
+
+        assert_eq!(reply(&mut stub, &format!("Z0,{:x},2", main)), "OK");
+        assert_eq!(stub.handle("c"), Action::Continue);
+        assert_eq!(stub.resume(false, || false), "S05");
+        assert_eq!(reply(&mut stub, "p22"), format!("{:08x}", main.swap_bytes()));
+
+        // The push of r28 at the start of main
+        let sp = stub.sim.sp() as u32;
+        assert_eq!(reply(&mut stub, &format!("Z2,{:x},1", 0x800000 + sp)), "OK");
+        assert_eq!(stub.resume(false, || false), format!("T05watch:{:x};", 0x800000 + sp));
+    }
+
+    #[test]
+    fn reverse_execution() {
+        let mut sim = Simulator::builder().image_file("testprogram.bin").build().unwrap();
+        let main = sim.symbol("main").unwrap();
+        sim.record(1000);
+        let mut stub = GdbStub::new(&mut sim);
+
+        assert!(matches!(stub.handle("qSupported"), Action::Reply(reply) if reply.contains("ReverseContinue+")));
+        stub.breakpoints.insert(main);
+        stub.resume(false, || false);
+        stub.resume(true, || false);
+
+        assert_eq!(stub.handle("bs"), Action::ReverseStep);
+        assert_eq!(stub.reverse(true, || false), "S05");
+        assert_eq!(stub.sim.pc() as u32, main);
+        assert_eq!(stub.handle("bc"), Action::ReverseContinue);
+        assert_eq!(stub.reverse(false, || false), "T05replaylog:begin;");
+        assert_eq!(stub.sim.pc(), 0);
+    }
+}
@@ -1,9 +1,12 @@
-use crate::avrcore::Avrcore;
+use crate::error::LoadError;
+use crate::memimage::MemoryImage;
 use std::fs;
 use regex::Regex;
 
 use std::str;
 
+#[allow(clippy::enum_variant_names)]
+#[derive(Copy, Clone)]
 enum FieldNumber {
     BCField = 1,
     AddrField = 2,
@@ -13,18 +16,26 @@ enum FieldNumber {
 }
 
 impl FieldNumber {
-    fn from_usize(value: usize) -> FieldNumber {
+    fn from_usize(value: usize) -> Option<FieldNumber> {
         match value {
-            1 => FieldNumber::BCField,
-            2 => FieldNumber::AddrField,
-            3 => FieldNumber::RTField,
-            4 => FieldNumber::DatField,
-            5 => FieldNumber::CSField,
-            _ => panic!("Unknown FieldNumber: {}", value)
+            1 => Some(FieldNumber::BCField),
+            2 => Some(FieldNumber::AddrField),
+            3 => Some(FieldNumber::RTField),
+            4 => Some(FieldNumber::DatField),
+            5 => Some(FieldNumber::CSField),
+            _ => None
         }
     }
 }
 
+// Record types
+const RT_DATA: u8 = 0x00;
+const RT_EOF: u8 = 0x01;
+const RT_EXT_SEGMENT_ADDR: u8 = 0x02;
+const RT_START_SEGMENT_ADDR: u8 = 0x03;
+const RT_EXT_LINEAR_ADDR: u8 = 0x04;
+const RT_START_LINEAR_ADDR: u8 = 0x05;
+
 #[derive(Debug)]
 struct IhexLine {
     byte_count:     u8,
@@ -43,7 +54,7 @@ impl IhexDump {
     pub fn get_next_word(&mut self) -> Result<u16, &str> {
         if self.indexer < self.data.len() {
             let next_word = self.data[self.indexer];
-            self.indexer = self.indexer + 1;
+            self.indexer += 1;
 
             Ok(next_word)
         } else {
@@ -51,29 +62,51 @@ impl IhexDump {
         }
     }
 
-    pub fn getIndex(&self) -> usize {
+    pub fn get_index(&self) -> usize {
         self.indexer*2
     }
+
+    // Word at a byte index
+    pub fn word_at(&self, index: usize) -> Option<u16> {
+        self.data.get(index / 2).copied()
+    }
+
+    pub fn from_words(data: Vec<u16>) -> IhexDump {
+        IhexDump {
+            indexer: 0,
+            data
+        }
+    }
+
+    // Little endian byte image of the dump
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.data.iter().flat_map(|word| word.to_le_bytes()).collect()
+    }
 }
 
 
+// Fields of one record, compiled once per file
+fn record_regex() -> Regex {
+    Regex::new(r"^:(?P<byte_count>[[:xdigit:]]{2})(?P<address>[[:xdigit:]]{4})(?P<record_type>[[:xdigit:]]{2})(?P<data>(?:[[:xdigit:]]{2})+)?(?P<check_sum>[[:xdigit:]]{2})$").unwrap()
+}
 
-fn split_ihex_line(line: &str) -> IhexLine {
-    if line.starts_with(":") {
+fn split_ihex_line(re: &Regex, line: &str, line_number: usize) -> Result<IhexLine, LoadError> {
+    if line.starts_with(':') {
         let mut ihexline = IhexLine{ byte_count: 0, 
                                      address: 0, 
                                      record_type: 0, 
                                      data: Vec::new(), 
                                      checksum: 0 };
 
-        let re = Regex::new(r":(?P<byte_count>[[:xdigit:]]{2})(?P<address>[[:xdigit:]]{4})(?P<record_type>[[:xdigit:]]{2})(?P<data>[[:xdigit:]]+)?(?P<check_sum>[[:xdigit:]]{2})").unwrap();
+        let caps = match re.captures(line) {
+            Some(caps) => caps,
+            None => return Err(LoadError::record(line_number, format!("Malformed record {}", line)))
+        };
 
-        let caps = re.captures(line).unwrap();
-
-        for i in 1..6 {
-            let field = caps.get(i).map_or("None", |m| m.as_str());
+        for field_number in (1..6).filter_map(FieldNumber::from_usize) {
+            let field = caps.get(field_number as usize).map_or("None", |m| m.as_str());
             
-            match FieldNumber::from_usize(i) {
+            match field_number {
                 FieldNumber::BCField => {
                     if field != "None" {
                         ihexline.byte_count = u8::from_str_radix(field, 16).unwrap()
@@ -119,30 +152,164 @@ fn split_ihex_line(line: &str) -> IhexLine {
 
         //println!("{:?}\n-----", ihexline)
 
-        ihexline
+        if ihexline.byte_count as usize != ihexline.data.len() {
+            return Err(LoadError::record(line_number, format!("Byte count mismatch: record says {} but holds {} bytes",
+                                                              ihexline.byte_count, ihexline.data.len())));
+        }
+
+        // All bytes of the record including the checksum sum to zero
+        let sum = ihexline.data.iter()
+            .chain([ihexline.byte_count,
+                    (ihexline.address >> 8) as u8,
+                    (ihexline.address & 0xFF) as u8,
+                    ihexline.record_type,
+                    ihexline.checksum].iter())
+            .fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+
+        if sum != 0 {
+            return Err(LoadError::record(line_number, format!("Checksum mismatch: expected {:#04x}, found {:#04x}",
+                                                              ihexline.checksum.wrapping_sub(sum), ihexline.checksum)));
+        }
+
+        Ok(ihexline)
 
     }
     else {
-        panic!("Encountered {}, but line does not start with ':'", line);
+        Err(LoadError::record(line_number, format!("Encountered {}, but line does not start with ':'", line)))
     }
 
 }
 
-pub fn ihex_to_dump(path: &str) -> IhexDump {
-    let mut flash: Vec<u16> = Vec::new();
+fn expect_data_len(ihex: &IhexLine, len: usize, line_number: usize) -> Result<(), LoadError> {
+    if ihex.data.len() != len {
+        return Err(LoadError::record(line_number, format!("Record type {:02x} must hold {} bytes, found {}",
+                                                          ihex.record_type, len, ihex.data.len())));
+    }
 
-    let data = fs::read_to_string(path).expect("Cannot read file");
+    Ok(())
+}
 
-    for line in data.lines() {
-        let ihex = split_ihex_line(line);
+// Place every data record at its absolute address, following extended segment
+// and extended linear address records
+pub fn parse_ihex(text: &str) -> Result<MemoryImage, LoadError> {
+    let mut image = MemoryImage::default();
+    let mut upper_address: u32 = 0;
+    let mut found_eof = false;
+    let re = record_regex();
 
-        for bytes in ihex.data.chunks(2) {
-            flash.push((bytes[1] as u16) << 8 | (bytes[0] as u16))
+    for (idx, line) in text.lines().enumerate() {
+        let line = line.trim_end();
+        if line.is_empty() {
+            continue
         }
+
+        let line_number = idx + 1;
+        let ihex = split_ihex_line(&re, line, line_number)?;
+        let word = |data: &[u8]| (data[0] as u32) << 8 | data[1] as u32;
+
+        match ihex.record_type {
+            RT_DATA => image.add(upper_address + ihex.address as u32, &ihex.data)
+                .map_err(|err| LoadError::record(line_number, err.to_string()))?,
+            RT_EOF => {
+                expect_data_len(&ihex, 0, line_number)?;
+                found_eof = true;
+                break
+            },
+            RT_EXT_SEGMENT_ADDR => {
+                expect_data_len(&ihex, 2, line_number)?;
+                upper_address = word(&ihex.data) << 4;
+            },
+            RT_EXT_LINEAR_ADDR => {
+                expect_data_len(&ihex, 2, line_number)?;
+                upper_address = word(&ihex.data) << 16;
+            },
+            RT_START_SEGMENT_ADDR => {
+                // CS:IP
+                expect_data_len(&ihex, 4, line_number)?;
+                image.start_address = Some((word(&ihex.data[0..2]) << 4) + word(&ihex.data[2..4]));
+            },
+            RT_START_LINEAR_ADDR => {
+                expect_data_len(&ihex, 4, line_number)?;
+                image.start_address = Some(word(&ihex.data[0..2]) << 16 | word(&ihex.data[2..4]));
+            },
+            _ => return Err(LoadError::record(line_number, format!("Unknown record type {:02x}", ihex.record_type)))
+        }
+    }
+
+    if !found_eof {
+        return Err(LoadError::Format(String::from("Missing end of file record")));
     }
 
-    IhexDump {
+    Ok(image)
+}
+
+pub fn ihex_to_image(path: &str) -> Result<MemoryImage, LoadError> {
+    let data = fs::read_to_string(path).map_err(|err| LoadError::io(path, err))?;
+
+    parse_ihex(&data)
+}
+
+// Byte image of the file from address 0
+pub fn ihex_to_bytes(path: &str) -> Result<Vec<u8>, LoadError> {
+    ihex_to_image(path)?.to_bytes()
+}
+
+pub fn ihex_to_dump(path: &str) -> Result<IhexDump, LoadError> {
+    let flash = ihex_to_bytes(path)?
+        .chunks(2)
+        .map(|bytes| (*bytes.get(1).unwrap_or(&0xFF) as u16) << 8 | (bytes[0] as u16))
+        .collect();
+
+    Ok(IhexDump {
         indexer: 0,
         data: flash
+    })
+}
+
+// Tests
+#[cfg(test)]
+mod tests {
+    use crate::hexreader::parse_ihex;
+    use crate::error::LoadError;
+
+    #[test]
+    fn extended_linear_address() {
+        // Two bytes at 0x0000, two at 0x1FFFE after an extended linear address record
+        let image = parse_ihex(":020000000C945E\n:020000040001F9\n:02FFFE00AABB9C\n:04000005000000C037\n:00000001FF\n").unwrap();
+
+        assert_eq!(image.segments.len(), 2);
+        assert_eq!(image.segments[0].data, vec![0x0C, 0x94]);
+        assert_eq!(image.segments[1].base, 0x1FFFE);
+        assert!(matches!(image.to_bytes(), Err(LoadError::Format(_))));
+        assert_eq!(image.start_address, Some(0xC0));
+    }
+
+    #[test]
+    fn extended_segment_address() {
+        let image = parse_ihex(":020000021000EC\n:01001000559A\n:00000001FF\n").unwrap();
+
+        assert_eq!(image.lowest_address(), 0x10010);
+        assert_eq!(image.contiguous(), vec![0x55]);
+    }
+
+    #[test]
+    fn out_of_order_records() {
+        let image = parse_ihex(":02000200CCDD53\n:02000000AABB99\n:00000001FF\n").unwrap();
+
+        assert_eq!(image.segments.len(), 1);
+        assert_eq!(image.contiguous(), vec![0xAA, 0xBB, 0xCC, 0xDD]);
+    }
+
+    #[test]
+    fn bad_records() {
+        let err = parse_ihex(":020000000C945E\n:02000200AABB00\n:00000001FF\n").unwrap_err();
+        assert!(matches!(err, LoadError::Record { line: 2, .. }));
+        assert_eq!(err.to_string(), "Checksum mismatch: expected 0x97, found 0x00 on line 2");
+
+        let err = parse_ihex(":030000000C945E\n:00000001FF\n").unwrap_err();
+        assert!(matches!(err, LoadError::Record { line: 1, .. }));
+
+        let err = parse_ihex(":020000000C945E\n").unwrap_err();
+        assert!(matches!(err, LoadError::Format(_)));
     }
 }
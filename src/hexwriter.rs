@@ -0,0 +1,89 @@
+use crate::memimage::MemoryImage;
+use std::fs;
+use std::io;
+
+// avr-objcopy writes 16 data bytes per record
+pub const DEFAULT_RECORD_LEN: usize = 16;
+
+fn record(record_type: u8, address: u16, data: &[u8]) -> String {
+    let mut bytes = vec![data.len() as u8, (address >> 8) as u8, (address & 0xFF) as u8, record_type];
+    bytes.extend_from_slice(data);
+
+    let checksum = bytes.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte)).wrapping_neg();
+    bytes.push(checksum);
+
+    let hex: String = bytes.iter().map(|byte| format!("{:02X}", byte)).collect();
+    format!(":{}\n", hex)
+}
+
+// Serialise an image as Intel HEX. Records never cross a 64 KiB boundary, an
+// extended linear address record is emitted whenever the upper address changes.
+// The record length is clamped to the 1 to 255 bytes a record can hold.
+pub fn image_to_ihex(image: &MemoryImage, record_len: usize) -> String {
+    let record_len = record_len.clamp(1, 255);
+    let mut hex = String::new();
+    let mut upper_address: u32 = 0;
+
+    for segment in image.segments.iter() {
+        let mut offset = 0;
+
+        while offset < segment.data.len() {
+            let address = segment.base + offset as u32;
+            if address >> 16 != upper_address {
+                upper_address = address >> 16;
+                hex += &record(0x04, 0, &(upper_address as u16).to_be_bytes());
+            }
+
+            let to_boundary = 0x10000 - (address & 0xFFFF) as usize;
+            let len = record_len.min(to_boundary).min(segment.data.len() - offset);
+
+            hex += &record(0x00, address as u16, &segment.data[offset..offset + len]);
+            offset += len;
+        }
+    }
+
+    if let Some(start) = image.start_address {
+        hex += &record(0x05, 0, &start.to_be_bytes());
+    }
+
+    hex + &record(0x01, 0, &[])
+}
+
+// Write `data` located at `base`, e.g. flash or EEPROM contents after a run
+pub fn bytes_to_ihex_file(path: &str, base: u32, data: &[u8], record_len: usize) -> io::Result<()> {
+    let image = MemoryImage::from_binary(base, data).map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+
+    fs::write(path, image_to_ihex(&image, record_len))
+}
+
+// Tests
+#[cfg(test)]
+mod tests {
+    use crate::hexreader;
+    use crate::hexwriter::{image_to_ihex, DEFAULT_RECORD_LEN};
+    use crate::memimage::MemoryImage;
+    use std::fs;
+
+    #[test]
+    fn round_trip() {
+        // avr-objcopy output is reproduced byte for byte
+        let original = fs::read_to_string("testprogram.hex").unwrap();
+        let image = hexreader::parse_ihex(&original).unwrap();
+        assert_eq!(image_to_ihex(&image, DEFAULT_RECORD_LEN), original.replace("\r\n", "\n"));
+
+        // Data spanning a 64 KiB boundary
+        let mut image = MemoryImage::from_binary(0xFFF0, &(0..40).collect::<Vec<u8>>()).unwrap();
+        image.add(0x20000, &[0xAA, 0xBB]).unwrap();
+        image.start_address = Some(0x1234);
+        let hex = image_to_ihex(&image, 32);
+        assert!(hex.contains(":020000040001F9\n"));
+        assert!(hex.contains(":020000040002F8\n"));
+
+        assert_eq!(hexreader::parse_ihex(&hex).unwrap(), image);
+
+        // Out of range record lengths are clamped
+        assert_eq!(image_to_ihex(&image, 0), image_to_ihex(&image, 1));
+        assert_eq!(image_to_ihex(&image, 1000), image_to_ihex(&image, 255));
+        assert_eq!(hexreader::parse_ihex(&image_to_ihex(&image, 0)).unwrap(), image);
+    }
+}
@@ -0,0 +1,312 @@
+use crate::avrcore::{Avrcore, SREG};
+use crate::error::ExecError;
+use crate::interrupts::Interrupt;
+use crate::selfprog::{SelfProgramming, PAGE_SIZE};
+use crate::sleep::SleepState;
+use crate::snapshot::Snapshot;
+use crate::watch::WatchHit;
+use std::collections::VecDeque;
+
+// Instructions between full snapshots of the core
+pub const SNAPSHOT_INTERVAL: u64 = 10_000;
+
+// Memory an instruction accessed, journaled while it is being recorded
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Access {
+    Read(u16), // Data space address loaded by the program
+    Write(u16, u8), // Data space address and the value it held before
+    Progmem(usize, u8), // Flash byte address and the value it held before
+}
+
+// State before one recorded instruction. The register file is always kept, the
+// I/O registers and peripheral state only when the instruction changed them.
+struct Delta {
+    pc: u16,
+    sp: u16,
+    sreg: u8,
+    general: [u8; 32],
+    cycles: u64,
+    pending_interrupts: u32,
+    interrupt_inhibit: bool,
+    sleep: Option<SleepState>,
+    lock: u8,
+    io: Option<Box<[u8; 64]>>,
+    extio: Option<Box<[u8; 160]>>,
+    spm: Option<Box<SelfProgramming>>,
+    scheduled: Option<Vec<(u64, Interrupt)>>,
+    accesses: Vec<Access>,
+}
+
+// Recorded execution for stepping backwards. Output already sent by the USART
+// cannot be taken back and is not part of the history.
+pub struct History {
+    limit: usize, // Most instructions kept
+    start: u64, // Index of the oldest kept instruction
+    deltas: VecDeque<Delta>,
+    snapshots: VecDeque<(u64, Snapshot)>, // Complete state before the instruction at an index
+}
+
+impl History {
+    pub fn new(limit: usize) -> History {
+        History {
+            limit,
+            start: 0,
+            deltas: VecDeque::new(),
+            snapshots: VecDeque::new(),
+        }
+    }
+
+    // Instructions that can be stepped back over
+    pub fn len(&self) -> usize {
+        self.deltas.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.deltas.is_empty()
+    }
+
+    // Forget everything recorded, e.g. after the state was replaced
+    pub fn clear(&mut self) {
+        self.start = 0;
+        self.deltas.clear();
+        self.snapshots.clear();
+    }
+
+    // Index of the next instruction to execute
+    fn position(&self) -> u64 {
+        self.start + self.deltas.len() as u64
+    }
+
+    // Execute one instruction and record how to undo it
+    pub fn record(&mut self, core: &mut Avrcore) -> Result<(), ExecError> {
+        let index = self.position();
+        let taken = self.snapshots.back().is_some_and(|(at, _)| *at == index);
+        if index.is_multiple_of(SNAPSHOT_INTERVAL) && !taken {
+            self.snapshots.push_back((index, Snapshot::capture(core)));
+        }
+
+        let (io, extio, spm, scheduled) = (core.io, core.extio, core.spm.clone(), core.scheduled.clone());
+        let mut delta = Delta {
+            pc: core.pc,
+            sp: core.sp.current_addr(),
+            sreg: core.sreg.to_byte(),
+            general: core.general,
+            cycles: core.cycles,
+            pending_interrupts: core.pending_interrupts,
+            interrupt_inhibit: core.interrupt_inhibit,
+            sleep: core.sleep,
+            lock: core.fuses.lock,
+            io: None,
+            extio: None,
+            spm: None,
+            scheduled: None,
+            accesses: Vec::new(),
+        };
+
+        // A journal kept by the caller, such as a tracer, gets the accesses too
+        let outer = core.journal.replace(Vec::new());
+        let result = core.execute();
+        delta.accesses = core.journal.take().unwrap_or_default();
+        if let Some(mut outer) = outer {
+            outer.extend_from_slice(&delta.accesses);
+            core.journal = Some(outer);
+        }
+
+        if core.io != io {
+            delta.io = Some(Box::new(io));
+        }
+        if core.extio != extio {
+            delta.extio = Some(Box::new(extio));
+        }
+        if core.spm != spm {
+            delta.spm = Some(Box::new(spm));
+        }
+        if core.scheduled != scheduled {
+            delta.scheduled = Some(scheduled);
+        }
+
+        // A fault before anything changed leaves nothing to step back over
+        if result.is_err() && delta.pc == core.pc && delta.cycles == core.cycles {
+            return result
+        }
+        self.deltas.push_back(delta);
+
+        while self.deltas.len() > self.limit {
+            self.deltas.pop_front();
+            self.start += 1;
+        }
+        while self.snapshots.front().is_some_and(|(at, _)| *at < self.start) {
+            self.snapshots.pop_front();
+        }
+
+        result
+    }
+
+    // Undo the last recorded instruction. Returns the watchpoint hits its memory
+    // accesses would have caused, or None when the history is exhausted.
+    pub fn undo(&mut self, core: &mut Avrcore) -> Option<Vec<WatchHit>> {
+        let delta = self.deltas.pop_back()?;
+        let mut hits = Vec::new();
+        let mut changed_flash: Option<(usize, usize)> = None;
+
+        for access in delta.accesses.iter().rev() {
+            match *access {
+                Access::Read(address) => {
+                    let value = core.read_data(address);
+                    hits.extend(core.match_watchpoints(delta.pc, address, false, value, value));
+                },
+                Access::Write(address, old) => {
+                    let new = core.read_data(address);
+                    hits.extend(core.match_watchpoints(delta.pc, address, true, old, new));
+                    core.restore_data(address, old);
+                },
+                Access::Progmem(address, old) => {
+                    core.progmem[address] = old;
+                    changed_flash = Some(match changed_flash {
+                        Some((start, end)) => (start.min(address), end.max(address + 1)),
+                        None => (address, address + 1)
+                    });
+                }
+            }
+        }
+        hits.reverse();
+
+        if let Some((start, end)) = changed_flash {
+            core.redecode(start, end);
+        }
+
+        core.pc = delta.pc;
+        core.set_sp(delta.sp);
+        core.sreg = SREG::from_byte(delta.sreg);
+        core.general = delta.general;
+        core.cycles = delta.cycles;
+        core.pending_interrupts = delta.pending_interrupts;
+        core.interrupt_inhibit = delta.interrupt_inhibit;
+        core.sleep = delta.sleep;
+        core.fuses.lock = delta.lock;
+        if let Some(io) = delta.io {
+            core.io = *io;
+        }
+        if let Some(extio) = delta.extio {
+            core.extio = *extio;
+        }
+        if let Some(spm) = delta.spm {
+            core.spm = *spm;
+        }
+        if let Some(scheduled) = delta.scheduled {
+            core.scheduled = scheduled;
+        }
+
+        // Snapshots ahead of us are taken again when execution gets there
+        let position = self.position();
+        while self.snapshots.back().is_some_and(|(at, _)| *at > position) {
+            self.snapshots.pop_back();
+        }
+
+        Some(hits)
+    }
+
+    // Go back `count` instructions without looking at watchpoints, restoring the
+    // nearest snapshot instead of undoing every instruction. Returns the number
+    // of instructions gone back.
+    pub fn rewind(&mut self, core: &mut Avrcore, count: usize) -> usize {
+        let count = count.min(self.deltas.len());
+        let target = self.position() - count as u64;
+
+        if let Some(idx) = self.snapshots.iter().position(|(at, _)| *at >= target) {
+            let (index, snapshot) = &self.snapshots[idx];
+            if *index < self.position() {
+                // Output already collected from the USART stays collected
+                let output = core.take_uart_output();
+                snapshot.restore(core);
+                core.uart_tx = output;
+
+                self.deltas.truncate((index - self.start) as usize);
+                self.snapshots.truncate(idx + 1);
+            }
+        }
+
+        while self.position() > target {
+            self.undo(core);
+        }
+
+        count
+    }
+}
+
+impl Avrcore {
+    // Remember a memory access of the instruction being recorded
+    pub fn journal(&mut self, access: Access) {
+        if let Some(journal) = &mut self.journal {
+            journal.push(access);
+        }
+    }
+
+    pub fn set_sp(&mut self, sp: u16) {
+        self.sp.SPL = (sp & 0xFF) as u8;
+        self.sp.SPH = (sp >> 8) as u8;
+    }
+
+    // Put back an old value without the side effects of `write_data`
+    fn restore_data(&mut self, addr: u16, value: u8) {
+        let addr = addr as usize;
+        match addr {
+            0x0000..=0x001F => self.general[addr] = value,
+            0x0020..=0x005F => self.io[addr - 0x20] = value,
+            0x0060..=0x00FF => self.extio[addr - 0x60] = value,
+            0x0100..=0x08FF => self.sram[addr - 0x100] = value,
+            _ => {}
+        }
+    }
+
+    // Save a flash page before SPM erases or writes it
+    pub fn journal_page(&mut self, page: usize) {
+        if self.journal.is_some() {
+            for address in page..page + PAGE_SIZE {
+                self.journal(Access::Progmem(address, self.progmem[address]));
+            }
+        }
+    }
+}
+
+// Tests
+#[cfg(test)]
+mod tests {
+    use crate::avrcore::Avrcore;
+    use crate::history::{History, SNAPSHOT_INTERVAL};
+
+    #[test]
+    fn undo_and_rewind() {
+        // ldi r16, 0x42; push r16; out 0x3f, r16; rjmp .-2
+        let progmem = [0x02, 0xE4, 0x0F, 0x93, 0x0F, 0xBF, 0xFF, 0xCF];
+        let mut core = Avrcore::from_bytes(&progmem);
+        let mut history = History::new(100);
+        let sp = core.sp.current_addr();
+
+        for _ in 0..3 {
+            history.record(&mut core).unwrap();
+        }
+        assert_eq!((core.pc, core.read_data(sp), core.sreg.to_byte()), (6, 0x42, 0x42));
+
+        assert!(history.undo(&mut core).unwrap().is_empty());
+        assert_eq!((core.pc, core.sreg.to_byte(), core.cycles), (4, 0, 3));
+
+        let watch = core.add_watchpoint(sp..sp + 1, crate::watch::WatchKind::Write);
+        let hits = history.undo(&mut core).unwrap();
+        assert_eq!((hits[0].id, hits[0].pc, hits[0].old, hits[0].new), (watch, 2, 0, 0x42));
+        assert_eq!((core.read_data(sp), core.sp.current_addr()), (0, sp));
+
+        history.undo(&mut core).unwrap();
+        assert_eq!((core.pc, core.general[16], core.cycles), (0, 0, 0));
+        assert!(history.undo(&mut core).is_none());
+
+        // Rewinding restores the snapshot taken before the first instruction, which
+        // also brings back a byte changed behind the history's back
+        let mut history = History::new(SNAPSHOT_INTERVAL as usize * 2);
+        core.extio[0] = 0x5A;
+        history.record(&mut core).unwrap();
+        core.extio[0] = 0;
+        assert_eq!(history.rewind(&mut core, 5), 1);
+        assert_eq!((core.pc, core.extio[0]), (0, 0x5A));
+    }
+}
@@ -1,5 +1,7 @@
 use enum_dispatch::enum_dispatch;
-use crate::avrcore::Avrcore;
+use crate::avrcore::{Avrcore, RAMPZ};
+use crate::error::ExecError;
+use crate::sleep::{SleepMode, SMCR};
 use std::ops::AddAssign;
 
 #[enum_dispatch]
@@ -21,7 +23,17 @@ pub enum Opcodes {
     POP(POPInstruction),
     RET(RETInstruction),
     CLI(CLIInstruction),
-    RJMP(RJMPInstruction)
+    RJMP(RJMPInstruction),
+    BRBS(BRBSInstruction),
+    BRBC(BRBCInstruction),
+    SEI(SEIInstruction),
+    RETI(RETIInstruction),
+    SLEEP(SLEEPInstruction),
+    SPM(SPMInstruction),
+    LPM(LPMInstruction),
+    ELPM(ELPMInstruction),
+    NOP(NOPInstruction),
+    WORD(WORDInstruction)
     //STD(STD_instruction),
 }
 
@@ -30,9 +42,54 @@ pub trait Instruction {
 
     fn pretty_print(&self);
 
-    fn execute(&self, core: &mut Avrcore) {
-        self.pretty_print();
-        panic!("Reached unimplemented opcode execution. Aborting");
+    // Mnemonic, operands and trailing comment in avr-objdump syntax
+    fn objdump(&self) -> (String, String, Option<String>);
+
+    // Size of the instruction in bytes
+    fn size(&self) -> u16 {
+        2
+    }
+
+    // Byte address control is transferred to when executed at `addr`
+    fn branch_target(&self, _addr: u32) -> Option<u32> {
+        None
+    }
+
+    // Whether execution can continue with the next instruction in memory
+    fn falls_through(&self) -> bool {
+        true
+    }
+
+    fn execute(&self, core: &mut Avrcore) -> Result<(), ExecError> {
+        Err(ExecError::Unimplemented { address: core.pc, mnemonic: self.objdump().0 })
+    }
+
+    // Clock cycles taken by the instruction
+    fn cycles(&self) -> u64 {
+        1
+    }
+}
+
+// avr-objdump prints absolute targets with C's %#x, which omits the prefix for zero
+fn objdump_address(address: u32) -> String {
+    if address == 0 {
+        String::from("0")
+    } else {
+        format!("{:#x}", address)
+    }
+}
+
+fn objdump_relative(offset: i16) -> String {
+    format!(".{:<+8}", offset)
+}
+
+fn objdump_z_operands(rd: u8, post_increment: bool, implied: bool) -> String {
+    if implied {
+        String::new()
+    } else if post_increment {
+        format!("r{}, Z+", rd)
+    } else {
+        format!("r{}, Z", rd)
     }
 }
 
@@ -47,8 +104,30 @@ impl Instruction for JMPInstruction {
         println!("JMP\t{:#04x}", self.address)
     }
 
-    fn execute(&self, core: &mut Avrcore) {
-        core.pc = self.address as u16;
+    fn objdump(&self) -> (String, String, Option<String>) {
+        (String::from("jmp"), objdump_address(self.address as u32), None)
+    }
+
+    fn size(&self) -> u16 {
+        4
+    }
+
+    fn branch_target(&self, _addr: u32) -> Option<u32> {
+        Some(self.address as u32)
+    }
+
+    fn falls_through(&self) -> bool {
+        false
+    }
+
+    fn execute(&self, core: &mut Avrcore) -> Result<(), ExecError> {
+        core.pc = self.address;
+
+        Ok(())
+    }
+
+    fn cycles(&self) -> u64 {
+        3
     }
 }
 
@@ -64,10 +143,16 @@ impl Instruction for EORInstruction {
         println!("EOR\tr{}, r{}", self.rd, self.rr)
     }
 
-    fn execute(&self, core: &mut Avrcore) {
-        core.general[self.rd as usize] = core.general[self.rd as usize] ^ core.general[self.rr as usize];
+    fn objdump(&self) -> (String, String, Option<String>) {
+        (String::from("eor"), format!("r{}, r{}", self.rd, self.rr), None)
+    }
 
-        core.pc.add_assign(2)
+    fn execute(&self, core: &mut Avrcore) -> Result<(), ExecError> {
+        core.general[self.rd as usize] ^= core.general[self.rr as usize];
+
+        core.pc.add_assign(2);
+
+        Ok(())
     }
 
 }
@@ -84,10 +169,16 @@ impl Instruction for OUTInstruction {
         println!("OUT\t{:#04x}, R{}", self.a, self.rr)
     }
 
-    fn execute(&self, core: &mut Avrcore) {
-        core.io[self.a as usize] = core.general[self.rr as usize];
+    fn objdump(&self) -> (String, String, Option<String>) {
+        (String::from("out"), format!("{:#04x}, r{}", self.a, self.rr), Some(format!("{}", self.a)))
+    }
+
+    fn execute(&self, core: &mut Avrcore) -> Result<(), ExecError> {
+        core.store(self.a as u16 + 0x20, core.general[self.rr as usize]);
 
         core.pc.add_assign(2);
+
+        Ok(())
     }
 }
 
@@ -103,10 +194,16 @@ impl Instruction for LDIInstruction {
         println!("LDI\tR{}, {:#04x}", self.rd, self.k)
     }
 
-    fn execute(&self, core: &mut Avrcore) {
+    fn objdump(&self) -> (String, String, Option<String>) {
+        (String::from("ldi"), format!("r{}, 0x{:02X}", self.rd, self.k), Some(format!("{}", self.k)))
+    }
+
+    fn execute(&self, core: &mut Avrcore) -> Result<(), ExecError> {
         core.general[self.rd as usize] = self.k;
 
-        core.pc.add_assign(2)
+        core.pc.add_assign(2);
+
+        Ok(())
     }
 
 }
@@ -122,18 +219,34 @@ impl Instruction for CALLInstruction {
         println!("CALL\t{:#04x}", self.k)
     }
 
-    fn execute(&self, core: &mut Avrcore) {
+    fn objdump(&self) -> (String, String, Option<String>) {
+        (String::from("call"), objdump_address(self.k), None)
+    }
+
+    fn size(&self) -> u16 {
+        4
+    }
+
+    fn branch_target(&self, _addr: u32) -> Option<u32> {
+        Some(self.k)
+    }
+
+    fn execute(&self, core: &mut Avrcore) -> Result<(), ExecError> {
         // Store current PC by splitting it into two u8 and put it on the stack
         let pc = core.pc + 4; // Point to next instruction. NOTE: the real CPU adds 2. However our flash memory operates on bytes and not words (2*bytes).
-        let lowerBytes = (pc & 0xFF) as u8;
-        let upperBytes = ((pc) >> 8) as u8;
+        let lower_bytes = (pc & 0xFF) as u8;
+        let upper_bytes = (pc >> 8) as u8;
 
-        core.sram[core.sp.getCurrentAddr() as usize] = lowerBytes;
-        core.sp.decrement(1);
-        core.sram[core.sp.getCurrentAddr() as usize] = upperBytes;
-        core.sp.decrement(1);
+        core.push(lower_bytes);
+        core.push(upper_bytes);
 
         core.pc = self.k as u16;
+
+        Ok(())
+    }
+
+    fn cycles(&self) -> u64 {
+        4
     }
 }
 
@@ -148,11 +261,20 @@ impl Instruction for PUSHInstruction {
         println!("PUSH\tR{}", self.rr)
     }
 
-    fn execute(&self, core: &mut Avrcore) {
-        core.sram[core.sp.getCurrentAddr() as usize] = self.rr;
-        core.sp.decrement(1);
+    fn objdump(&self) -> (String, String, Option<String>) {
+        (String::from("push"), format!("r{}", self.rr), None)
+    }
+
+    fn execute(&self, core: &mut Avrcore) -> Result<(), ExecError> {
+        core.push(core.general[self.rr as usize]);
 
         core.pc.add_assign(2);
+
+        Ok(())
+    }
+
+    fn cycles(&self) -> u64 {
+        2
     }
 
 }
@@ -163,11 +285,40 @@ pub struct RCALLInstruction {
     pub k: u16
 }
 
+impl RCALLInstruction {
+    // Sign extended byte offset relative to the next instruction
+    pub fn offset(&self) -> i16 {
+        (((self.k << 4) as i16) >> 4) * 2
+    }
+}
+
 impl Instruction for RCALLInstruction {
     fn pretty_print(&self) {
         println!("RCALL\t{}", self.k)
     }
 
+    fn objdump(&self) -> (String, String, Option<String>) {
+        (String::from("rcall"), objdump_relative(self.offset()), None)
+    }
+
+    fn branch_target(&self, addr: u32) -> Option<u32> {
+        Some((addr as i32 + 2 + self.offset() as i32) as u32)
+    }
+
+    fn execute(&self, core: &mut Avrcore) -> Result<(), ExecError> {
+        // Return address as a byte address, like CALL
+        let pc = core.pc.wrapping_add(2);
+        core.push((pc & 0xFF) as u8);
+        core.push((pc >> 8) as u8);
+
+        core.pc = (pc as i32 + self.offset() as i32) as u16;
+
+        Ok(())
+    }
+
+    fn cycles(&self) -> u64 {
+        3
+    }
 }
 
 //---------------------
@@ -182,6 +333,17 @@ impl Instruction for INInstruction {
         println!("IN\tR{}, {}", self.rd, self.a)
     }
 
+    fn objdump(&self) -> (String, String, Option<String>) {
+        (String::from("in"), format!("r{}, {:#04x}", self.rd, self.a), Some(format!("{}", self.a)))
+    }
+
+    fn execute(&self, core: &mut Avrcore) -> Result<(), ExecError> {
+        core.general[self.rd as usize] = core.load(self.a as u16 + 0x20);
+
+        core.pc.add_assign(2);
+
+        Ok(())
+    }
 }
 
 //--------------------
@@ -196,6 +358,21 @@ impl Instruction for STDyInstruction {
         println!("STD Y+{}, r{}", self.q, self.rr)
     }
 
+    fn objdump(&self) -> (String, String, Option<String>) {
+        (String::from("std"), format!("Y+{}, r{}", self.q, self.rr), Some(format!("{:#04x}", self.q)))
+    }
+
+    fn execute(&self, core: &mut Avrcore) -> Result<(), ExecError> {
+        core.store(core.y_pointer().wrapping_add(self.q as u16), core.general[self.rr as usize]);
+
+        core.pc.add_assign(2);
+
+        Ok(())
+    }
+
+    fn cycles(&self) -> u64 {
+        2
+    }
 }
 
 //-------------------
@@ -208,6 +385,38 @@ pub struct LDDyInstruction {
 impl Instruction for LDDyInstruction {
     fn pretty_print(&self) { println!("LDD R{}, Y+{}", self.rd, self.q)}
 
+    fn objdump(&self) -> (String, String, Option<String>) {
+        (String::from("ldd"), format!("r{}, Y+{}", self.rd, self.q), Some(format!("{:#04x}", self.q)))
+    }
+
+    fn execute(&self, core: &mut Avrcore) -> Result<(), ExecError> {
+        core.general[self.rd as usize] = core.load(core.y_pointer().wrapping_add(self.q as u16));
+
+        core.pc.add_assign(2);
+
+        Ok(())
+    }
+
+    fn cycles(&self) -> u64 {
+        2
+    }
+}
+
+// Rd <- Rd + Rr + carry, setting H, S, V, N, Z and C
+fn execute_add(core: &mut Avrcore, rd: u8, rr: u8, carry: bool) {
+    let (a, b) = (core.general[rd as usize], core.general[rr as usize]);
+    let sum = a as u16 + b as u16 + carry as u16;
+    let result = sum as u8;
+
+    core.sreg.H = (a & 0x0F) + (b & 0x0F) + carry as u8 > 0x0F;
+    core.sreg.V = (a ^ result) & (b ^ result) & 0x80 != 0;
+    core.sreg.N = result & 0x80 != 0;
+    core.sreg.S = core.sreg.N != core.sreg.V;
+    core.sreg.Z = result == 0;
+    core.sreg.C = sum > 0xFF;
+
+    core.general[rd as usize] = result;
+    core.pc.add_assign(2);
 }
 
 //------------------
@@ -222,6 +431,15 @@ impl Instruction for ADDInstruction {
         println!("ADD R{}, R{}", self.rd, self.rr)
     }
 
+    fn objdump(&self) -> (String, String, Option<String>) {
+        (String::from("add"), format!("r{}, r{}", self.rd, self.rr), None)
+    }
+
+    fn execute(&self, core: &mut Avrcore) -> Result<(), ExecError> {
+        execute_add(core, self.rd, self.rr, false);
+
+        Ok(())
+    }
 }
 
 //------------------
@@ -236,6 +454,16 @@ impl Instruction for ADCInstruction {
         println!("ADC R{}, R{}", self.rd, self.rr)
     }
 
+    fn objdump(&self) -> (String, String, Option<String>) {
+        (String::from("adc"), format!("r{}, r{}", self.rd, self.rr), None)
+    }
+
+    fn execute(&self, core: &mut Avrcore) -> Result<(), ExecError> {
+        let carry = core.sreg.C;
+        execute_add(core, self.rd, self.rr, carry);
+
+        Ok(())
+    }
 }
 
 //------------------
@@ -249,6 +477,21 @@ impl Instruction for POPInstruction {
         println!("POP R{}", self.rd)
     }
 
+    fn objdump(&self) -> (String, String, Option<String>) {
+        (String::from("pop"), format!("r{}", self.rd), None)
+    }
+
+    fn execute(&self, core: &mut Avrcore) -> Result<(), ExecError> {
+        core.general[self.rd as usize] = core.pop();
+
+        core.pc.add_assign(2);
+
+        Ok(())
+    }
+
+    fn cycles(&self) -> u64 {
+        2
+    }
 }
 
 //------------------
@@ -261,6 +504,26 @@ impl Instruction for RETInstruction {
         println!("RET")
     }
 
+    fn objdump(&self) -> (String, String, Option<String>) {
+        (String::from("ret"), String::new(), None)
+    }
+
+    fn falls_through(&self) -> bool {
+        false
+    }
+
+    fn execute(&self, core: &mut Avrcore) -> Result<(), ExecError> {
+        let upper_bytes = core.pop() as u16;
+        let lower_bytes = core.pop() as u16;
+
+        core.pc = upper_bytes << 8 | lower_bytes;
+
+        Ok(())
+    }
+
+    fn cycles(&self) -> u64 {
+        4
+    }
 }
 
 //------------------
@@ -273,6 +536,38 @@ impl Instruction for CLIInstruction {
         println!("CLI")
     }
 
+    fn objdump(&self) -> (String, String, Option<String>) {
+        (String::from("cli"), String::new(), None)
+    }
+
+    fn execute(&self, core: &mut Avrcore) -> Result<(), ExecError> {
+        core.sreg.I = false;
+
+        core.pc.add_assign(2);
+
+        Ok(())
+    }
+}
+
+//------------------
+#[derive(Debug, Copy, Clone)]
+pub struct NOPInstruction {
+}
+
+impl Instruction for NOPInstruction {
+    fn pretty_print(&self) {
+        println!("NOP")
+    }
+
+    fn objdump(&self) -> (String, String, Option<String>) {
+        (String::from("nop"), String::new(), None)
+    }
+
+    fn execute(&self, core: &mut Avrcore) -> Result<(), ExecError> {
+        core.pc.add_assign(2);
+
+        Ok(())
+    }
 }
 
 //------------------
@@ -286,4 +581,315 @@ impl Instruction for RJMPInstruction {
         println!("RJMP {}", self.k)
     }
 
-}
\ No newline at end of file
+    fn objdump(&self) -> (String, String, Option<String>) {
+        (String::from("rjmp"), objdump_relative(self.k), None)
+    }
+
+    fn branch_target(&self, addr: u32) -> Option<u32> {
+        Some((addr as i32 + 2 + self.k as i32) as u32)
+    }
+
+    fn falls_through(&self) -> bool {
+        false
+    }
+
+    fn execute(&self, core: &mut Avrcore) -> Result<(), ExecError> {
+        core.pc = (core.pc as i32 + 2 + self.k as i32) as u16;
+
+        Ok(())
+    }
+
+    fn cycles(&self) -> u64 {
+        2
+    }
+}
+
+// avr-objdump names conditional branches after the SREG bit they test,
+// BRBS first and BRBC second
+const BRANCH_MNEMONICS: [(&str, &str); 8] = [
+    ("brcs", "brcc"), ("breq", "brne"), ("brmi", "brpl"), ("brvs", "brvc"),
+    ("brlt", "brge"), ("brhs", "brhc"), ("brts", "brtc"), ("brie", "brid"),
+];
+
+// Relative branch taken when SREG bit `s` equals `set`
+fn execute_branch(core: &mut Avrcore, s: u8, k: i16, set: bool) {
+    core.pc.add_assign(2);
+
+    if (core.sreg.to_byte() >> s & 1 != 0) == set {
+        core.pc = (core.pc as i32 + k as i32) as u16;
+        core.cycles += 1;
+    }
+}
+
+//------------------
+#[derive(Debug, Copy, Clone)]
+pub struct BRBSInstruction {
+    pub s: u8, // SREG bit
+    pub k: i16, // Offset in bytes
+}
+
+impl Instruction for BRBSInstruction {
+    fn pretty_print(&self) {
+        println!("BRBS {}, {}", self.s, self.k)
+    }
+
+    fn objdump(&self) -> (String, String, Option<String>) {
+        (String::from(BRANCH_MNEMONICS[self.s as usize].0), objdump_relative(self.k), None)
+    }
+
+    fn branch_target(&self, addr: u32) -> Option<u32> {
+        Some((addr as i32 + 2 + self.k as i32) as u32)
+    }
+
+    // One cycle more when taken
+    fn execute(&self, core: &mut Avrcore) -> Result<(), ExecError> {
+        execute_branch(core, self.s, self.k, true);
+
+        Ok(())
+    }
+}
+
+//------------------
+#[derive(Debug, Copy, Clone)]
+pub struct BRBCInstruction {
+    pub s: u8, // SREG bit
+    pub k: i16, // Offset in bytes
+}
+
+impl Instruction for BRBCInstruction {
+    fn pretty_print(&self) {
+        println!("BRBC {}, {}", self.s, self.k)
+    }
+
+    fn objdump(&self) -> (String, String, Option<String>) {
+        (String::from(BRANCH_MNEMONICS[self.s as usize].1), objdump_relative(self.k), None)
+    }
+
+    fn branch_target(&self, addr: u32) -> Option<u32> {
+        Some((addr as i32 + 2 + self.k as i32) as u32)
+    }
+
+    // One cycle more when taken
+    fn execute(&self, core: &mut Avrcore) -> Result<(), ExecError> {
+        execute_branch(core, self.s, self.k, false);
+
+        Ok(())
+    }
+}
+
+//------------------
+#[derive(Debug, Copy, Clone)]
+pub struct SEIInstruction {
+}
+
+impl Instruction for SEIInstruction {
+    fn pretty_print(&self) {
+        println!("SEI")
+    }
+
+    fn objdump(&self) -> (String, String, Option<String>) {
+        (String::from("sei"), String::new(), None)
+    }
+
+    fn execute(&self, core: &mut Avrcore) -> Result<(), ExecError> {
+        core.sreg.I = true;
+        core.interrupt_inhibit = true;
+
+        core.pc.add_assign(2);
+
+        Ok(())
+    }
+}
+
+//------------------
+#[derive(Debug, Copy, Clone)]
+pub struct RETIInstruction {
+}
+
+impl Instruction for RETIInstruction {
+    fn pretty_print(&self) {
+        println!("RETI")
+    }
+
+    fn objdump(&self) -> (String, String, Option<String>) {
+        (String::from("reti"), String::new(), None)
+    }
+
+    fn falls_through(&self) -> bool {
+        false
+    }
+
+    fn execute(&self, core: &mut Avrcore) -> Result<(), ExecError> {
+        let upper_bytes = core.pop() as u16;
+        let lower_bytes = core.pop() as u16;
+
+        core.pc = upper_bytes << 8 | lower_bytes;
+        core.sreg.I = true;
+        core.interrupt_inhibit = true;
+
+        Ok(())
+    }
+
+    fn cycles(&self) -> u64 {
+        4
+    }
+}
+
+//------------------
+#[derive(Debug, Copy, Clone)]
+pub struct SLEEPInstruction {
+}
+
+impl Instruction for SLEEPInstruction {
+    fn pretty_print(&self) {
+        println!("SLEEP")
+    }
+
+    fn objdump(&self) -> (String, String, Option<String>) {
+        (String::from("sleep"), String::new(), None)
+    }
+
+    fn execute(&self, core: &mut Avrcore) -> Result<(), ExecError> {
+        // Wake-up resumes at the instruction following SLEEP
+        core.pc.add_assign(2);
+
+        if let Some(mode) = SleepMode::from_smcr(core.io[SMCR]) {
+            core.enter_sleep(mode);
+        }
+
+        Ok(())
+    }
+}
+
+//------------------
+#[derive(Debug, Copy, Clone)]
+pub struct SPMInstruction {
+}
+
+impl Instruction for SPMInstruction {
+    fn pretty_print(&self) {
+        println!("SPM")
+    }
+
+    fn objdump(&self) -> (String, String, Option<String>) {
+        (String::from("spm"), String::new(), None)
+    }
+
+    fn execute(&self, core: &mut Avrcore) -> Result<(), ExecError> {
+        core.store_program_memory();
+
+        core.pc.add_assign(2);
+
+        Ok(())
+    }
+}
+
+//------------------
+#[derive(Debug, Copy, Clone)]
+pub struct LPMInstruction {
+    pub rd: u8,
+    pub post_increment: bool,
+    pub implied: bool // LPM without operands loads R0
+}
+
+impl Instruction for LPMInstruction {
+    fn pretty_print(&self) {
+        if self.implied {
+            println!("LPM")
+        } else if self.post_increment {
+            println!("LPM R{}, Z+", self.rd)
+        } else {
+            println!("LPM R{}, Z", self.rd)
+        }
+    }
+
+    fn objdump(&self) -> (String, String, Option<String>) {
+        (String::from("lpm"), objdump_z_operands(self.rd, self.post_increment, self.implied), None)
+    }
+
+    fn execute(&self, core: &mut Avrcore) -> Result<(), ExecError> {
+        let z = core.z_pointer();
+        core.general[self.rd as usize] = core.load_program_memory(z as u32);
+
+        if self.post_increment {
+            core.set_z_pointer(z.wrapping_add(1));
+        }
+
+        core.pc.add_assign(2);
+
+        Ok(())
+    }
+
+    fn cycles(&self) -> u64 {
+        3
+    }
+}
+
+//------------------
+#[derive(Debug, Copy, Clone)]
+pub struct ELPMInstruction {
+    pub rd: u8,
+    pub post_increment: bool,
+    pub implied: bool // ELPM without operands loads R0
+}
+
+impl Instruction for ELPMInstruction {
+    fn pretty_print(&self) {
+        if self.implied {
+            println!("ELPM")
+        } else if self.post_increment {
+            println!("ELPM R{}, Z+", self.rd)
+        } else {
+            println!("ELPM R{}, Z", self.rd)
+        }
+    }
+
+    fn objdump(&self) -> (String, String, Option<String>) {
+        (String::from("elpm"), objdump_z_operands(self.rd, self.post_increment, self.implied), None)
+    }
+
+    fn execute(&self, core: &mut Avrcore) -> Result<(), ExecError> {
+        // RAMPZ:Z forms a 24 bit byte address
+        let address = (core.io[RAMPZ] as u32) << 16 | core.z_pointer() as u32;
+        core.general[self.rd as usize] = core.load_program_memory(address);
+
+        if self.post_increment {
+            let next = address.wrapping_add(1);
+            core.set_z_pointer((next & 0xFFFF) as u16);
+            core.io[RAMPZ] = (next >> 16) as u8;
+        }
+
+        core.pc.add_assign(2);
+
+        Ok(())
+    }
+
+    fn cycles(&self) -> u64 {
+        3
+    }
+}
+
+//------------------
+// A program memory word that does not decode to a supported instruction
+#[derive(Debug, Copy, Clone)]
+pub struct WORDInstruction {
+    pub word: u16
+}
+
+impl Instruction for WORDInstruction {
+    fn pretty_print(&self) {
+        println!(".WORD {:#06x}", self.word)
+    }
+
+    fn objdump(&self) -> (String, String, Option<String>) {
+        (String::from(".word"), format!("{:#06x}", self.word), Some(String::from("????")))
+    }
+
+    fn falls_through(&self) -> bool {
+        false
+    }
+
+    fn execute(&self, core: &mut Avrcore) -> Result<(), ExecError> {
+        Err(ExecError::Undecodable { address: core.pc, word: self.word })
+    }
+}
@@ -0,0 +1,169 @@
+use crate::avrcore::Avrcore;
+use crate::sleep::{ClockDomain, WakeSource};
+
+// External Interrupt Control Register A (memory mapped)
+pub const EICRA: u16 = 0x69;
+// Asynchronous Status Register (memory mapped)
+pub const ASSR: u16 = 0xB6;
+const ASSR_AS2: u8 = 5;
+
+// Entries in the vector table, including reset
+pub const VECTOR_COUNT: u8 = 26;
+
+// ATmega328P interrupt vectors. The discriminant is the vector number, so the
+// handler lives at flash word 2*n (byte address 4*n).
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Interrupt {
+    Int0 = 1,
+    Int1 = 2,
+    PcInt0 = 3,
+    PcInt1 = 4,
+    PcInt2 = 5,
+    Wdt = 6,
+    Timer2CompA = 7,
+    Timer2CompB = 8,
+    Timer2Ovf = 9,
+    Timer1Capt = 10,
+    Timer1CompA = 11,
+    Timer1CompB = 12,
+    Timer1Ovf = 13,
+    Timer0CompA = 14,
+    Timer0CompB = 15,
+    Timer0Ovf = 16,
+    SpiStc = 17,
+    UsartRx = 18,
+    UsartUdre = 19,
+    UsartTx = 20,
+    Adc = 21,
+    EeReady = 22,
+    AnalogComp = 23,
+    Twi = 24,
+    SpmReady = 25,
+}
+
+impl Interrupt {
+    pub fn vector(&self) -> u8 {
+        *self as u8
+    }
+
+    // Byte address of the vector table entry
+    pub fn address(&self) -> u16 {
+        self.vector() as u16 * 4
+    }
+
+    // Vector name as used in the datasheet and avr-libc, without the _vect suffix
+    pub fn name(&self) -> &'static str {
+        use Interrupt::*;
+        match self {
+            Int0 => "INT0",
+            Int1 => "INT1",
+            PcInt0 => "PCINT0",
+            PcInt1 => "PCINT1",
+            PcInt2 => "PCINT2",
+            Wdt => "WDT",
+            Timer2CompA => "TIMER2_COMPA",
+            Timer2CompB => "TIMER2_COMPB",
+            Timer2Ovf => "TIMER2_OVF",
+            Timer1Capt => "TIMER1_CAPT",
+            Timer1CompA => "TIMER1_COMPA",
+            Timer1CompB => "TIMER1_COMPB",
+            Timer1Ovf => "TIMER1_OVF",
+            Timer0CompA => "TIMER0_COMPA",
+            Timer0CompB => "TIMER0_COMPB",
+            Timer0Ovf => "TIMER0_OVF",
+            SpiStc => "SPI_STC",
+            UsartRx => "USART_RX",
+            UsartUdre => "USART_UDRE",
+            UsartTx => "USART_TX",
+            Adc => "ADC",
+            EeReady => "EE_READY",
+            AnalogComp => "ANALOG_COMP",
+            Twi => "TWI",
+            SpmReady => "SPM_READY",
+        }
+    }
+
+    pub fn from_vector(vector: u8) -> Option<Interrupt> {
+        use Interrupt::*;
+        let irq = match vector {
+            1 => Int0,
+            2 => Int1,
+            3 => PcInt0,
+            4 => PcInt1,
+            5 => PcInt2,
+            6 => Wdt,
+            7 => Timer2CompA,
+            8 => Timer2CompB,
+            9 => Timer2Ovf,
+            10 => Timer1Capt,
+            11 => Timer1CompA,
+            12 => Timer1CompB,
+            13 => Timer1Ovf,
+            14 => Timer0CompA,
+            15 => Timer0CompB,
+            16 => Timer0Ovf,
+            17 => SpiStc,
+            18 => UsartRx,
+            19 => UsartUdre,
+            20 => UsartTx,
+            21 => Adc,
+            22 => EeReady,
+            23 => AnalogComp,
+            24 => Twi,
+            25 => SpmReady,
+            _ => return None
+        };
+
+        Some(irq)
+    }
+
+    // Look up an interrupt by its datasheet vector name, e.g. `TIMER0_OVF`
+    pub fn from_name(name: &str) -> Option<Interrupt> {
+        (1..=25).filter_map(Interrupt::from_vector).find(|irq| irq.name() == name)
+    }
+
+    // The clock domain the source peripheral runs from. External interrupts,
+    // pin changes, the watchdog, TWI address match and the NVM controllers are
+    // asynchronous and keep working with every clock stopped.
+    pub fn clock_domain(&self, core: &Avrcore) -> Option<ClockDomain> {
+        use Interrupt::*;
+        match self {
+            Int0 | Int1 | PcInt0 | PcInt1 | PcInt2 | Wdt | Twi | EeReady | SpmReady => None,
+            Timer2CompA | Timer2CompB | Timer2Ovf => {
+                if timer2_async(core) {
+                    Some(ClockDomain::Asy)
+                } else {
+                    Some(ClockDomain::Io)
+                }
+            },
+            Adc => Some(ClockDomain::Adc),
+            _ => Some(ClockDomain::Io)
+        }
+    }
+
+    pub fn wake_source(&self, core: &Avrcore) -> WakeSource {
+        use Interrupt::*;
+        match self {
+            Int0 | Int1 => {
+                // ISCn1:ISCn0 == 00 selects the low level interrupt
+                let shift = if *self == Int0 { 0 } else { 2 };
+                if (core.read_data(EICRA) >> shift) & 0b11 == 0 {
+                    WakeSource::ExternalLevel
+                } else {
+                    WakeSource::ExternalEdge
+                }
+            },
+            PcInt0 | PcInt1 | PcInt2 => WakeSource::PinChange,
+            Wdt => WakeSource::Watchdog,
+            Twi => WakeSource::TwiAddressMatch,
+            Timer2CompA | Timer2CompB | Timer2Ovf => WakeSource::Timer2 { asynchronous: timer2_async(core) },
+            EeReady | SpmReady => WakeSource::SpmEepromReady,
+            Adc => WakeSource::Adc,
+            _ => WakeSource::OtherIo
+        }
+    }
+}
+
+fn timer2_async(core: &Avrcore) -> bool {
+    core.read_data(ASSR) & (1 << ASSR_AS2) != 0
+}
@@ -0,0 +1,43 @@
+#![allow(clippy::upper_case_acronyms)]
+
+// AVR (ATmega328P) instruction set simulator. `Simulator` is the entry point
+// for embedding; the modules below expose the core, loaders and decoder.
+
+pub mod avrcore;
+pub mod callstack;
+pub mod coverage;
+pub mod hexreader;
+pub mod hexwriter;
+pub mod disasm;
+pub mod disassembler;
+pub mod dwarf;
+pub mod elfreader;
+pub mod error;
+pub mod fuses;
+pub mod gdbstub;
+pub mod history;
+pub mod instructions;
+pub mod listing;
+pub mod interrupts;
+pub mod memimage;
+pub mod profile;
+pub mod reset;
+pub mod selfprog;
+pub mod simulator;
+pub mod sleep;
+pub mod stack;
+pub mod snapshot;
+pub mod srecreader;
+#[cfg(test)]
+mod testutil;
+pub mod trace;
+pub mod usart;
+pub mod vcd;
+pub mod watch;
+#[macro_use] extern crate bitpat;
+
+pub use crate::avrcore::Avrcore;
+pub use crate::error::{Error, LoadError, DecodeError, ExecError};
+pub use crate::fuses::Fuses;
+pub use crate::memimage::{ImageFormat, MemoryImage, Program};
+pub use crate::simulator::{Simulator, SimulatorBuilder, StopReason};
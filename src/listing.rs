@@ -0,0 +1,211 @@
+use crate::elfreader::{ElfImage, SymbolKind, SymbolBinding, DATA_OFFSET};
+use crate::instructions::{Instruction, Opcodes};
+use crate::error::LoadError;
+use std::fs;
+
+// Flash symbols with a single preferred name per address, sorted by address
+#[derive(Debug, Default)]
+pub struct SymbolMap {
+    symbols: Vec<(u32, String)>,
+}
+
+impl SymbolMap {
+    pub fn from_elf(elf: &ElfImage) -> SymbolMap {
+        let mut candidates: Vec<_> = elf.flash_symbols();
+
+        // Prefer functions, then the strongest binding
+        candidates.sort_by_key(|symbol| {
+            (symbol.value, symbol.kind != SymbolKind::Function, std::cmp::Reverse(symbol.binding))
+        });
+        candidates.dedup_by_key(|symbol| symbol.value);
+
+        SymbolMap {
+            symbols: candidates.iter().map(|symbol| (symbol.value, symbol.name.clone())).collect()
+        }
+    }
+
+    pub fn from_nm(path: &str) -> Result<SymbolMap, LoadError> {
+        let data = fs::read_to_string(path).map_err(|err| LoadError::io(path, err))?;
+
+        Ok(SymbolMap::parse_nm(&data))
+    }
+
+    // Parse `avr-nm` output, keeping text symbols
+    pub fn parse_nm(data: &str) -> SymbolMap {
+        let mut symbols: Vec<(u32, String, SymbolBinding)> = Vec::new();
+
+        for line in data.lines() {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.len() != 3 {
+                continue
+            }
+
+            let address = match u32::from_str_radix(fields[0], 16) {
+                Ok(address) if address < DATA_OFFSET => address,
+                _ => continue
+            };
+
+            let binding = match fields[1] {
+                "T" => SymbolBinding::Global,
+                "W" => SymbolBinding::Weak,
+                "t" | "w" => SymbolBinding::Local,
+                _ => continue
+            };
+
+            symbols.push((address, fields[2].to_string(), binding));
+        }
+
+        symbols.sort_by_key(|(address, _, binding)| (*address, std::cmp::Reverse(*binding)));
+        symbols.dedup_by_key(|(address, _, _)| *address);
+
+        SymbolMap {
+            symbols: symbols.into_iter().map(|(address, name, _)| (address, name)).collect()
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.symbols.is_empty()
+    }
+
+    // Symbol starting exactly at `addr`
+    pub fn label_at(&self, addr: u32) -> Option<&str> {
+        self.symbols.binary_search_by_key(&addr, |(address, _)| *address)
+            .ok()
+            .map(|idx| self.symbols[idx].1.as_str())
+    }
+
+    // Closest symbol at or below `addr` and the offset from it
+    pub fn lookup(&self, addr: u32) -> Option<(&str, u32)> {
+        let idx = match self.symbols.binary_search_by_key(&addr, |(address, _)| *address) {
+            Ok(idx) => idx,
+            Err(0) => return None,
+            Err(idx) => idx - 1
+        };
+
+        let (address, name) = &self.symbols[idx];
+        Some((name.as_str(), addr - address))
+    }
+
+    pub fn address_of(&self, name: &str) -> Option<u32> {
+        self.symbols.iter().find(|(_, symbol)| symbol == name).map(|(address, _)| *address)
+    }
+
+    // `<symbol>` or `<symbol+0x12>` as printed by avr-objdump
+    pub fn annotate(&self, addr: u32) -> Option<String> {
+        self.lookup(addr).map(|(name, offset)| {
+            if offset == 0 {
+                format!("<{}>", name)
+            } else {
+                format!("<{}+{:#x}>", name, offset)
+            }
+        })
+    }
+}
+
+// The header lines avr-objdump prints before the listing
+pub struct ListingHeader {
+    pub file_name: String,
+    pub file_format: String, // "ihex" or "elf32-avr"
+    pub section: String, // ".sec1" for Intel HEX input, ".text" for ELF
+}
+
+// Comment avr-objdump prints after the operands, including its leading space.
+// Branches show their target and the symbol it falls in.
+pub fn objdump_comment(addr: usize, opcode: &Opcodes, symbols: &SymbolMap) -> Option<String> {
+    match opcode.branch_target(addr as u32) {
+        Some(target) => match symbols.annotate(target) {
+            Some(annotation) => Some(format!(" {:#x} {}", target, annotation)),
+            None => Some(format!("  {:#x}", target))
+        },
+        None => opcode.objdump().2.map(|comment| format!(" {}", comment))
+    }
+}
+
+// Address, raw bytes, mnemonic, operands and comment in avr-objdump columns
+pub fn format_line(addr: usize, raw: &[u8], mnemonic: &str, operands: &str, comment: Option<&str>) -> String {
+    let raw: String = raw.iter().map(|byte| format!("{:02x} ", byte)).collect();
+
+    let mut line = format!("{:>4x}:\t{:<12}\t{}", addr, raw, mnemonic);
+    if !operands.is_empty() {
+        line += &format!("\t{}", operands);
+    }
+    if let Some(comment) = comment {
+        line += &format!("\t;{}", comment);
+    }
+
+    line
+}
+
+// One listing line: address, raw bytes, mnemonic, operands and comment
+pub fn objdump_line(progmem: &[u8], addr: usize, opcode: &Opcodes, symbols: &SymbolMap) -> String {
+    let size = opcode.size() as usize;
+    let raw = &progmem[addr..(addr + size).min(progmem.len())];
+
+    let (mnemonic, operands, _) = opcode.objdump();
+    let comment = objdump_comment(addr, opcode, symbols);
+
+    format_line(addr, raw, &mnemonic, &operands, comment.as_deref())
+}
+
+// Render an `avr-objdump -d` style listing of the decoded instructions
+pub fn objdump_listing(progmem: &[u8], decoded: &[(usize, Opcodes)], symbols: &SymbolMap, header: &ListingHeader) -> String {
+    let mut listing = format!("\n{}:     file format {}\n\n\nDisassembly of section {}:\n",
+                              header.file_name, header.file_format, header.section);
+
+    for (idx, (addr, opcode)) in decoded.iter().enumerate() {
+        let label = match symbols.label_at(*addr as u32) {
+            Some(name) => Some(name.to_string()),
+            None if idx == 0 => Some(header.section.clone()),
+            None => None
+        };
+
+        if let Some(label) = label {
+            listing += &format!("\n{:08x} <{}>:\n", addr, label);
+        }
+
+        listing += &objdump_line(progmem, *addr, opcode, symbols);
+        listing += "\n";
+    }
+
+    listing
+}
+
+// Tests
+#[cfg(test)]
+mod tests {
+    use crate::listing::{objdump_listing, ListingHeader, SymbolMap};
+    use crate::disassembler;
+    use crate::elfreader;
+    use crate::hexreader;
+    use std::fs;
+
+    #[test]
+    fn matches_objdump() {
+        let image = hexreader::ihex_to_bytes("testprogram.hex").unwrap();
+        let (dissasm, flash_idx) = disassembler::dissasm_bytes(&image);
+        let decoded: Vec<_> = flash_idx.into_iter().zip(dissasm).collect();
+
+        let header = ListingHeader {
+            file_name: String::from("testprogram.hex"),
+            file_format: String::from("ihex"),
+            section: String::from(".sec1"),
+        };
+
+        let listing = objdump_listing(&image, &decoded, &SymbolMap::default(), &header);
+        assert_eq!(listing, fs::read_to_string("testprogram.hexdisassembly").unwrap());
+    }
+
+    #[test]
+    fn symbols() {
+        let elf = elfreader::elf_to_image("testprogram.bin").unwrap();
+        let symbols = SymbolMap::from_elf(&elf);
+
+        assert_eq!(symbols.label_at(0x80), Some("main"));
+        assert_eq!(symbols.annotate(0x86), Some(String::from("<main+0x6>")));
+        assert_eq!(symbols.address_of("main"), Some(0x80));
+
+        let nm = SymbolMap::parse_nm("00000080 T main\n0000007c W __vector_1\n0000007c T __bad_interrupt\n00800100 D _edata\n");
+        assert_eq!(nm.label_at(0x7c), Some("__bad_interrupt"));
+        assert_eq!(nm.lookup(0x90), Some(("main", 0x10)));
+    }
+}
@@ -1,38 +1,508 @@
-mod avrcore;
-mod hexreader;
-mod disassembler;
-mod instructions;
-#[macro_use] extern crate bitpat;
+mod cli;
+mod monitor;
 
+use avrsim::avrcore;
+use avrsim::coverage::Coverage;
+use avrsim::disasm::{self, OutputFormat};
+use avrsim::disassembler;
+use avrsim::dwarf::LineTable;
+use avrsim::gdbstub::GdbStub;
+use avrsim::hexwriter;
+use avrsim::listing::{self, ListingHeader, SymbolMap};
+use avrsim::profile::Profiler;
+use avrsim::snapshot::Snapshot;
+use avrsim::stack::{StackMonitor, StackOverflow};
+use avrsim::trace::Tracer;
+use avrsim::vcd::{Signal, VcdWriter};
+use avrsim::watch::WatchHit;
+use avrsim::simulator::Probes;
+use avrsim::{ExecError, Fuses, ImageFormat, LoadError, Program, Simulator};
+use crate::cli::{DisasmOptions, FuseSetting, Options, UartBackend};
+use crate::monitor::Monitor;
+use std::env;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::net::TcpListener;
+use std::process;
 
-use crate::instructions::{Instruction, Opcodes};
-use std::collections::HashMap;
+// Why the run loop ended
+enum Stop {
+    Finished, // Asleep with nothing left to wake the core
+    ExitCondition,
+    Watchpoint(WatchHit),
+    Limit(&'static str),
+    Fault(ExecError),
+    StackOverflow(StackOverflow),
+}
 
 fn main() {
-    let ihex = hexreader::ihex_to_dump("testprogram.hex");
-    let (dissasm, flash_idx) = disassembler::dissasm_ihex(ihex);
+    if env::args().nth(1).as_deref() == Some("disasm") {
+        disasm_main();
+    }
+
+    let options = match cli::parse_args(env::args().skip(1)) {
+        Ok(options) => options,
+        Err(message) => {
+            eprintln!("avrsim: {}\n\n{}", message, cli::USAGE);
+            process::exit(cli::EXIT_USAGE);
+        }
+    };
+
+    if options.help {
+        print!("{}", cli::USAGE);
+        return
+    }
+
+    process::exit(run(&options));
+}
+
+fn disasm_main() -> ! {
+    let options = match cli::parse_disasm_args(env::args().skip(2)) {
+        Ok(options) => options,
+        Err(message) => {
+            eprintln!("avrsim: {}\n\n{}", message, cli::DISASM_USAGE);
+            process::exit(cli::EXIT_USAGE);
+        }
+    };
+
+    if options.help {
+        print!("{}", cli::DISASM_USAGE);
+        process::exit(cli::EXIT_OK);
+    }
+
+    match disassemble(&options) {
+        Ok(()) => process::exit(cli::EXIT_OK),
+        Err(err) => {
+            eprintln!("avrsim: {}", err);
+            process::exit(cli::EXIT_LOAD_ERROR);
+        }
+    }
+}
+
+fn load_file(image: &str, format: Option<ImageFormat>, binary_base: u32) -> Result<Program, LoadError> {
+    let bytes = fs::read(image).map_err(|err| LoadError::io(image, err))?;
+
+    match format {
+        Some(format) => Program::parse(&bytes, format, binary_base),
+        None => Program::from_bytes(&bytes, binary_base)
+    }
+}
+
+fn load(options: &Options) -> Result<Program, LoadError> {
+    load_file(&options.image, options.format, options.binary_base)
+}
+
+// Labels from avr-nm output if given, otherwise from the ELF file
+fn symbol_map(nm: &Option<String>, program: &Program) -> Result<SymbolMap, LoadError> {
+    Ok(match (nm, &program.elf) {
+        (Some(nm), _) => SymbolMap::from_nm(nm)?,
+        (None, Some(elf)) => SymbolMap::from_elf(elf),
+        (None, None) => SymbolMap::default()
+    })
+}
+
+fn disassemble(options: &DisasmOptions) -> Result<(), LoadError> {
+    let program = load_file(&options.image, options.format, options.binary_base)?;
+    let symbols = symbol_map(&options.symbols, &program)?;
+    let end = options.end.unwrap_or(program.flash.len()).min(program.flash.len());
+
+    let lines = disasm::disassemble(&program, &symbols, options.start..end, options.mode);
+    match options.output {
+        OutputFormat::Text => print!("{}", disasm::render_text(&lines)),
+        OutputFormat::Json => print!("{}", disasm::render_json(&lines)),
+    }
+
+    Ok(())
+}
+
+fn listing(options: &Options, program: &Program) -> Result<(), LoadError> {
+    let header = ListingHeader {
+        file_name: options.image.clone(),
+        file_format: String::from(program.format.bfd_name()),
+        section: String::from(match program.format {
+            ImageFormat::Elf => ".text",
+            ImageFormat::Binary => ".data",
+            _ => ".sec1"
+        }),
+    };
+    let symbols = symbol_map(&options.symbols, program)?;
+    let (dissasm, flash_idx) = disassembler::dissasm_bytes(&program.flash);
+    let decoded: Vec<_> = flash_idx.into_iter().zip(dissasm).collect();
 
-    /*
-    for asm in &dissasm {
-        asm.pretty_print()
+    print!("{}", listing::objdump_listing(&program.flash, &decoded, &symbols, &header));
+    Ok(())
+}
+
+fn uart_writer(backend: &UartBackend) -> io::Result<Box<dyn Write>> {
+    Ok(match backend {
+        UartBackend::Stdout => Box::new(io::stdout()),
+        UartBackend::Stderr => Box::new(io::stderr()),
+        UartBackend::Null => Box::new(io::sink()),
+        UartBackend::File(path) => Box::new(File::create(path)?),
+    })
+}
+
+fn tracer(options: &Options, sim: &Simulator) -> Result<Tracer, String> {
+    let out: Box<dyn Write> = match &options.trace_file {
+        Some(path) => Box::new(BufWriter::new(File::create(path).map_err(|err| format!("Cannot create {}: {}", path, err))?)),
+        None => Box::new(io::stderr())
+    };
+    let mut tracer = Tracer::new(out, options.trace_format).map_err(|err| format!("Cannot write trace: {}", err))?;
+
+    for spec in options.trace_filters.iter() {
+        tracer.filter(sim.resolve_flash_range(spec).ok_or(format!("Unknown trace filter address or symbol: {}", spec))?);
+    }
+
+    Ok(tracer)
+}
+
+fn vcd(options: &Options, path: &str, sim: &Simulator) -> Result<VcdWriter, String> {
+    let file = File::create(path).map_err(|err| format!("Cannot create {}: {}", path, err))?;
+    let signals = match options.vcd_signals.is_empty() {
+        true => Signal::defaults(),
+        false => options.vcd_signals.clone()
+    };
+
+    VcdWriter::new(Box::new(BufWriter::new(file)), signals, &sim.core).map_err(|err| format!("Cannot write VCD: {}", err))
+}
+
+fn write_profile(options: &Options, profiler: &Profiler) -> io::Result<()> {
+    if let Some(path) = &options.profile {
+        fs::write(path, profiler.flat() + "\n" + &profiler.call_graph())?;
+    }
+    if let Some(path) = &options.profile_folded {
+        fs::write(path, profiler.folded())?;
     }
 
-     */
+    Ok(())
+}
 
-    let flash_map: HashMap<usize, Opcodes> = flash_idx.iter().cloned().zip(dissasm.iter().cloned()).collect();
+// lcov when the ELF image has line information, the per-address report otherwise
+fn write_coverage(options: &Options, path: &str, coverage: &Coverage, sim: &Simulator) -> Result<(), String> {
+    let lines = match &sim.program.elf {
+        Some(elf) => LineTable::from_elf(elf).map_err(|err| err.to_string())?,
+        None => LineTable::default()
+    };
 
-    let mut core = avrcore::Avrcore{
-        sreg: avrcore::SREG::default(),
-        sp: avrcore::StackPointer{ SPH: 0xFF, SPL: 0xFF },
-        pc: 0,
-        general: [0; 32],
-        io: [0; 64],
-        extio: [0; 160],
-        sram: [0; 2047],
-        flash: flash_map,
+    let report = match (&sim.program.elf, lines.is_empty()) {
+        (Some(elf), false) => coverage.lcov(&sim.core, &lines, &elf.symbols),
+        _ => {
+            eprintln!("avrsim: No DWARF line information, writing a per-address coverage report");
+            let symbols = symbol_map(&options.symbols, &sim.program).map_err(|err| err.to_string())?;
+            coverage.report(&sim.core, &symbols)
+        }
     };
 
+    fs::write(path, report).map_err(|err| format!("{}: {}", path, err))
+}
+
+fn simulate(options: &Options, sim: &mut Simulator, uart: &mut dyn Write) -> Stop {
+    let exit_at = options.exit_at.as_ref().and_then(|target| sim.resolve_address(target));
+    let mut instructions: u64 = 0;
+    let mut elapsed = 0.0;
+
     loop {
-        core.execute()
+        let awake = sim.core.sleep.is_none();
+
+        if awake && exit_at == Some(sim.pc()) {
+            return Stop::ExitCondition
+        }
+        if !awake && options.exit_on_sleep {
+            return Stop::ExitCondition
+        }
+        if sim.core.sleeping_forever() {
+            return Stop::Finished
+        }
+        if options.max_cycles.is_some_and(|max| sim.cycles() >= max) {
+            return Stop::Limit("cycle")
+        }
+        if options.max_instructions.is_some_and(|max| instructions >= max) {
+            return Stop::Limit("instruction")
+        }
+        if options.max_time.is_some_and(|max| elapsed >= max) {
+            return Stop::Limit("time")
+        }
+
+        let (before, hz) = (sim.cycles(), sim.core.clock_hz());
+        if let Err(err) = sim.step() {
+            return Stop::Fault(err)
+        }
+
+        for err in sim.take_probe_errors() {
+            eprintln!("avrsim: {}", err);
+        }
+        if let Some(overflow) = sim.take_stack_overflow() {
+            return Stop::StackOverflow(overflow)
+        }
+        if let Some(hit) = sim.take_watch_stops().first() {
+            return Stop::Watchpoint(*hit)
+        }
+        elapsed += (sim.cycles() - before) as f64 / hz as f64;
+        if awake {
+            instructions += 1;
+        }
+
+        let output = sim.core.take_uart_output();
+        if !output.is_empty() {
+            let _ = uart.write_all(&output).and_then(|_| uart.flush());
+        }
+    }
+}
+
+// Run under the control of one avr-gdb connection
+fn debug(port: u16, sim: &mut Simulator, uart: &mut dyn Write) -> io::Result<()> {
+    let listener = TcpListener::bind(("127.0.0.1", port))?;
+    eprintln!("avrsim: Waiting for GDB on port {}", port);
+
+    let (stream, peer) = listener.accept()?;
+    eprintln!("avrsim: GDB connected from {}", peer);
+    GdbStub::new(sim).serve(stream)?;
+
+    uart.write_all(&sim.core.take_uart_output())?;
+    uart.flush()
+}
+
+// The image's own fuses with the command line settings applied over them
+fn fuses(options: &Options, program: &Program) -> Result<Fuses, String> {
+    let mut fuses = Fuses::default();
+    program.apply_fuses(&mut fuses);
+
+    for setting in options.fuses.iter() {
+        match setting {
+            FuseSetting::Flag(flag) => fuses.set_from_flag(flag)?,
+            FuseSetting::FuseHex(path) => fuses.load_fuse_hex(path).map_err(|err| err.to_string())?,
+            FuseSetting::LockHex(path) => fuses.load_lock_hex(path).map_err(|err| err.to_string())?
+        }
+    }
+
+    Ok(fuses)
+}
+
+fn run(options: &Options) -> i32 {
+    let program = match load(options) {
+        Ok(program) => program,
+        Err(err) => {
+            eprintln!("avrsim: {}", err);
+            return cli::EXIT_LOAD_ERROR
+        }
+    };
+
+    if options.listing {
+        return match listing(options, &program) {
+            Ok(()) => cli::EXIT_OK,
+            Err(err) => {
+                eprintln!("avrsim: {}", err);
+                cli::EXIT_LOAD_ERROR
+            }
+        }
+    }
+
+    let fuses = match fuses(options, &program) {
+        Ok(fuses) => fuses,
+        Err(err) => {
+            eprintln!("avrsim: {}", err);
+            return cli::EXIT_LOAD_ERROR
+        }
+    };
+
+    let built = Simulator::builder()
+        .program(program)
+        .fuses(fuses)
+        .clock_hz(options.clock_hz)
+        .build();
+    let mut sim = match built {
+        Ok(sim) => sim,
+        Err(err) => {
+            eprintln!("avrsim: {}", err);
+            return cli::EXIT_LOAD_ERROR
+        }
+    };
+
+    if let Some(path) = &options.load_snapshot {
+        match Snapshot::load(path) {
+            Ok(snapshot) => sim.restore(&snapshot),
+            Err(err) => {
+                eprintln!("avrsim: {}", err);
+                return cli::EXIT_LOAD_ERROR
+            }
+        }
+    }
+
+    if let Some(target) = &options.exit_at {
+        if sim.resolve_address(target).is_none() {
+            eprintln!("avrsim: Unknown exit address or symbol: {}", target);
+            return cli::EXIT_USAGE
+        }
+    }
+
+    for (kind, spec) in options.watches.iter() {
+        match sim.resolve_data_range(spec) {
+            Some(range) => sim.watch(range, *kind),
+            None => {
+                eprintln!("avrsim: Unknown watch address or symbol: {}", spec);
+                return cli::EXIT_USAGE
+            }
+        };
+    }
+
+    let mut uart = match uart_writer(&options.uart) {
+        Ok(uart) => uart,
+        Err(err) => {
+            eprintln!("avrsim: Cannot open UART output: {}", err);
+            return cli::EXIT_USAGE
+        }
+    };
+
+    if options.gdb_port.is_some() || options.monitor {
+        sim.record(options.history);
+    }
+
+    if let Some(port) = options.gdb_port {
+        return match debug(port, &mut sim, uart.as_mut()) {
+            Ok(()) => cli::EXIT_OK,
+            Err(err) => {
+                eprintln!("avrsim: GDB connection failed: {}", err);
+                cli::EXIT_EXEC_ERROR
+            }
+        }
+    }
+
+    if options.monitor {
+        return match Monitor::new(&mut sim, uart.as_mut()).repl() {
+            Ok(()) => cli::EXIT_OK,
+            Err(err) => {
+                eprintln!("avrsim: Monitor failed: {}", err);
+                cli::EXIT_EXEC_ERROR
+            }
+        }
+    }
+
+    let tracer = match options.trace {
+        true => match tracer(options, &sim) {
+            Ok(tracer) => Some(tracer),
+            Err(err) => {
+                eprintln!("avrsim: {}", err);
+                return cli::EXIT_USAGE
+            }
+        },
+        false => None
+    };
+
+    let vcd = match &options.vcd {
+        Some(path) => match vcd(options, path, &sim) {
+            Ok(vcd) => Some(vcd),
+            Err(err) => {
+                eprintln!("avrsim: {}", err);
+                return cli::EXIT_USAGE
+            }
+        },
+        None => None
+    };
+
+    let profiler = match options.profile.is_some() || options.profile_folded.is_some() {
+        true => match symbol_map(&options.symbols, &sim.program) {
+            Ok(symbols) => Some(Profiler::new(symbols, &sim.core)),
+            Err(err) => {
+                eprintln!("avrsim: {}", err);
+                return cli::EXIT_LOAD_ERROR
+            }
+        },
+        false => None
+    };
+
+    let coverage = options.coverage.as_ref().map(|_| Coverage::new());
+
+    let guard = match &options.stack_guard {
+        Some(spec) => match sim.resolve_stack_guard(spec) {
+            Some(guard) => Some(guard),
+            None => {
+                eprintln!("avrsim: Unknown stack guard address or symbol: {}", spec);
+                return cli::EXIT_USAGE
+            }
+        },
+        None => None
+    };
+    let stack = match options.stack_guard.is_some() || options.stack_report {
+        true => Some(StackMonitor::new(&sim.core, guard)),
+        false => None
+    };
+
+    sim.probes = Probes { tracer, vcd, profiler, coverage, stack };
+
+    let stop = simulate(options, &mut sim, uart.as_mut());
+    if let Err(err) = sim.probes.vcd.take().map_or(Ok(()), |mut vcd| vcd.finish(&sim.core)) {
+        eprintln!("avrsim: Cannot write VCD: {}", err);
+    }
+
+    if options.dump_registers {
+        print!("{}", avrcore::register_dump(&sim.core));
+    }
+
+    if let Some(profiler) = &sim.probes.profiler {
+        if let Err(err) = write_profile(options, profiler) {
+            eprintln!("avrsim: Cannot write profile: {}", err);
+        }
+    }
+
+    if let (Some(coverage), Some(path)) = (&sim.probes.coverage, &options.coverage) {
+        if let Err(err) = write_coverage(options, path, coverage, &sim) {
+            eprintln!("avrsim: Cannot write coverage: {}", err);
+        }
     }
+
+    let dumped = dump_memories(options, &sim);
+    if let Err(err) = &dumped {
+        eprintln!("avrsim: Cannot write memory dump: {}", err);
+    }
+
+    let symbols = match options.stack_report || matches!(stop, Stop::StackOverflow(_)) {
+        true => symbol_map(&options.symbols, &sim.program).unwrap_or_default(),
+        false => SymbolMap::default()
+    };
+    if let (Some(stack), true) = (&sim.probes.stack, options.stack_report) {
+        print!("{}", stack.report(&symbols));
+    }
+
+    if let Stop::Watchpoint(hit) = &stop {
+        if hit.write {
+            eprintln!("avrsim: Watchpoint {} hit: {:#06x} written by instruction at {:#x}, {:#04x} -> {:#04x}",
+                      hit.id, hit.address, hit.pc, hit.old, hit.new);
+        } else {
+            eprintln!("avrsim: Watchpoint {} hit: {:#06x} read by instruction at {:#x}, value {:#04x}",
+                      hit.id, hit.address, hit.pc, hit.new);
+        }
+    }
+
+    match stop {
+        Stop::Finished | Stop::ExitCondition | Stop::Watchpoint(_) if dumped.is_ok() => cli::EXIT_OK,
+        Stop::Finished | Stop::ExitCondition | Stop::Watchpoint(_) => cli::EXIT_EXEC_ERROR,
+        Stop::Limit(limit) => {
+            eprintln!("avrsim: {} limit reached after {} cycles", limit, sim.cycles());
+            cli::EXIT_LIMIT
+        },
+        Stop::Fault(err) => {
+            eprintln!("avrsim: {}", err);
+            cli::EXIT_EXEC_ERROR
+        },
+        Stop::StackOverflow(overflow) => {
+            eprint!("avrsim: {}", overflow.describe(&symbols));
+            cli::EXIT_STACK_OVERFLOW
+        }
+    }
+}
+
+fn dump_memories(options: &Options, sim: &Simulator) -> io::Result<()> {
+    if let Some(path) = &options.dump_flash {
+        // Erased pages at the end of flash are left out
+        let end = sim.flash().iter().rposition(|&byte| byte != 0xFF).map_or(0, |idx| (idx + 2) & !1);
+        hexwriter::bytes_to_ihex_file(path, 0, &sim.flash()[..end], hexwriter::DEFAULT_RECORD_LEN)?;
+    }
+    if let Some(path) = &options.dump_eeprom {
+        hexwriter::bytes_to_ihex_file(path, 0, sim.eeprom(), hexwriter::DEFAULT_RECORD_LEN)?;
+    }
+    if let Some(path) = &options.save_snapshot {
+        sim.snapshot().save(path)?;
+    }
+
+    Ok(())
 }
@@ -0,0 +1,288 @@
+use crate::avrcore::Avrcore;
+use crate::elfreader::{self, ElfImage};
+use crate::error::LoadError;
+use crate::fuses::{Fuses, FLASH_WORDS};
+use crate::hexreader;
+use crate::srecreader;
+use std::convert::TryFrom;
+use std::fs;
+
+// A contiguous run of bytes
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Segment {
+    pub base: u32,
+    pub data: Vec<u8>,
+}
+
+impl Segment {
+    pub fn end(&self) -> u32 {
+        self.base + self.data.len() as u32
+    }
+}
+
+// Sparse memory contents as loaded from an image file. Segments are sorted by
+// address and never overlap or touch.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MemoryImage {
+    pub segments: Vec<Segment>,
+    pub start_address: Option<u32>, // Entry point recorded in the file, if any
+}
+
+impl MemoryImage {
+    pub fn from_binary(base: u32, data: &[u8]) -> Result<MemoryImage, LoadError> {
+        let mut image = MemoryImage::default();
+        image.add(base, data)?;
+        Ok(image)
+    }
+
+    // Place `data` at `base`, replacing what was there before. Data past the
+    // end of the 32 bit address space is rejected.
+    pub fn add(&mut self, base: u32, data: &[u8]) -> Result<(), LoadError> {
+        if data.is_empty() {
+            return Ok(())
+        }
+
+        let end = u32::try_from(data.len()).ok().and_then(|len| base.checked_add(len))
+            .ok_or(LoadError::Format(format!("{} bytes at {:#x} run past the end of the address space", data.len(), base)))?;
+        let (touching, mut segments): (Vec<Segment>, Vec<Segment>) = self.segments.drain(..)
+            .partition(|segment| segment.base <= end && segment.end() >= base);
+
+        let merged_base = touching.iter().map(|segment| segment.base).fold(base, u32::min);
+        let merged_end = touching.iter().map(|segment| segment.end()).fold(end, u32::max);
+
+        let mut merged = vec![0xFF; (merged_end - merged_base) as usize];
+        for segment in touching.iter() {
+            let offset = (segment.base - merged_base) as usize;
+            merged[offset..offset + segment.data.len()].copy_from_slice(&segment.data);
+        }
+        let offset = (base - merged_base) as usize;
+        merged[offset..offset + data.len()].copy_from_slice(data);
+
+        segments.push(Segment { base: merged_base, data: merged });
+        segments.sort_by_key(|segment| segment.base);
+        self.segments = segments;
+        Ok(())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.segments.is_empty()
+    }
+
+    pub fn lowest_address(&self) -> u32 {
+        self.segments.first().map_or(0, |segment| segment.base)
+    }
+
+    pub fn end(&self) -> u32 {
+        self.segments.last().map_or(0, |segment| segment.end())
+    }
+
+    // Everything from the lowest address up, gaps read as erased flash (0xFF)
+    pub fn contiguous(&self) -> Vec<u8> {
+        let base = self.lowest_address();
+        let mut bytes = vec![0xFF; (self.end() - base) as usize];
+        for segment in self.segments.iter() {
+            let offset = (segment.base - base) as usize;
+            bytes[offset..offset + segment.data.len()].copy_from_slice(&segment.data);
+        }
+        bytes
+    }
+
+    // Image contents starting at address 0. Images that do not fit in flash
+    // are rejected before anything is allocated.
+    pub fn to_bytes(&self) -> Result<Vec<u8>, LoadError> {
+        let flash_size = FLASH_WORDS as u32 * 2;
+        if self.end() > flash_size {
+            return Err(LoadError::Format(format!("Image ends at {:#x}, past the end of the {} byte flash", self.end(), flash_size)))
+        }
+
+        let mut bytes = vec![0xFF; self.lowest_address() as usize];
+        bytes.extend(self.contiguous());
+        Ok(bytes)
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ImageFormat {
+    Elf,
+    Ihex,
+    Srec,
+    Binary,
+}
+
+impl ImageFormat {
+    // Guess the format from the file contents. Anything that is not ELF and
+    // does not start like a text record is taken to be raw binary.
+    pub fn detect(bytes: &[u8]) -> ImageFormat {
+        if elfreader::is_elf(bytes) {
+            return ImageFormat::Elf
+        }
+
+        let first = bytes.iter().position(|byte| !byte.is_ascii_whitespace()).map(|idx| &bytes[idx..]);
+        match first {
+            Some([b':', ..]) if bytes.is_ascii() => ImageFormat::Ihex,
+            Some([b'S', digit, ..]) if digit.is_ascii_digit() && bytes.is_ascii() => ImageFormat::Srec,
+            _ => ImageFormat::Binary
+        }
+    }
+
+    // Format named on the command line
+    pub fn from_name(name: &str) -> Option<ImageFormat> {
+        match name {
+            "elf" => Some(ImageFormat::Elf),
+            "ihex" | "hex" => Some(ImageFormat::Ihex),
+            "srec" => Some(ImageFormat::Srec),
+            "binary" | "bin" => Some(ImageFormat::Binary),
+            _ => None
+        }
+    }
+
+    // Name avr-objdump uses for the format
+    pub fn bfd_name(&self) -> &'static str {
+        match self {
+            ImageFormat::Elf => "elf32-avr",
+            ImageFormat::Ihex => "ihex",
+            ImageFormat::Srec => "srec",
+            ImageFormat::Binary => "binary",
+        }
+    }
+}
+
+// Load the flash contents of any supported image. `binary_base` is where a raw
+// binary is placed, the other formats carry their own addresses.
+pub fn load_image(path: &str, binary_base: u32) -> Result<(ImageFormat, MemoryImage), LoadError> {
+    let bytes = fs::read(path).map_err(|err| LoadError::io(path, err))?;
+
+    parse_image(&bytes, binary_base)
+}
+
+pub fn parse_image(bytes: &[u8], binary_base: u32) -> Result<(ImageFormat, MemoryImage), LoadError> {
+    let format = ImageFormat::detect(bytes);
+
+    Ok((format, parse_image_as(bytes, format, binary_base)?))
+}
+
+// Parse `bytes` as the given format instead of guessing it
+pub fn parse_image_as(bytes: &[u8], format: ImageFormat, binary_base: u32) -> Result<MemoryImage, LoadError> {
+    let image = match format {
+        ImageFormat::Elf => {
+            let elf = elfreader::parse_elf(bytes)?;
+            let mut image = MemoryImage::from_binary(0, &elf.flash)?;
+            image.start_address = Some(elf.entry);
+            image
+        },
+        ImageFormat::Ihex => hexreader::parse_ihex(&String::from_utf8_lossy(bytes))?,
+        ImageFormat::Srec => srecreader::parse_srec(&String::from_utf8_lossy(bytes))?,
+        ImageFormat::Binary => MemoryImage::from_binary(binary_base, bytes)?,
+    };
+
+    Ok(image)
+}
+
+// A program ready to be run: flash contents from address 0, initial EEPROM
+// contents and the ELF file it came from, if any
+#[derive(Debug)]
+pub struct Program {
+    pub format: ImageFormat,
+    pub flash: Vec<u8>,
+    pub eeprom: Vec<u8>,
+    pub elf: Option<ElfImage>,
+}
+
+impl Program {
+    pub fn load(path: &str, binary_base: u32) -> Result<Program, LoadError> {
+        let bytes = fs::read(path).map_err(|err| LoadError::io(path, err))?;
+
+        Program::from_bytes(&bytes, binary_base)
+    }
+
+    pub fn from_bytes(bytes: &[u8], binary_base: u32) -> Result<Program, LoadError> {
+        Program::parse(bytes, ImageFormat::detect(bytes), binary_base)
+    }
+
+    pub fn parse(bytes: &[u8], format: ImageFormat, binary_base: u32) -> Result<Program, LoadError> {
+        if format == ImageFormat::Elf {
+            let elf = elfreader::parse_elf(bytes)?;
+
+            return Ok(Program {
+                format: ImageFormat::Elf,
+                flash: elf.flash.clone(),
+                eeprom: elf.eeprom.clone(),
+                elf: Some(elf),
+            })
+        }
+
+        let image = parse_image_as(bytes, format, binary_base)?;
+
+        Ok(Program {
+            format,
+            flash: image.to_bytes()?,
+            eeprom: Vec::new(),
+            elf: None,
+        })
+    }
+
+    // ELF files carry their own fuses and lock bits
+    pub fn apply_fuses(&self, fuses: &mut Fuses) {
+        if let Some(elf) = &self.elf {
+            fuses.load_section(&elf.fuse);
+            fuses.load_lock_section(&elf.lock);
+        }
+    }
+}
+
+impl Avrcore {
+    // Initial EEPROM contents of an image. Bytes beyond the end of the EEPROM
+    // are dropped.
+    pub fn load_eeprom(&mut self, bytes: &[u8]) {
+        let end = bytes.len().min(self.eeprom.len());
+        self.eeprom[..end].copy_from_slice(&bytes[..end]);
+    }
+}
+
+// Tests
+#[cfg(test)]
+mod tests {
+    use crate::avrcore::{Avrcore, EEPROM_SIZE};
+    use crate::error::LoadError;
+    use crate::memimage::{ImageFormat, MemoryImage, Program};
+    use std::collections::HashMap;
+
+    #[test]
+    fn merge_segments() {
+        let mut image = MemoryImage::default();
+        image.add(0x10, &[1, 2]).unwrap();
+        image.add(0x00, &[3, 4]).unwrap();
+        image.add(0x12, &[5]).unwrap();
+        image.add(0x11, &[6, 7]).unwrap();
+
+        assert_eq!(image.segments.len(), 2);
+        assert_eq!(image.segments[1].base, 0x10);
+        assert_eq!(image.segments[1].data, vec![1, 6, 7]);
+        assert_eq!(image.to_bytes().unwrap()[0..3], [3, 4, 0xFF]);
+        assert_eq!(image.contiguous().len(), 0x13);
+
+        // Nothing may end past 4 GiB
+        assert!(image.add(0xFFFF_FFFE, &[1, 2, 3]).is_err());
+        assert!(MemoryImage::from_binary(0xFFFF_FFF0, &[0; 16]).is_err());
+
+        // Valid records far beyond flash are rejected, not allocated
+        let far = Program::parse(b"S30780000000AABB13\n", ImageFormat::Srec, 0);
+        assert!(matches!(far, Err(LoadError::Format(_))));
+        assert_eq!(image.segments.len(), 2);
+    }
+
+    #[test]
+    fn detect() {
+        assert_eq!(ImageFormat::detect(b":00000001FF\n"), ImageFormat::Ihex);
+        assert_eq!(ImageFormat::detect(b"S00600004844521B\n"), ImageFormat::Srec);
+        assert_eq!(ImageFormat::detect(&[0x0C, 0x94, 0x34, 0x00]), ImageFormat::Binary);
+        assert_eq!(ImageFormat::detect(&std::fs::read("testprogram.bin").unwrap()), ImageFormat::Elf);
+    }
+
+    #[test]
+    fn load_eeprom() {
+        let mut core = Avrcore::new(HashMap::new());
+        core.load_eeprom(&vec![0x5A; EEPROM_SIZE + 16]);
+        assert_eq!(core.eeprom, vec![0x5A; EEPROM_SIZE]);
+    }
+}
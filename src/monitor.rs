@@ -0,0 +1,574 @@
+use crate::cli;
+use avrsim::avrcore::{self, RAMEND, SREG};
+use avrsim::instructions::Instruction;
+use avrsim::interrupts::Interrupt;
+use avrsim::listing::SymbolMap;
+use avrsim::reset::{CLKPR, MCUSR, WDTCSR};
+use avrsim::selfprog::SPMCSR;
+use avrsim::sleep::SMCR;
+use avrsim::snapshot::Snapshot;
+use avrsim::usart::{UCSR0A, UDR0};
+use avrsim::watch::{WatchHit, WatchKind};
+use avrsim::Simulator;
+use rustyline::completion::Completer;
+use rustyline::error::ReadlineError;
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::history::DefaultHistory;
+use rustyline::validate::Validator;
+use rustyline::{Context, Editor, Helper};
+use std::collections::BTreeSet;
+use std::env;
+use std::io::Write;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+pub const HELP: &str = "\
+step [N]                  Execute N instructions (default: 1)
+continue                  Run until a breakpoint, watchpoint or fault
+until <ADDR|SYMBOL>       Run until execution reaches an address
+reverse-step [N]          Step back over N instructions (default: 1)
+reverse-continue          Run backwards to a breakpoint, watchpoint or the start of
+                          the recorded history
+break [ADDR|SYMBOL]       Set a breakpoint, or list them without an argument
+delete <ADDR|SYMBOL>      Clear a breakpoint
+watch <ADDR|SYMBOL[:LEN]> Stop on writes to a data space range, also rwatch and awatch
+unwatch <ID>              Clear a watchpoint
+regs                      Print the registers
+set <REG> <VALUE>         Modify r0 - r31, pc, sp or sreg
+mem <ADDR|SYMBOL> [LEN]   Dump the data space
+flash <ADDR> [LEN]        Dump program memory
+eeprom <ADDR> [LEN]       Dump EEPROM
+poke <ADDR> <BYTE>...     Write bytes to the data space
+list [ADDR|SYMBOL]        Disassemble around the PC or an address
+save <FILE>               Save the complete machine state
+restore <FILE>            Continue from a saved machine state
+info                      Show clocks, sleep, interrupts and peripheral registers
+help                      Print this help
+quit                      Leave the monitor
+
+Commands can be abbreviated to their first letter where unambiguous (s, c, u,
+b, d, r, x, l, i, q), the reverse commands to rs and rc. An empty line repeats
+the previous command. Ctrl-C stops a running program.
+";
+
+const COMMANDS: [&str; 23] = [
+    "step", "continue", "until", "reverse-step", "reverse-continue", "break", "delete", "watch",
+    "rwatch", "awatch", "unwatch", "regs", "set", "mem", "flash", "eeprom", "poke", "list", "save",
+    "restore", "info", "help", "quit",
+];
+
+// Bytes dumped when no length is given
+const DUMP_LEN: usize = 64;
+
+// Instructions shown before and after the listed address
+const LIST_CONTEXT: usize = 4;
+
+// Whether the REPL keeps reading commands
+#[derive(Debug, PartialEq, Eq)]
+pub enum Flow {
+    Continue,
+    Quit,
+}
+
+// Tab completion of command names, symbols and register names
+struct MonitorHelper {
+    symbols: Vec<String>,
+}
+
+impl Completer for MonitorHelper {
+    type Candidate = String;
+
+    fn complete(&self, line: &str, pos: usize, _ctx: &Context<'_>) -> rustyline::Result<(usize, Vec<String>)> {
+        let start = line[..pos].rfind(' ').map_or(0, |idx| idx + 1);
+        let word = &line[start..pos];
+
+        let candidates: Vec<String> = if start == 0 {
+            COMMANDS.iter().filter(|command| command.starts_with(word)).map(|command| command.to_string()).collect()
+        } else {
+            let registers = ["pc", "sp", "sreg"].iter().map(|name| name.to_string())
+                .chain((0..32).map(|idx| format!("r{}", idx)));
+            self.symbols.iter().cloned()
+                .chain(registers)
+                .filter(|name| name.starts_with(word))
+                .collect()
+        };
+
+        Ok((start, candidates))
+    }
+}
+
+impl Hinter for MonitorHelper {
+    type Hint = String;
+}
+
+impl Highlighter for MonitorHelper {}
+
+impl Validator for MonitorHelper {}
+
+impl Helper for MonitorHelper {}
+
+// Hex and ASCII dump, 16 bytes per line
+fn hex_dump(base: usize, bytes: &[u8]) -> String {
+    bytes.chunks(16).enumerate()
+        .map(|(idx, chunk)| {
+            let hex: Vec<String> = chunk.iter().map(|byte| format!("{:02x}", byte)).collect();
+            let ascii: String = chunk.iter()
+                .map(|&byte| if byte.is_ascii_graphic() || byte == b' ' { byte as char } else { '.' })
+                .collect();
+            format!("{:#06x}:  {:<48} {}\n", base + idx * 16, hex.join(" "), ascii)
+        })
+        .collect()
+}
+
+fn parse_value(value: Option<&str>) -> Result<u64, String> {
+    cli::parse_number(value.ok_or("Missing value")?)
+}
+
+// An interactive debugger driving a simulator
+pub struct Monitor<'a> {
+    pub sim: &'a mut Simulator,
+    pub breakpoints: BTreeSet<u16>,
+    symbols: SymbolMap,
+    uart: &'a mut dyn Write,
+    interrupted: Arc<AtomicBool>, // Set by Ctrl-C while the program runs
+}
+
+impl<'a> Monitor<'a> {
+    pub fn new(sim: &'a mut Simulator, uart: &'a mut dyn Write) -> Monitor<'a> {
+        let symbols = sim.program.elf.as_ref().map(SymbolMap::from_elf).unwrap_or_default();
+
+        Monitor {
+            sim,
+            breakpoints: BTreeSet::new(),
+            symbols,
+            uart,
+            interrupted: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    // Read and run commands until `quit` or end of input
+    pub fn repl(&mut self) -> rustyline::Result<()> {
+        let interrupted = self.interrupted.clone();
+        // Only one handler can be installed per process
+        let _ = ctrlc::set_handler(move || interrupted.store(true, Ordering::SeqCst));
+
+        let mut editor: Editor<MonitorHelper, DefaultHistory> = Editor::new()?;
+        let symbols = self.sim.program.elf.as_ref()
+            .map(|elf| elf.symbols.iter().map(|symbol| symbol.name.clone()).collect())
+            .unwrap_or_default();
+        editor.set_helper(Some(MonitorHelper { symbols }));
+
+        let history = env::var("HOME").ok().map(|home| format!("{}/.avrsim_history", home));
+        if let Some(path) = &history {
+            let _ = editor.load_history(path);
+        }
+
+        self.show_location();
+        let mut previous = String::new();
+
+        loop {
+            let line = match editor.readline("(avrsim) ") {
+                Ok(line) => line,
+                Err(ReadlineError::Interrupted) => continue,
+                Err(ReadlineError::Eof) => break,
+                Err(err) => return Err(err)
+            };
+
+            let line = match line.trim() {
+                "" => previous.clone(),
+                line => {
+                    editor.add_history_entry(line)?;
+                    line.to_string()
+                }
+            };
+
+            match self.command(&line) {
+                Ok(Flow::Quit) => break,
+                Ok(Flow::Continue) => (),
+                Err(message) => println!("{}", message)
+            }
+            self.flush_uart();
+            previous = line;
+        }
+
+        if let Some(path) = &history {
+            let _ = editor.save_history(path);
+        }
+        Ok(())
+    }
+
+    pub fn command(&mut self, line: &str) -> Result<Flow, String> {
+        let mut words = line.split_whitespace();
+        let command = match words.next() {
+            Some(command) => command,
+            None => return Ok(Flow::Continue)
+        };
+        let args: Vec<&str> = words.collect();
+
+        match command {
+            "step" | "s" => {
+                let count = args.first().map_or(Ok(1), |count| cli::parse_number(count))?;
+                self.step(count);
+            },
+            "continue" | "c" => self.run(None),
+            "until" | "u" => {
+                let target = args.first().ok_or("Missing address or symbol")?;
+                let addr = self.sim.resolve_address(target).ok_or(format!("Unknown address or symbol: {}", target))?;
+                self.run(Some(addr));
+            },
+            "reverse-step" | "rs" => {
+                let count = args.first().map_or(Ok(1), |count| cli::parse_number(count))?;
+                self.reverse(Some(count))?;
+            },
+            "reverse-continue" | "rc" => self.reverse(None)?,
+            "break" | "b" => match args.first() {
+                Some(target) => {
+                    let addr = self.sim.resolve_address(target).ok_or(format!("Unknown address or symbol: {}", target))?;
+                    self.breakpoints.insert(addr);
+                    println!("Breakpoint at {}", self.describe(addr));
+                },
+                None => {
+                    for addr in self.breakpoints.iter() {
+                        println!("{}", self.describe(*addr));
+                    }
+                }
+            },
+            "delete" | "d" => {
+                let target = args.first().ok_or("Missing address or symbol")?;
+                let addr = self.sim.resolve_address(target).ok_or(format!("Unknown address or symbol: {}", target))?;
+                if !self.breakpoints.remove(&addr) {
+                    return Err(format!("No breakpoint at {:#x}", addr))
+                }
+            },
+            "watch" | "rwatch" | "awatch" => {
+                let kind = match command {
+                    "watch" => WatchKind::Write,
+                    "rwatch" => WatchKind::Read,
+                    _ => WatchKind::Access
+                };
+                let spec = args.first().ok_or("Missing address or symbol")?;
+                let range = self.sim.resolve_data_range(spec).ok_or(format!("Unknown watch address or symbol: {}", spec))?;
+                println!("Watchpoint {} on {:#06x}..{:#06x}", self.sim.watch(range.clone(), kind), range.start, range.end);
+            },
+            "unwatch" => {
+                let id = parse_value(args.first().copied())?;
+                if !self.sim.unwatch(id as u32) {
+                    return Err(format!("No watchpoint {}", id))
+                }
+            },
+            "regs" | "r" => print!("{}", avrcore::register_dump(&self.sim.core)),
+            "set" => {
+                let value = parse_value(args.get(1).copied())?;
+                self.set_register(args.first().copied().unwrap_or(""), value)?;
+            },
+            "mem" | "x" => {
+                let target = args.first().ok_or("Missing address or symbol")?;
+                let range = self.sim.resolve_data_range(target).ok_or(format!("Unknown address or symbol: {}", target))?;
+                let start = range.start as usize;
+                let len = match args.get(1) {
+                    Some(len) => cli::parse_number(len)? as usize,
+                    None if cli::parse_number(target).is_ok() => DUMP_LEN,
+                    None => range.len()
+                };
+                // Clamped to the data space
+                let end = start.saturating_add(len).min(RAMEND as usize + 1);
+                let bytes: Vec<u8> = (start..end).map(|addr| self.sim.read_data(addr as u16)).collect();
+                print!("{}", hex_dump(start, &bytes));
+            },
+            "flash" | "eeprom" => {
+                let start = parse_value(args.first().copied())? as usize;
+                let len = args.get(1).map_or(Ok(DUMP_LEN as u64), |len| cli::parse_number(len))? as usize;
+                let memory = if command == "flash" { self.sim.flash() } else { self.sim.eeprom() };
+                let end = start.saturating_add(len).min(memory.len());
+                print!("{}", hex_dump(start, &memory[start.min(end)..end]));
+            },
+            "poke" => {
+                let addr = parse_value(args.first().copied())?;
+                if args.len() < 2 {
+                    return Err(String::from("Missing value"))
+                }
+                let values = args[1..].iter().map(|value| cli::parse_number(value)).collect::<Result<Vec<_>, _>>()?;
+                if addr.saturating_add(values.len() as u64) > RAMEND as u64 + 1 {
+                    return Err(format!("Data space ends at {:#06x}", RAMEND))
+                }
+                for (offset, value) in values.into_iter().enumerate() {
+                    self.sim.write_data(addr as u16 + offset as u16, value as u8);
+                }
+            },
+            "list" | "l" => {
+                let addr = match args.first() {
+                    Some(target) => self.sim.resolve_address(target).ok_or(format!("Unknown address or symbol: {}", target))?,
+                    None => self.sim.pc()
+                };
+                self.list(addr);
+            },
+            "save" => {
+                let path = args.first().ok_or("Missing file name")?;
+                self.sim.snapshot().save(path).map_err(|err| format!("Cannot write {}: {}", path, err))?;
+            },
+            "restore" => {
+                let path = args.first().ok_or("Missing file name")?;
+                let snapshot = Snapshot::load(path).map_err(|err| err.to_string())?;
+                self.sim.restore(&snapshot);
+                self.show_location();
+            },
+            "info" | "i" => self.info(),
+            "help" | "h" | "?" => print!("{}", HELP),
+            "quit" | "q" => return Ok(Flow::Quit),
+            _ => return Err(format!("Unknown command: {}, try help", command))
+        }
+
+        Ok(Flow::Continue)
+    }
+
+    // `0x80 <main>`
+    fn describe(&self, addr: u16) -> String {
+        match self.symbols.annotate(addr as u32) {
+            Some(annotation) => format!("{:#x} {}", addr, annotation),
+            None => format!("{:#x}", addr)
+        }
+    }
+
+    fn show_location(&self) {
+        let pc = self.sim.pc();
+        print!("=> {}:\t", self.describe(pc));
+        match self.sim.core.flash.get(&(pc as usize)) {
+            Some(opcode) => opcode.pretty_print(),
+            None => println!("(no instruction)")
+        }
+    }
+
+    fn flush_uart(&mut self) {
+        let output = self.sim.core.take_uart_output();
+        if !output.is_empty() {
+            let _ = self.uart.write_all(&output).and_then(|_| self.uart.flush());
+        }
+    }
+
+    fn report_watch(&self, hit: &WatchHit) {
+        if hit.write {
+            println!("Watchpoint {}: {:#06x} written at {:#x}, {:#04x} -> {:#04x}", hit.id, hit.address, hit.pc, hit.old, hit.new);
+        } else {
+            println!("Watchpoint {}: {:#06x} read at {:#x}, value {:#04x}", hit.id, hit.address, hit.pc, hit.new);
+        }
+    }
+
+    // Execute one instruction, reporting faults and watchpoints. False if execution should stop.
+    fn single_step(&mut self) -> bool {
+        if let Err(err) = self.sim.step() {
+            println!("{}", err);
+            return false
+        }
+
+        let hits = self.sim.take_watch_stops();
+        for hit in hits.iter() {
+            self.report_watch(hit);
+        }
+        self.flush_uart();
+
+        hits.is_empty()
+    }
+
+    fn step(&mut self, count: u64) {
+        self.interrupted.store(false, Ordering::SeqCst);
+
+        for _ in 0..count {
+            if !self.single_step() {
+                break
+            }
+            if self.interrupted.swap(false, Ordering::SeqCst) {
+                println!("Interrupted");
+                break
+            }
+        }
+        self.show_location();
+    }
+
+    // Run until a breakpoint, `target`, a watchpoint, a fault, Ctrl-C or the core sleeping forever
+    fn run(&mut self, target: Option<u16>) {
+        self.interrupted.store(false, Ordering::SeqCst);
+
+        loop {
+            if !self.single_step() {
+                break
+            }
+
+            let pc = self.sim.pc();
+            let awake = self.sim.core.sleep.is_none();
+            if awake && self.breakpoints.contains(&pc) {
+                println!("Breakpoint at {}", self.describe(pc));
+                break
+            }
+            if awake && target == Some(pc) {
+                break
+            }
+            if self.sim.core.sleeping_forever() {
+                println!("Sleeping with no way to wake up");
+                break
+            }
+            if self.interrupted.swap(false, Ordering::SeqCst) {
+                println!("Interrupted");
+                break
+            }
+        }
+
+        self.show_location();
+    }
+
+    // Step back `count` instructions, or until a breakpoint or watchpoint when None
+    fn reverse(&mut self, count: Option<u64>) -> Result<(), String> {
+        if !self.sim.recording() {
+            return Err(String::from("Reverse execution is disabled, see --history"))
+        }
+
+        let mut undone = 0;
+        loop {
+            if !self.sim.step_back() {
+                println!("Reached the start of the recorded history");
+                break
+            }
+            undone += 1;
+
+            let hits = self.sim.take_watch_stops();
+            for hit in hits.iter() {
+                self.report_watch(hit);
+            }
+            if !hits.is_empty() || count == Some(undone) {
+                break
+            }
+
+            let pc = self.sim.pc();
+            if count.is_none() && self.sim.core.sleep.is_none() && self.breakpoints.contains(&pc) {
+                println!("Breakpoint at {}", self.describe(pc));
+                break
+            }
+        }
+
+        self.show_location();
+        Ok(())
+    }
+
+    fn set_register(&mut self, name: &str, value: u64) -> Result<(), String> {
+        match name {
+            "pc" => self.sim.set_pc(value as u16),
+            "sp" => {
+                self.sim.core.sp.SPL = value as u8;
+                self.sim.core.sp.SPH = (value >> 8) as u8;
+            },
+            "sreg" => self.sim.core.sreg = SREG::from_byte(value as u8),
+            _ => {
+                let rd = name.strip_prefix('r')
+                    .and_then(|idx| idx.parse::<usize>().ok())
+                    .filter(|idx| *idx < 32)
+                    .ok_or(format!("Unknown register: {}", name))?;
+                self.sim.set_register(rd, value as u8);
+            }
+        }
+
+        Ok(())
+    }
+
+    // Instructions around `addr`, printed with `Instruction::pretty_print`
+    fn list(&self, addr: u16) {
+        let flash = &self.sim.core.flash;
+        let mut addresses: Vec<usize> = flash.keys().copied().collect();
+        addresses.sort_unstable();
+
+        let idx = addresses.partition_point(|&address| address < addr as usize);
+        let start = idx.saturating_sub(LIST_CONTEXT);
+        let end = (idx + LIST_CONTEXT + 1).min(addresses.len());
+
+        for address in addresses[start..end].iter() {
+            if let Some(label) = self.symbols.label_at(*address as u32) {
+                println!("<{}>:", label);
+            }
+            let marker = if *address == self.sim.pc() as usize { "=>" } else { "  " };
+            print!("{} {:6x}:\t", marker, address);
+            flash[address].pretty_print();
+        }
+    }
+
+    fn info(&self) {
+        let core = &self.sim.core;
+
+        println!("Clock       {} Hz, CLKPR {:#04x}, {} cycles", core.clock_hz(), core.read_data(CLKPR), core.cycles);
+        match core.sleep {
+            Some(state) => println!("Sleep       {:?} since cycle {}, SMCR {:#04x}", state.mode, state.since, core.io[SMCR]),
+            None => println!("Sleep       awake, SMCR {:#04x}", core.io[SMCR])
+        }
+
+        let pending: Vec<&str> = (0..32u8)
+            .filter(|vector| core.pending_interrupts & (1 << vector) != 0)
+            .filter_map(Interrupt::from_vector)
+            .map(|irq| irq.name())
+            .collect();
+        println!("Interrupts  {}, pending: {}", if core.sreg.I { "enabled" } else { "disabled" },
+                 if pending.is_empty() { String::from("none") } else { pending.join(" ") });
+        for (at, irq) in core.scheduled.iter() {
+            println!("            {} scheduled at cycle {}", irq.name(), at);
+        }
+
+        println!("USART0      UCSR0A {:#04x}, UDR0 {:#04x}, {} bytes unread", core.read_data(UCSR0A), core.read_data(UDR0), core.uart_tx.len());
+        match core.spm.busy_until {
+            Some(until) => println!("SPM         SPMCSR {:#04x}, busy until cycle {}", core.io[SPMCSR], until),
+            None => println!("SPM         SPMCSR {:#04x}", core.io[SPMCSR])
+        }
+        println!("Reset       MCUSR {:#04x}, WDTCSR {:#04x}", core.io[MCUSR], core.read_data(WDTCSR));
+        println!("Fuses       low {:#04x}, high {:#04x}, extended {:#04x}, lock {:#04x}",
+                 core.fuses.low, core.fuses.high, core.fuses.extended, core.fuses.lock);
+        for watch in core.watchpoints.iter() {
+            println!("Watchpoint  {} {:?} {:#06x}..{:#06x}", watch.id, watch.kind, watch.range.start, watch.range.end);
+        }
+    }
+}
+
+// Tests
+#[cfg(test)]
+mod tests {
+    use crate::monitor::{hex_dump, Flow, Monitor};
+    use avrsim::watch::WatchKind;
+    use avrsim::Simulator;
+    use std::io;
+    use std::sync::atomic::Ordering;
+
+    #[test]
+    fn commands() {
+        let mut sim = Simulator::builder().image_file("testprogram.bin").build().unwrap();
+        let main = sim.symbol("main").unwrap() as u16;
+        let mut uart = io::sink();
+        let mut monitor = Monitor::new(&mut sim, &mut uart);
+
+        assert_eq!(monitor.command("break main"), Ok(Flow::Continue));
+        monitor.command("continue").unwrap();
+        assert_eq!(monitor.sim.pc(), main);
+
+        monitor.command("set r24 0x2a").unwrap();
+        monitor.command("poke 0x100 1 2").unwrap();
+        assert_eq!(monitor.sim.register(24), 0x2a);
+        assert_eq!(monitor.sim.read_data(0x101), 2);
+        assert!(monitor.command("poke 0xffff 1 2").is_err());
+        assert!(monitor.command("poke 0x8ff 1 2").is_err());
+        assert_eq!(monitor.command("mem 0x8f0 0x100"), Ok(Flow::Continue));
+
+        monitor.command("step 2").unwrap();
+        assert_eq!(monitor.sim.pc(), main + 4);
+        assert!(monitor.command("rs").is_err());
+        assert_eq!(monitor.command("flash 0x10 0xFFFFFFFFFFFFFFFF"), Ok(Flow::Continue));
+
+        // Ctrl-C during `step N` stops after the current instruction, the rcall pushing its return address
+        let interrupted = monitor.interrupted.clone();
+        monitor.sim.watch_with(0x0800..0x0900, WatchKind::Write, move |_| {
+            interrupted.store(true, Ordering::SeqCst);
+            false
+        });
+        monitor.command("step 100").unwrap();
+        assert_eq!(monitor.sim.pc(), main + 6);
+
+        assert!(monitor.command("set r32 1").is_err());
+        assert!(monitor.command("frobnicate").is_err());
+        assert_eq!(monitor.command("quit"), Ok(Flow::Quit));
+
+        assert_eq!(hex_dump(0x100, b"AB\x00"), "0x0100:  41 42 00                                         AB.\n");
+    }
+}
@@ -0,0 +1,329 @@
+use crate::avrcore::{Avrcore, INTERRUPT_RESPONSE_CYCLES};
+use crate::callstack::{CallStack, CallState};
+use crate::listing::SymbolMap;
+use std::collections::HashMap;
+
+// Name of the pseudo function charged while the core sleeps
+const SLEEP: &str = "<sleep>";
+
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct FunctionStats {
+    pub calls: u64,
+    pub inclusive: u64, // Cycles in the function and everything it called
+    pub exclusive: u64, // Cycles in the function's own instructions
+}
+
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct EdgeStats {
+    pub calls: u64,
+    pub cycles: u64, // Inclusive cycles of the callee when called from this caller
+}
+
+// State before one profiled step
+pub struct ProfileState {
+    cycles: u64,
+    calls: Option<CallState>, // None while asleep
+}
+
+// Attributes cycles to functions by following the call stack. Interrupts get
+// a frame named after the vector, with the handler below it.
+pub struct Profiler {
+    symbols: SymbolMap,
+    names: Vec<String>,
+    ids: HashMap<String, usize>,
+    functions: Vec<FunctionStats>,
+    edges: HashMap<(usize, usize), EdgeStats>,
+    stacks: HashMap<Vec<usize>, u64>, // Exclusive cycles per complete stack
+    calls: CallStack,
+    active: Vec<usize>, // Function holding the code of each frame, jumps move it
+    total: u64,
+}
+
+impl Profiler {
+    // Start profiling in the function the core is currently executing
+    pub fn new(symbols: SymbolMap, core: &Avrcore) -> Profiler {
+        let mut profiler = Profiler {
+            symbols,
+            names: Vec::new(),
+            ids: HashMap::new(),
+            functions: Vec::new(),
+            edges: HashMap::new(),
+            stacks: HashMap::new(),
+            calls: CallStack::new(core),
+            active: Vec::new(),
+            total: 0,
+        };
+
+        let entry = core.pc as u32;
+        let function = profiler.function_at(entry, entry);
+        profiler.active.push(function);
+        profiler
+    }
+
+    fn intern(&mut self, name: &str) -> usize {
+        if let Some(id) = self.ids.get(name) {
+            return *id
+        }
+
+        self.names.push(name.to_string());
+        self.functions.push(FunctionStats::default());
+        self.ids.insert(name.to_string(), self.names.len() - 1);
+        self.names.len() - 1
+    }
+
+    // The symbol containing `pc`, or the frame's entry address without symbols
+    fn function_at(&mut self, pc: u32, entry: u32) -> usize {
+        let name = match self.symbols.lookup(pc) {
+            Some((name, _)) => name.to_string(),
+            None => format!("{:#x}", entry)
+        };
+        self.intern(&name)
+    }
+
+    fn count_call(&mut self, caller: Option<usize>, callee: usize) {
+        self.functions[callee].calls += 1;
+
+        if let Some(caller) = caller {
+            self.edges.entry((caller, callee)).or_default().calls += 1;
+        }
+    }
+
+    // Functions on the stack from the bottom up, interrupt frames starting
+    // with the vector
+    fn path(&mut self) -> Vec<usize> {
+        let mut path = Vec::new();
+
+        for (idx, frame) in self.calls.frames().to_vec().iter().enumerate() {
+            if let Some(irq) = frame.interrupt {
+                path.push(self.intern(irq.name()));
+            }
+            if let Some(function) = self.active.get(idx) {
+                path.push(*function);
+            }
+        }
+
+        path
+    }
+
+    // Add cycles to the top of `path` and, once per function, to everything below
+    fn charge(&mut self, path: Vec<usize>, cycles: u64) {
+        self.total += cycles;
+
+        if let Some(top) = path.last() {
+            self.functions[*top].exclusive += cycles;
+        }
+        for (idx, function) in path.iter().enumerate() {
+            if !path[..idx].contains(function) {
+                self.functions[*function].inclusive += cycles;
+            }
+        }
+        for (idx, pair) in path.windows(2).enumerate() {
+            if !path.windows(2).take(idx).any(|earlier| earlier == pair) {
+                self.edges.entry((pair[0], pair[1])).or_default().cycles += cycles;
+            }
+        }
+
+        match self.stacks.get_mut(&path[..]) {
+            Some(total) => *total += cycles,
+            None => {
+                self.stacks.insert(path, cycles);
+            }
+        }
+    }
+
+    pub fn before(&self, core: &Avrcore) -> ProfileState {
+        ProfileState { cycles: core.cycles, calls: CallState::capture(core) }
+    }
+
+    // Account for the step executed since `before`
+    pub fn after(&mut self, state: ProfileState, core: &Avrcore) {
+        let mut cycles = core.cycles - state.cycles;
+
+        let calls = match state.calls {
+            Some(calls) => calls,
+            None => {
+                let mut path = self.path();
+                path.push(self.intern(SLEEP));
+                self.charge(path, cycles);
+                return
+            }
+        };
+
+        if let Some(irq) = calls.interrupt {
+            let caller = self.path().last().copied();
+            let vector = self.intern(irq.name());
+            self.calls.interrupt(&calls);
+            self.count_call(caller, vector);
+            let path = self.path();
+            self.charge(path, INTERRUPT_RESPONSE_CYCLES.min(cycles));
+            cycles = cycles.saturating_sub(INTERRUPT_RESPONSE_CYCLES);
+
+            let entry = irq.address() as u32;
+            let handler = self.function_at(entry, entry);
+            self.count_call(Some(vector), handler);
+            self.active.push(handler);
+        }
+
+        // Jumps move the top frame into whichever function holds the code
+        if let Some(frame) = self.calls.top().copied() {
+            let function = self.function_at(calls.pc as u32, frame.function);
+            if let Some(top) = self.active.last_mut() {
+                *top = function;
+            }
+        }
+        let path = self.path();
+        self.charge(path, cycles);
+
+        let caller = self.active.last().copied();
+        self.calls.transfer(&calls, core);
+        if self.calls.frames().len() > self.active.len() {
+            let entry = core.pc as u32;
+            let callee = self.function_at(entry, entry);
+            self.count_call(caller, callee);
+            self.active.push(callee);
+        }
+        self.active.truncate(self.calls.frames().len());
+    }
+
+    pub fn total_cycles(&self) -> u64 {
+        self.total
+    }
+
+    pub fn function(&self, name: &str) -> Option<FunctionStats> {
+        self.ids.get(name).map(|id| self.functions[*id])
+    }
+
+    pub fn edge(&self, caller: &str, callee: &str) -> Option<EdgeStats> {
+        let key = (*self.ids.get(caller)?, *self.ids.get(callee)?);
+        self.edges.get(&key).copied()
+    }
+
+    // Function ids by decreasing `key`, ties broken by name
+    fn sorted_by<F: Fn(&FunctionStats) -> u64>(&self, key: F) -> Vec<usize> {
+        let mut ids: Vec<usize> = (0..self.names.len()).collect();
+        ids.sort_by(|a, b| key(&self.functions[*b]).cmp(&key(&self.functions[*a])).then(self.names[*a].cmp(&self.names[*b])));
+        ids
+    }
+
+    // gprof style flat profile, hottest function first
+    pub fn flat(&self) -> String {
+        let mut report = format!("Flat profile, {} cycles\n\n", self.total);
+        report += &format!("{:>8} {:>12} {:>12} {:>8}  function\n", "%cycles", "exclusive", "inclusive", "calls");
+
+        for id in self.sorted_by(|stats| stats.exclusive) {
+            let stats = &self.functions[id];
+            let share = if self.total == 0 { 0.0 } else { stats.exclusive as f64 * 100.0 / self.total as f64 };
+            report += &format!("{:>8.2} {:>12} {:>12} {:>8}  {}\n", share, stats.exclusive, stats.inclusive, stats.calls, self.names[id]);
+        }
+
+        report
+    }
+
+    // Every function with its callers and callees, by decreasing inclusive cycles
+    pub fn call_graph(&self) -> String {
+        let mut report = String::from("Call graph\n");
+
+        for id in self.sorted_by(|stats| stats.inclusive) {
+            let stats = &self.functions[id];
+            report += &format!("\n{}: {} inclusive, {} exclusive, {} calls\n", self.names[id], stats.inclusive, stats.exclusive, stats.calls);
+
+            let mut callers: Vec<_> = self.edges.iter().filter(|((_, callee), _)| *callee == id).collect();
+            callers.sort_by_key(|((caller, _), _)| &self.names[*caller]);
+            for ((caller, _), edge) in callers {
+                report += &format!("    <- {:<32} {:>8} calls\n", self.names[*caller], edge.calls);
+            }
+
+            let mut callees: Vec<_> = self.edges.iter().filter(|((caller, _), _)| *caller == id).collect();
+            callees.sort_by(|((_, a), x), ((_, b), y)| y.cycles.cmp(&x.cycles).then(self.names[*a].cmp(&self.names[*b])));
+            for ((_, callee), edge) in callees {
+                report += &format!("    -> {:<32} {:>8} calls {:>12} cycles\n", self.names[*callee], edge.calls, edge.cycles);
+            }
+        }
+
+        report
+    }
+
+    // One `caller;callee count` line per stack, the input of flamegraph.pl and inferno
+    pub fn folded(&self) -> String {
+        let mut lines: Vec<String> = self.stacks.iter()
+            .filter(|(_, cycles)| **cycles > 0)
+            .map(|(path, cycles)| {
+                let names: Vec<&str> = path.iter().map(|id| self.names[*id].as_str()).collect();
+                format!("{} {}\n", names.join(";"), cycles)
+            })
+            .collect();
+
+        lines.sort();
+        lines.concat()
+    }
+}
+
+// Tests
+#[cfg(test)]
+mod tests {
+    use crate::avrcore::Avrcore;
+    use crate::interrupts::Interrupt;
+    use crate::listing::SymbolMap;
+    use crate::profile::Profiler;
+
+    #[test]
+    fn calls_and_interrupts() {
+        let mut progmem = vec![0; 0x34];
+        // jmp main; jmp isr
+        progmem[..8].copy_from_slice(&[0x0C, 0x94, 0x10, 0x00, 0x0C, 0x94, 0x18, 0x00]);
+        // main: sei; call work
+        progmem[0x20..0x26].copy_from_slice(&[0x78, 0x94, 0x0E, 0x94, 0x14, 0x00]);
+        // work: ldi r16, 1; jmp work
+        progmem[0x28..0x2E].copy_from_slice(&[0x01, 0xE0, 0x0C, 0x94, 0x14, 0x00]);
+        // isr: ldi r17, 2; reti
+        progmem[0x30..0x34].copy_from_slice(&[0x12, 0xE0, 0x18, 0x95]);
+
+        let mut core = Avrcore::from_bytes(&progmem);
+        let symbols = SymbolMap::parse_nm("00000000 T __vectors\n00000020 T main\n00000028 T work\n00000030 T isr\n");
+        let mut profiler = Profiler::new(symbols, &core);
+
+        for step in 0..12 {
+            if step == 6 {
+                core.raise_interrupt(Interrupt::Int0);
+            }
+            let state = profiler.before(&core);
+            core.execute().unwrap();
+            profiler.after(state, &core);
+        }
+
+        assert_eq!(profiler.total_cycles(), core.cycles);
+        let work = profiler.function("work").unwrap();
+        assert_eq!((work.calls, work.inclusive), (1, core.cycles - 3 - 1 - 4));
+        assert_eq!(profiler.function("INT0").unwrap().calls, 1);
+        assert_eq!(profiler.edge("work", "INT0").unwrap().calls, 1);
+        assert_eq!(profiler.function("isr").unwrap().exclusive, 1 + 4);
+
+        // jmp at reset (3), sei (1), call (4), vector jmp (3) after the response (4)
+        let folded = profiler.folded();
+        assert!(folded.starts_with("__vectors 3\nmain 5\nmain;work "));
+        assert!(folded.ends_with("main;work;INT0 4\nmain;work;INT0;__vectors 3\nmain;work;INT0;isr 5\n"));
+        assert!(profiler.flat().contains("work\n"));
+        assert!(profiler.call_graph().contains("    -> INT0"));
+    }
+
+    #[test]
+    fn rcall_and_ret() {
+        // main: rcall f; rcall f; rjmp .-2; f: ldi r16, 1; ret
+        let progmem = [0x02, 0xD0, 0x01, 0xD0, 0xFF, 0xCF, 0x01, 0xE0, 0x08, 0x95];
+        let mut core = Avrcore::from_bytes(&progmem);
+        let mut profiler = Profiler::new(SymbolMap::parse_nm("00000000 T main\n00000006 T f\n"), &core);
+
+        for _ in 0..7 {
+            let state = profiler.before(&core);
+            core.execute().unwrap();
+            profiler.after(state, &core);
+        }
+
+        // rcall (3), ldi (1) and ret (4) twice, then rjmp (2) back in main
+        let f = profiler.function("f").unwrap();
+        assert_eq!((f.calls, f.inclusive, f.exclusive), (2, 10, 10));
+        assert_eq!(profiler.edge("main", "f").unwrap().calls, 2);
+        assert_eq!(profiler.function("main").unwrap().exclusive, 3 + 3 + 2);
+        assert_eq!(profiler.folded(), "main 8\nmain;f 10\n");
+    }
+}
@@ -0,0 +1,141 @@
+use crate::avrcore::{Avrcore, SREG, RAMEND};
+use crate::selfprog::SelfProgramming;
+
+// MCU Status Register (I/O address)
+pub const MCUSR: usize = 0x34;
+pub const MCUSR_PORF: u8 = 0;
+pub const MCUSR_EXTRF: u8 = 1;
+pub const MCUSR_BORF: u8 = 2;
+pub const MCUSR_WDRF: u8 = 3;
+
+// Watchdog Timer Control Register (memory mapped)
+pub const WDTCSR: u16 = 0x60;
+const WDTCSR_WDE: u8 = 3;
+
+// Clock Prescale Register (memory mapped)
+pub const CLKPR: u16 = 0x61;
+
+// Memory mapped registers that do not reset to zero
+const RESET_VALUES: [(u16, u8); 5] = [
+    (0xB9, 0xF8), // TWSR
+    (0xBA, 0xFE), // TWAR
+    (0xBB, 0xFF), // TWDR
+    (0xC0, 0x20), // UCSR0A, UDRE0 set
+    (0xC2, 0x06), // UCSR0C, 8N1
+];
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ResetCause {
+    PowerOn,
+    External,
+    BrownOut,
+    Watchdog,
+}
+
+impl ResetCause {
+    fn mcusr_flag(&self) -> u8 {
+        match self {
+            ResetCause::PowerOn => MCUSR_PORF,
+            ResetCause::External => MCUSR_EXTRF,
+            ResetCause::BrownOut => MCUSR_BORF,
+            ResetCause::Watchdog => MCUSR_WDRF,
+        }
+    }
+}
+
+impl Avrcore {
+    // Bring the core to its datasheet reset state. The register file and SRAM
+    // keep their contents unless power was lost.
+    pub fn reset(&mut self, cause: ResetCause) {
+        let mcusr = self.io[MCUSR];
+
+        if cause == ResetCause::PowerOn {
+            self.general = [0; 32];
+            self.sram = [0; 2048];
+            self.cycles = 0;
+        }
+
+        self.io = [0; 64];
+        self.extio = [0; 160];
+        for &(addr, value) in RESET_VALUES.iter() {
+            self.write_data(addr, value);
+        }
+
+        // CKDIV8 selects a system clock prescaler of 8
+        if self.fuses.ckdiv8() {
+            self.write_data(CLKPR, 0x03);
+        }
+
+        self.sreg = SREG::default();
+        self.sp.SPH = (RAMEND >> 8) as u8;
+        self.sp.SPL = (RAMEND & 0xFF) as u8;
+        self.pc = self.fuses.reset_vector();
+
+        // A power-on reset clears the other flags, the rest accumulate until software clears them
+        self.io[MCUSR] = if cause == ResetCause::PowerOn {
+            1 << MCUSR_PORF
+        } else {
+            (mcusr & 0x0F) | 1 << cause.mcusr_flag()
+        };
+
+        // WDE is forced on while WDRF is set or the WDTON fuse is programmed
+        if self.io[MCUSR] & (1 << MCUSR_WDRF) != 0 || self.fuses.wdton() {
+            self.write_data(WDTCSR, 1 << WDTCSR_WDE);
+        }
+
+        self.spm = SelfProgramming::default();
+        self.pending_interrupts = 0;
+        self.interrupt_inhibit = false;
+        self.scheduled.clear();
+        self.sleep = None;
+    }
+
+    // Pull the RESET pin low
+    pub fn external_reset(&mut self) {
+        self.reset(ResetCause::External)
+    }
+}
+
+// Tests
+#[cfg(test)]
+mod tests {
+    use crate::avrcore::Avrcore;
+    use crate::reset::{ResetCause, MCUSR, CLKPR};
+    use std::collections::HashMap;
+
+    #[test]
+    fn reset_state() {
+        let mut core = Avrcore::new(HashMap::new());
+
+        assert_eq!(core.sp.current_addr(), 0x08FF);
+        assert_eq!(core.pc, 0);
+        assert_eq!(core.io[MCUSR], 0b0001);
+
+        core.sram[0] = 0xAA;
+        core.pc = 0x100;
+        core.external_reset();
+        assert_eq!(core.io[MCUSR], 0b0011);
+        assert_eq!(core.sram[0], 0xAA);
+        assert_eq!(core.pc, 0);
+
+        core.reset(ResetCause::Watchdog);
+        assert_eq!(core.io[MCUSR], 0b1011);
+        assert_eq!(core.read_data(0x60), 0x08);
+
+        core.reset(ResetCause::PowerOn);
+        assert_eq!(core.io[MCUSR], 0b0001);
+        assert_eq!(core.sram[0], 0x00);
+    }
+
+    #[test]
+    fn bootrst() {
+        let mut core = Avrcore::new(HashMap::new());
+        assert_eq!(core.read_data(CLKPR), 0x03);
+
+        core.fuses.set_from_flag("hfuse=0xDE").unwrap();
+        core.fuses.set_from_flag("lfuse=0xFF").unwrap();
+        core.reset(ResetCause::PowerOn);
+        assert_eq!(core.pc, 0x7E00);
+        assert_eq!(core.read_data(CLKPR), 0x00);
+    }
+}
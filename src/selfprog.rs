@@ -0,0 +1,396 @@
+use crate::avrcore::Avrcore;
+use crate::disassembler;
+use crate::fuses::FLASH_WORDS;
+use crate::instructions::{Opcodes, WORDInstruction};
+use crate::interrupts::Interrupt;
+
+// Store Program Memory Control and Status Register (I/O address)
+pub const SPMCSR: usize = 0x37;
+pub const SPMCSR_SPMEN: u8 = 0;
+pub const SPMCSR_PGERS: u8 = 1;
+pub const SPMCSR_PGWRT: u8 = 2;
+pub const SPMCSR_BLBSET: u8 = 3;
+pub const SPMCSR_RWWSRE: u8 = 4;
+pub const SPMCSR_SIGRD: u8 = 5;
+pub const SPMCSR_RWWSB: u8 = 6;
+pub const SPMCSR_SPMIE: u8 = 7;
+
+// Flash page size in bytes (64 words)
+pub const PAGE_SIZE: usize = 128;
+
+// Byte address of the No-Read-While-Write section, fixed regardless of BOOTSZ
+pub const NRWW_START: u32 = 0x7000;
+
+// SPMEN is cleared by hardware if SPM does not follow within four cycles
+const SPM_ARM_CYCLES: u64 = 4;
+
+// Typical page erase and page write time
+const PAGE_PROGRAMMING_MS: u64 = 4;
+
+// Device signature and oscillator calibration byte, read with SIGRD
+const SIGNATURE_ROW: [u8; 6] = [0x1E, 0x66, 0x95, 0x00, 0x0F, 0x00];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SelfProgramming {
+    pub buffer: [u8; PAGE_SIZE], // Temporary page buffer
+    pub armed_at: u64, // Cycle SPMCSR was last written with SPMEN set
+    pub busy_until: Option<u64>, // Completion of an ongoing RWW erase or write
+}
+
+impl Default for SelfProgramming {
+    fn default() -> SelfProgramming {
+        SelfProgramming {
+            buffer: [0xFF; PAGE_SIZE],
+            armed_at: 0,
+            busy_until: None,
+        }
+    }
+}
+
+fn bit(value: u8, bit: u8) -> bool {
+    value & (1 << bit) != 0
+}
+
+impl Avrcore {
+    // Store a software write to SPMCSR. RWWSB is read-only.
+    pub fn write_spmcsr(&mut self, value: u8) {
+        let rwwsb = self.io[SPMCSR] & (1 << SPMCSR_RWWSB);
+        self.io[SPMCSR] = (value & !(1 << SPMCSR_RWWSB)) | rwwsb;
+
+        if bit(value, SPMCSR_SPMEN) {
+            self.spm.armed_at = self.cycles;
+        }
+    }
+
+    fn spm_armed(&self) -> bool {
+        bit(self.io[SPMCSR], SPMCSR_SPMEN) && self.cycles.saturating_sub(self.spm.armed_at) <= SPM_ARM_CYCLES
+    }
+
+    fn clear_spm_command(&mut self) {
+        self.io[SPMCSR] &= !0b0011_1111;
+    }
+
+
+    fn page_programming_cycles(&self) -> u64 {
+        self.clock_hz() as u64 * PAGE_PROGRAMMING_MS / 1000
+    }
+
+    pub fn rww_busy(&self) -> bool {
+        bit(self.io[SPMCSR], SPMCSR_RWWSB)
+    }
+
+    // Execute SPM with the operation selected in SPMCSR
+    pub fn store_program_memory(&mut self) {
+        // SPM is only executable from the boot loader section
+        if !self.fuses.in_boot_section(self.pc as u32) || !self.spm_armed() || self.spm.busy_until.is_some() {
+            self.clear_spm_command();
+            return
+        }
+
+        let spmcsr = self.io[SPMCSR];
+        // Z15 is ignored, flash is only 32 KiB
+        let z = self.z_pointer() as usize & (FLASH_WORDS as usize * 2 - 1);
+        let page = z & !(PAGE_SIZE - 1);
+
+        if bit(spmcsr, SPMCSR_PGERS) {
+            if self.fuses.spm_allowed(page as u32) {
+                self.journal_page(page);
+                self.progmem[page..page + PAGE_SIZE].iter_mut().for_each(|byte| *byte = 0xFF);
+                self.start_page_operation(page);
+            } else {
+                self.clear_spm_command();
+            }
+        } else if bit(spmcsr, SPMCSR_PGWRT) {
+            if self.fuses.spm_allowed(page as u32) {
+                // Programming can only clear bits, erase sets them
+                self.journal_page(page);
+                for (offset, byte) in self.spm.buffer.iter().enumerate() {
+                    self.progmem[page + offset] &= byte;
+                }
+                self.spm.buffer = [0xFF; PAGE_SIZE];
+                self.start_page_operation(page);
+            } else {
+                self.clear_spm_command();
+            }
+        } else if bit(spmcsr, SPMCSR_BLBSET) {
+            // Only BLB0 and BLB1 can be programmed, and never unprogrammed
+            self.fuses.lock &= self.general[0] | 0b1100_0011;
+            self.clear_spm_command();
+        } else if bit(spmcsr, SPMCSR_RWWSRE) {
+            self.io[SPMCSR] &= !(1 << SPMCSR_RWWSB);
+            self.spm.buffer = [0xFF; PAGE_SIZE];
+            self.clear_spm_command();
+        } else if bit(spmcsr, SPMCSR_SIGRD) {
+            // Reads go through LPM, SPM itself does nothing
+            self.clear_spm_command();
+        } else {
+            // Fill the temporary page buffer with R1:R0
+            let offset = z & (PAGE_SIZE - 2);
+            self.spm.buffer[offset] = self.general[0];
+            self.spm.buffer[offset + 1] = self.general[1];
+            self.clear_spm_command();
+        }
+    }
+
+    fn start_page_operation(&mut self, page: usize) {
+        self.redecode_page(page);
+
+        let duration = self.page_programming_cycles();
+
+        if (page as u32) < NRWW_START {
+            // The CPU keeps running from the NRWW section
+            self.io[SPMCSR] |= 1 << SPMCSR_RWWSB;
+            self.spm.busy_until = Some(self.cycles + duration);
+
+            if bit(self.io[SPMCSR], SPMCSR_SPMIE) {
+                self.schedule_interrupt(self.cycles + duration, Interrupt::SpmReady);
+            }
+        } else {
+            // The CPU is halted while the NRWW section is written
+            self.cycles += duration;
+            self.clear_spm_command();
+
+            if bit(self.io[SPMCSR], SPMCSR_SPMIE) {
+                self.raise_interrupt(Interrupt::SpmReady);
+            }
+        }
+    }
+
+    // Called after every instruction to complete an ongoing RWW operation
+    pub fn update_self_programming(&mut self) {
+        if let Some(until) = self.spm.busy_until {
+            if self.cycles >= until {
+                self.spm.busy_until = None;
+                self.clear_spm_command();
+            }
+        } else if bit(self.io[SPMCSR], SPMCSR_SPMEN) && !self.spm_armed() {
+            // No SPM or LPM followed within the four cycles
+            self.clear_spm_command();
+        }
+    }
+
+    // Byte LPM returns while SIGRD or BLBSET is armed, None for a regular flash read
+    pub fn spm_readback(&mut self, z: u16) -> Option<u8> {
+        if !self.spm_armed() {
+            return None
+        }
+
+        let spmcsr = self.io[SPMCSR];
+        let value = if bit(spmcsr, SPMCSR_SIGRD) {
+            SIGNATURE_ROW.get(z as usize).copied().unwrap_or(0)
+        } else if bit(spmcsr, SPMCSR_BLBSET) {
+            match z {
+                0x0000 => self.fuses.low,
+                0x0001 => self.fuses.lock,
+                0x0002 => self.fuses.extended,
+                0x0003 => self.fuses.high,
+                _ => 0
+            }
+        } else {
+            return None
+        };
+
+        self.clear_spm_command();
+        Some(value)
+    }
+
+    fn redecode_page(&mut self, page: usize) {
+        self.redecode(page, page + PAGE_SIZE);
+    }
+
+    // Refresh the decoded instructions for `progmem[start..end]` after it has been
+    // modified. The word before is included since it may start a two word instruction.
+    pub fn redecode(&mut self, start: usize, end: usize) {
+        let end = end.min(self.progmem.len());
+
+        for addr in (start.saturating_sub(2) & !1..end).step_by(2) {
+            let opcode = disassembler::decode_at(&self.progmem, addr).unwrap_or_else(|| {
+                let word = (self.progmem[addr + 1] as u16) << 8 | self.progmem[addr] as u16;
+                Opcodes::WORD(WORDInstruction { word })
+            });
+            self.flash.insert(addr, opcode);
+        }
+    }
+
+    // Read a byte of program memory for LPM/ELPM. SIGRD and BLBSET redirect the
+    // read to the signature row and the fuse and lock bytes. Reads blocked by boot
+    // lock bits or of the RWW section during an erase or write return 0xFF.
+    pub fn load_program_memory(&mut self, addr: u32) -> u8 {
+        if let Some(value) = self.spm_readback(addr as u16) {
+            return value
+        }
+
+        if !self.fuses.lpm_allowed(self.pc as u32, addr) {
+            return 0xFF
+        }
+
+        if self.rww_busy() && addr < NRWW_START {
+            return 0xFF
+        }
+
+        self.progmem.get(addr as usize).copied().unwrap_or(0xFF)
+    }
+
+    // Copy a raw image into program memory. Bytes beyond the end of flash are
+    // dropped and the decoded instructions are left untouched.
+    pub fn load_progmem(&mut self, base: usize, bytes: &[u8]) {
+        let end = base.saturating_add(bytes.len()).min(FLASH_WORDS as usize * 2);
+        if base < end {
+            self.progmem[base..end].copy_from_slice(&bytes[..end - base]);
+        }
+    }
+
+    // Patch program memory, e.g. from a debugger, and decode the changed words again
+    pub fn write_progmem(&mut self, base: usize, bytes: &[u8]) {
+        self.load_progmem(base, bytes);
+        self.redecode(base, base + bytes.len());
+    }
+}
+
+// Tests
+#[cfg(test)]
+mod tests {
+    use crate::avrcore::{Avrcore, RAMPZ};
+    use crate::disassembler;
+    use crate::instructions::Instruction;
+    use crate::selfprog::{SPMCSR, PAGE_SIZE};
+    use crate::reset::ResetCause;
+    use std::collections::HashMap;
+
+    fn spm(core: &mut Avrcore, spmcsr: u8, z: u16) {
+        core.general[30] = (z & 0xFF) as u8;
+        core.general[31] = (z >> 8) as u8;
+        core.write_spmcsr(spmcsr);
+        core.store_program_memory();
+    }
+
+    #[test]
+    fn erase_fill_write() {
+        let mut core = Avrcore::new(HashMap::new());
+        core.fuses.set_from_flag("hfuse=0xDE").unwrap();
+        core.reset(ResetCause::PowerOn);
+        core.load_progmem(0x0100, &[0x00, 0x00]);
+
+        // Running from the boot section
+        core.pc = 0x7E00;
+        spm(&mut core, 0x03, 0x0100);
+        assert_eq!(core.progmem[0x0100], 0xFF);
+        assert!(core.rww_busy());
+
+        // Wait for the erase to finish
+        core.cycles = core.spm.busy_until.unwrap();
+        core.update_self_programming();
+        assert_eq!(core.io[SPMCSR] & 0x01, 0);
+
+        // LDI r16, 0xAB
+        core.general[0] = 0x0B;
+        core.general[1] = 0xEA;
+        spm(&mut core, 0x01, 0x0102);
+        assert_eq!(core.spm.buffer[2], 0x0B);
+
+        spm(&mut core, 0x05, 0x0100);
+        assert_eq!(&core.progmem[0x0100..0x0104], &[0xFF, 0xFF, 0x0B, 0xEA]);
+        assert!(core.flash.contains_key(&0x0102));
+        assert_eq!(core.spm.buffer, [0xFF; PAGE_SIZE]);
+
+        // RWWSB stays set until re-enabled
+        core.cycles = core.spm.busy_until.unwrap();
+        core.update_self_programming();
+        assert!(core.rww_busy());
+        spm(&mut core, 0x11, 0);
+        assert!(!core.rww_busy());
+    }
+
+    #[test]
+    fn z_wraps_to_flash() {
+        let mut core = Avrcore::new(HashMap::new());
+        core.load_progmem(0x0100, &[0x00, 0x00]);
+        core.pc = 0x7E00;
+
+        // ldi r31, 0x81; spm erases the page at 0x0100
+        spm(&mut core, 0x03, 0x8100);
+        assert_eq!(core.progmem[0x0100], 0xFF);
+        assert_eq!(core.progmem.len(), 0x8000);
+    }
+
+    #[test]
+    fn application_cannot_spm() {
+        let mut core = Avrcore::new(HashMap::new());
+        core.load_progmem(0x0000, &[0x0B, 0xEA]);
+
+        core.pc = 0x0200;
+        spm(&mut core, 0x03, 0x0000);
+        assert_eq!(core.progmem[0x0000], 0x0B);
+    }
+
+    #[test]
+    fn spmen_expires() {
+        let mut core = Avrcore::new(HashMap::new());
+        core.pc = 0x7E00;
+
+        core.write_spmcsr(0x01);
+        core.cycles += 4;
+        core.update_self_programming();
+        assert_eq!(core.io[SPMCSR], 0x01);
+
+        core.cycles += 1;
+        core.update_self_programming();
+        assert_eq!(core.io[SPMCSR], 0x00);
+
+        // Writes past the end of flash are dropped
+        core.load_progmem(0x8000, &[0x00, 0x00]);
+        core.write_progmem(0x7FFE, &[0x0B, 0xEA, 0x0B, 0xEA]);
+        assert_eq!(core.progmem.len(), 0x8000);
+        assert_eq!(&core.progmem[0x7FFE..], &[0x0B, 0xEA]);
+        assert!(core.flash.contains_key(&0x7FFE));
+    }
+
+    #[test]
+    fn signature_and_fuses() {
+        let mut core = Avrcore::new(HashMap::new());
+        core.pc = 0x7000;
+
+        core.write_spmcsr(0x21);
+        assert_eq!(core.spm_readback(0x0004), Some(0x0F));
+        assert_eq!(core.spm_readback(0x0004), None);
+
+        core.write_spmcsr(0x09);
+        assert_eq!(core.spm_readback(0x0003), Some(0xD9));
+
+        // Boot lock bits can only be programmed
+        core.general[0] = 0xEF;
+        core.general[30] = 0;
+        core.general[31] = 0;
+        spm(&mut core, 0x09, 0);
+        assert_eq!(core.fuses.lock, 0xEF);
+    }
+
+    #[test]
+    fn lpm_reads_data_words() {
+        let mut core = Avrcore::new(HashMap::new());
+        // An erased word followed by a PROGMEM string
+        core.load_progmem(0x0200, &[0xFF, 0xFF, b'H', b'i']);
+
+        // LPM R24, Z+
+        let lpm = disassembler::decode_at(&[0x85, 0x91], 0).unwrap();
+        core.set_z_pointer(0x0201);
+        lpm.execute(&mut core).unwrap();
+        lpm.execute(&mut core).unwrap();
+        assert_eq!(core.general[24], b'H');
+        assert_eq!(core.z_pointer(), 0x0203);
+
+        // ELPM R0 with RAMPZ beyond the end of flash
+        let elpm = disassembler::decode_at(&[0xD8, 0x95], 0).unwrap();
+        core.io[RAMPZ] = 1;
+        elpm.execute(&mut core).unwrap();
+        assert_eq!(core.general[0], 0xFF);
+
+        // Signature row through SIGRD
+        core.io[RAMPZ] = 0;
+        core.pc = 0x7000;
+        core.set_z_pointer(0x0000);
+        core.write_spmcsr(0x21);
+        lpm.execute(&mut core).unwrap();
+        assert_eq!(core.general[24], 0x1E);
+    }
+}
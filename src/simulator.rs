@@ -0,0 +1,630 @@
+use crate::avrcore::{Avrcore, SREG};
+use crate::callstack::CallState;
+use crate::coverage::Coverage;
+use crate::disassembler;
+use crate::elfreader::DATA_OFFSET;
+use crate::error::{Error, ExecError};
+use crate::fuses::Fuses;
+use crate::history::History;
+use crate::instructions::Opcodes;
+use crate::memimage::Program;
+use crate::profile::{ProfileState, Profiler};
+use crate::snapshot::Snapshot;
+use crate::stack::{StackMonitor, StackOverflow, GUARD_SYMBOLS};
+use crate::trace::{TraceState, Tracer};
+use crate::vcd::VcdWriter;
+use crate::watch::{WatchHit, WatchKind};
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::ops::Range;
+
+// Why a run stopped
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum StopReason {
+    SleepingForever, // Asleep with nothing left that can wake the core
+    Condition, // The `run_until` predicate held
+    Watchpoint(WatchHit), // First hit of the instruction that stopped the run
+    HistoryStart, // Reverse execution reached the oldest recorded instruction
+    StackOverflow, // Details from `take_stack_overflow`
+}
+
+// Called for every hit of a watchpoint, returns true to stop execution
+pub type WatchCallback = Box<dyn FnMut(&WatchHit) -> bool>;
+
+// Instrumentation that looks at every step
+#[derive(Default)]
+pub struct Probes {
+    pub tracer: Option<Tracer>,
+    pub vcd: Option<VcdWriter>,
+    pub profiler: Option<Profiler>,
+    pub coverage: Option<Coverage>,
+    pub stack: Option<StackMonitor>,
+}
+
+// What the probes captured before one step
+struct ProbeState {
+    trace: Option<TraceState>,
+    profile: Option<ProfileState>,
+    coverage: Option<u16>,
+    stack: Option<CallState>,
+}
+
+// Decimal or 0x prefixed hexadecimal number
+pub fn parse_number(value: &str) -> Option<u64> {
+    match value.strip_prefix("0x").or_else(|| value.strip_prefix("0X")) {
+        Some(digits) => u64::from_str_radix(digits, 16).ok(),
+        None => value.parse().ok()
+    }
+}
+
+// Configures and loads a `Simulator`
+#[derive(Default)]
+pub struct SimulatorBuilder {
+    path: Option<String>,
+    program: Option<Program>,
+    binary_base: u32,
+    fuses: Option<Fuses>,
+    clock_hz: Option<u32>,
+    strict_decode: bool,
+}
+
+impl SimulatorBuilder {
+    // Image file in any supported format
+    pub fn image_file(mut self, path: &str) -> SimulatorBuilder {
+        self.path = Some(path.to_string());
+        self
+    }
+
+    // An already loaded program
+    pub fn program(mut self, program: Program) -> SimulatorBuilder {
+        self.program = Some(program);
+        self
+    }
+
+    // Load address of a raw binary image
+    pub fn binary_base(mut self, base: u32) -> SimulatorBuilder {
+        self.binary_base = base;
+        self
+    }
+
+    // Fuse and lock bytes. These replace those stored in an ELF image.
+    pub fn fuses(mut self, fuses: Fuses) -> SimulatorBuilder {
+        self.fuses = Some(fuses);
+        self
+    }
+
+    // Frequency of the crystal or external clock fitted to the board
+    pub fn clock_hz(mut self, hz: u32) -> SimulatorBuilder {
+        self.clock_hz = Some(hz);
+        self
+    }
+
+    // Fail to load when a flash word does not decode, instead of faulting
+    // only once it is executed
+    pub fn strict_decode(mut self, strict: bool) -> SimulatorBuilder {
+        self.strict_decode = strict;
+        self
+    }
+
+    pub fn build(self) -> Result<Simulator, Error> {
+        let program = match (self.program, &self.path) {
+            (Some(program), _) => program,
+            (None, Some(path)) => Program::load(path, self.binary_base)?,
+            (None, None) => Program::from_bytes(&[], self.binary_base)?
+        };
+
+        let mut fuses = Fuses::default();
+        program.apply_fuses(&mut fuses);
+        if let Some(explicit) = self.fuses {
+            fuses = explicit;
+        }
+
+        let (dissasm, flash_idx) = match self.strict_decode {
+            true => disassembler::dissasm_strict(&program.flash)?,
+            false => disassembler::dissasm_bytes(&program.flash)
+        };
+        let flash_map: HashMap<usize, Opcodes> = flash_idx.into_iter().zip(dissasm).collect();
+
+        let mut core = Avrcore::with_fuses(flash_map, fuses);
+        if let Some(hz) = self.clock_hz {
+            core.external_clock_hz = hz;
+        }
+        core.load_progmem(0, &program.flash);
+        core.load_eeprom(&program.eeprom);
+
+        Ok(Simulator {
+            core,
+            program,
+            watch_callbacks: HashMap::new(),
+            watch_stops: Vec::new(),
+            history: None,
+            probes: Probes::default(),
+            probe_errors: Vec::new(),
+            stack_overflow: None,
+        })
+    }
+}
+
+// A loaded core together with the program it runs
+pub struct Simulator {
+    pub core: Avrcore,
+    pub program: Program,
+    watch_callbacks: HashMap<u32, WatchCallback>,
+    watch_stops: Vec<WatchHit>, // Hits that asked for execution to stop
+    history: Option<History>, // Recorded instructions while reverse execution is enabled
+    pub probes: Probes,
+    probe_errors: Vec<String>, // Failed writes of probes that were detached since
+    stack_overflow: Option<StackOverflow>,
+}
+
+impl Simulator {
+    pub fn builder() -> SimulatorBuilder {
+        SimulatorBuilder::default()
+    }
+
+    // Execute one instruction, or advance to the next wake-up event while asleep
+    pub fn step(&mut self) -> Result<(), ExecError> {
+        let probed = self.probes_before();
+        let result = match &mut self.history {
+            Some(history) => history.record(&mut self.core),
+            None => self.core.execute()
+        };
+
+        if result.is_ok() {
+            self.probes_after(probed);
+        }
+        let hits = std::mem::take(&mut self.core.watch_hits);
+        self.handle_watch_hits(hits);
+
+        result
+    }
+
+    fn probes_before(&mut self) -> ProbeState {
+        let core = &mut self.core;
+        ProbeState {
+            trace: self.probes.tracer.as_ref().and_then(|tracer| tracer.before(core)),
+            profile: self.probes.profiler.as_ref().map(|profiler| profiler.before(core)),
+            coverage: self.probes.coverage.as_ref().and_then(|coverage| coverage.before(core)),
+            stack: self.probes.stack.as_ref().and_then(|stack| stack.before(core)),
+        }
+    }
+
+    // Hand the step to the probes. Writers that fail are detached.
+    fn probes_after(&mut self, state: ProbeState) {
+        let probes = &mut self.probes;
+
+        if let Some(coverage) = probes.coverage.as_mut() {
+            coverage.after(state.coverage, &self.core);
+        }
+        if let (Some(profiler), Some(profile)) = (probes.profiler.as_mut(), state.profile) {
+            profiler.after(profile, &self.core);
+        }
+        if let Some(writer) = probes.vcd.as_mut() {
+            if let Err(err) = writer.sample(&self.core) {
+                self.probe_errors.push(format!("Cannot write VCD: {}", err));
+                probes.vcd = None;
+            }
+        }
+        if let (Some(tracer), Some(trace)) = (probes.tracer.as_mut(), state.trace) {
+            if let Err(err) = tracer.after(trace, &mut self.core) {
+                self.probe_errors.push(format!("Cannot write trace: {}", err));
+                probes.tracer = None;
+            }
+        }
+        if let Some(stack) = probes.stack.as_mut() {
+            if let Err(overflow) = stack.after(state.stack, &self.core) {
+                self.stack_overflow = Some(overflow);
+            }
+        }
+    }
+
+    // Run the callbacks of the hits and keep those that stop execution
+    fn handle_watch_hits(&mut self, hits: Vec<WatchHit>) {
+        for hit in hits {
+            let stop = match self.watch_callbacks.get_mut(&hit.id) {
+                Some(callback) => callback(&hit),
+                None => true
+            };
+            if stop {
+                self.watch_stops.push(hit);
+            }
+        }
+    }
+
+    // Run until the core sleeps with no way to wake up
+    pub fn run(&mut self) -> Result<StopReason, ExecError> {
+        self.run_until(|_| false)
+    }
+
+    // Run until `condition` holds before an instruction, or the core sleeps forever
+    pub fn run_until<F>(&mut self, mut condition: F) -> Result<StopReason, ExecError>
+    where
+        F: FnMut(&Avrcore) -> bool,
+    {
+        loop {
+            if condition(&self.core) {
+                return Ok(StopReason::Condition)
+            }
+            if self.core.sleeping_forever() {
+                return Ok(StopReason::SleepingForever)
+            }
+
+            self.step()?;
+
+            if let Some(hit) = self.take_watch_stops().first() {
+                return Ok(StopReason::Watchpoint(*hit))
+            }
+            if self.stack_overflow.is_some() {
+                return Ok(StopReason::StackOverflow)
+            }
+        }
+    }
+
+    // Record execution from now on so it can be reversed, keeping at most `limit`
+    // instructions. A limit of 0 stops recording and drops the history.
+    pub fn record(&mut self, limit: usize) {
+        self.history = if limit == 0 { None } else { Some(History::new(limit)) };
+    }
+
+    pub fn recording(&self) -> bool {
+        self.history.is_some()
+    }
+
+    // Instructions that can be stepped back over
+    pub fn recorded(&self) -> usize {
+        self.history.as_ref().map_or(0, |history| history.len())
+    }
+
+    // Undo the last executed instruction. False when there is no history left.
+    // Watchpoints the instruction hit are reported by `take_watch_stops`.
+    pub fn step_back(&mut self) -> bool {
+        let core = &mut self.core;
+        let hits = match self.history.as_mut().and_then(|history| history.undo(core)) {
+            Some(hits) => hits,
+            None => return false
+        };
+
+        self.handle_watch_hits(hits);
+        true
+    }
+
+    // Go back up to `count` instructions, ignoring watchpoints. Returns how many were undone.
+    pub fn rewind(&mut self, count: usize) -> usize {
+        match &mut self.history {
+            Some(history) => history.rewind(&mut self.core, count),
+            None => 0
+        }
+    }
+
+    // Execute backwards until `condition` holds, an undone instruction hit a
+    // watchpoint or the start of the history is reached
+    pub fn reverse_until<F>(&mut self, mut condition: F) -> StopReason
+    where
+        F: FnMut(&Avrcore) -> bool,
+    {
+        loop {
+            if !self.step_back() {
+                return StopReason::HistoryStart
+            }
+            if let Some(hit) = self.take_watch_stops().first() {
+                return StopReason::Watchpoint(*hit)
+            }
+            if condition(&self.core) {
+                return StopReason::Condition
+            }
+        }
+    }
+
+    // Stop execution when an instruction accesses `range` of the data space.
+    // Returns an id for `unwatch`.
+    pub fn watch(&mut self, range: Range<u16>, kind: WatchKind) -> u32 {
+        self.core.add_watchpoint(range, kind)
+    }
+
+    // Like `watch`, but `callback` decides whether a hit stops execution
+    pub fn watch_with<F>(&mut self, range: Range<u16>, kind: WatchKind, callback: F) -> u32
+    where
+        F: FnMut(&WatchHit) -> bool + 'static,
+    {
+        let id = self.core.add_watchpoint(range, kind);
+        self.watch_callbacks.insert(id, Box::new(callback));
+        id
+    }
+
+    pub fn unwatch(&mut self, id: u32) -> bool {
+        self.watch_callbacks.remove(&id);
+        self.core.remove_watchpoint(id)
+    }
+
+    // Watchpoint hits that stopped execution since the last call, for callers of `step`
+    pub fn take_watch_stops(&mut self) -> Vec<WatchHit> {
+        std::mem::take(&mut self.watch_stops)
+    }
+
+    // Failures of the trace and VCD writers since the last call
+    pub fn take_probe_errors(&mut self) -> Vec<String> {
+        std::mem::take(&mut self.probe_errors)
+    }
+
+    // The stack overflow the stack monitor detected, if any
+    pub fn take_stack_overflow(&mut self) -> Option<StackOverflow> {
+        self.stack_overflow.take()
+    }
+
+    // Complete machine state, e.g. to run many tests from one booted firmware
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot::capture(&self.core)
+    }
+
+    // Continue from a snapshot. The recorded history is dropped.
+    pub fn restore(&mut self, snapshot: &Snapshot) {
+        snapshot.restore(&mut self.core);
+        if let Some(history) = &mut self.history {
+            history.clear();
+        }
+    }
+
+    // Byte address of a symbol from the ELF image
+    pub fn symbol(&self, name: &str) -> Option<u32> {
+        self.program.elf.as_ref()?.symbol(name).map(|symbol| symbol.value)
+    }
+
+    // Byte address from a number or an ELF symbol
+    pub fn resolve_address(&self, target: &str) -> Option<u16> {
+        match parse_number(target) {
+            Some(addr) => u16::try_from(addr).ok(),
+            None => u16::try_from(self.symbol(target)?).ok()
+        }
+    }
+
+    // Data space range from a number or an ELF object, with an optional `:LEN`
+    pub fn resolve_data_range(&self, spec: &str) -> Option<Range<u16>> {
+        let (target, len) = match spec.split_once(':') {
+            Some((target, len)) => (target, Some(u32::try_from(parse_number(len)?).ok()?)),
+            None => (spec, None)
+        };
+
+        let (addr, size) = match parse_number(target) {
+            Some(addr) => (u32::try_from(addr).ok()?, 1),
+            None => {
+                let symbol = self.program.elf.as_ref()?.symbol(target)?;
+                (symbol.value, symbol.size.max(1))
+            }
+        };
+
+        // ELF data addresses carry the 0x800000 offset
+        let start = addr.checked_sub(DATA_OFFSET).unwrap_or(addr);
+        let end = start.checked_add(len.unwrap_or(size))?;
+        if end > 0x10000 || len == Some(0) {
+            return None
+        }
+
+        Some(start as u16..end as u16)
+    }
+
+    // Flash range from `START-END`, a single address or the extent of an ELF function
+    pub fn resolve_flash_range(&self, spec: &str) -> Option<Range<u32>> {
+        if let Some((start, end)) = spec.split_once('-') {
+            let (start, end) = (self.resolve_address(start)? as u32, self.resolve_address(end)? as u32);
+            return if start < end { Some(start..end) } else { None }
+        }
+
+        match parse_number(spec) {
+            Some(addr) => {
+                let addr = u32::try_from(addr).ok()?;
+                Some(addr..addr.checked_add(2)?)
+            },
+            None => {
+                let symbol = self.program.elf.as_ref()?.symbol(spec)?;
+                Some(symbol.value..symbol.value.checked_add(symbol.size.max(2))?)
+            }
+        }
+    }
+
+    // Lowest data space address the stack may grow to, from a number, an ELF
+    // symbol or `auto` for the end of the variables
+    pub fn resolve_stack_guard(&self, spec: &str) -> Option<u16> {
+        let addr = match (spec, parse_number(spec)) {
+            (_, Some(addr)) => addr,
+            ("auto", _) => GUARD_SYMBOLS.iter().filter_map(|name| self.symbol(name)).find(|addr| *addr > DATA_OFFSET)? as u64,
+            _ => self.symbol(spec)? as u64
+        };
+
+        // ELF data addresses carry the 0x800000 offset
+        let addr = addr.checked_sub(DATA_OFFSET as u64).unwrap_or(addr);
+        if addr > 0xFFFF {
+            return None
+        }
+
+        Some(addr as u16)
+    }
+
+    pub fn pc(&self) -> u16 {
+        self.core.pc
+    }
+
+    pub fn set_pc(&mut self, pc: u16) {
+        self.core.pc = pc;
+    }
+
+    pub fn cycles(&self) -> u64 {
+        self.core.cycles
+    }
+
+    pub fn register(&self, rd: usize) -> u8 {
+        self.core.general[rd]
+    }
+
+    pub fn set_register(&mut self, rd: usize, value: u8) {
+        self.core.general[rd] = value;
+    }
+
+    pub fn sreg(&self) -> &SREG {
+        &self.core.sreg
+    }
+
+    pub fn sp(&self) -> u16 {
+        self.core.sp.current_addr()
+    }
+
+    // Byte of the unified data space
+    pub fn read_data(&self, addr: u16) -> u8 {
+        self.core.read_data(addr)
+    }
+
+    pub fn write_data(&mut self, addr: u16, value: u8) {
+        self.core.write_data(addr, value)
+    }
+
+    pub fn flash(&self) -> &[u8] {
+        &self.core.progmem
+    }
+
+    pub fn eeprom(&self) -> &[u8] {
+        &self.core.eeprom
+    }
+}
+
+// Tests
+#[cfg(test)]
+mod tests {
+    use crate::coverage::Coverage;
+    use crate::simulator::{Simulator, StopReason};
+    use crate::stack::StackMonitor;
+    use crate::error::{DecodeError, Error, ExecError};
+    use crate::fuses::Fuses;
+    use crate::instructions::Opcodes;
+    use crate::memimage::Program;
+    use crate::watch::WatchKind;
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    #[test]
+    fn run_to_main() {
+        let mut sim = Simulator::builder().image_file("testprogram.bin").build().unwrap();
+        let main = sim.symbol("main").unwrap() as u16;
+
+        assert_eq!(sim.run_until(|core| core.pc == main).unwrap(), StopReason::Condition);
+        assert_eq!(sim.register(1), 0);
+        assert_eq!(sim.read_data(0x5F), 0x00);
+        assert_eq!(sim.sp(), 0x08FD);
+
+        // main adds 4 to a zeroed local on its frame and returns 0
+        let exit = sim.symbol("_exit").unwrap() as u16;
+        assert_eq!(sim.run_until(|core| core.pc == exit).unwrap(), StopReason::Condition);
+        assert_eq!((sim.register(24), sim.register(25)), (0, 0));
+        assert_eq!((sim.read_data(0x08F8), sim.read_data(0x08F9)), (4, 0));
+        assert_eq!(sim.sp(), 0x08FF);
+
+        // _exit disables interrupts and spins in place
+        assert_eq!(sim.run().unwrap(), StopReason::SleepingForever);
+        assert_eq!(sim.pc(), exit + 2);
+    }
+
+    #[test]
+    fn explicit_fuses_win() {
+        let program = || {
+            let mut program = Program::load("testprogram.bin", 0).unwrap();
+            program.elf.as_mut().unwrap().fuse = vec![0xFF, 0xDE, 0xFD];
+            program
+        };
+
+        let sim = Simulator::builder().program(program()).build().unwrap();
+        assert_eq!((sim.core.fuses.low, sim.core.fuses.high, sim.core.fuses.extended), (0xFF, 0xDE, 0xFD));
+
+        let sim = Simulator::builder().program(program()).fuses(Fuses::default()).build().unwrap();
+        assert_eq!(sim.core.fuses, Fuses::default());
+    }
+
+    #[test]
+    fn data_faults_when_executed() {
+        // jmp over a data word to cli, then fall into a second data word
+        let image = [0x0C, 0x94, 0x03, 0x00, 0xFF, 0xFF, 0xF8, 0x94, 0x48, 0x69];
+        let strict = Simulator::builder().program(Program::from_bytes(&image, 0).unwrap()).strict_decode(true).build();
+        assert!(matches!(strict, Err(Error::Decode(DecodeError { address: 4, word: 0xFFFF, .. }))));
+
+        let mut sim = Simulator::builder().program(Program::from_bytes(&image, 0).unwrap()).build().unwrap();
+
+        sim.step().unwrap();
+        sim.step().unwrap();
+        assert_eq!(sim.step(), Err(ExecError::Undecodable { address: 8, word: 0x6948 }));
+    }
+
+    #[test]
+    fn watchpoint_callback() {
+        let mut sim = Simulator::builder().image_file("testprogram.bin").build().unwrap();
+        let sp = sim.sp();
+
+        // Count the writes to SREG, then stop at the first stack write, the call to main
+        let sreg_writes = Rc::new(Cell::new(0));
+        let counter = sreg_writes.clone();
+        sim.watch_with(0x5F..0x60, WatchKind::Write, move |_| {
+            counter.set(counter.get() + 1);
+            false
+        });
+        sim.watch(sp - 1..sp + 1, WatchKind::Write);
+
+        match sim.run().unwrap() {
+            StopReason::Watchpoint(hit) => {
+                assert_eq!(hit.address, sp);
+                assert!(matches!(sim.core.flash[&(hit.pc as usize)], Opcodes::CALL(_)));
+                assert_eq!(hit.new as u32, (hit.pc as u32 + 4) & 0xFF);
+            },
+            reason => panic!("Unexpected stop {:?}", reason)
+        }
+        assert_eq!(sreg_writes.get(), 1);
+    }
+
+    #[test]
+    fn reverse_to_watchpoint() {
+        let mut sim = Simulator::builder().image_file("testprogram.bin").build().unwrap();
+        let main = sim.symbol("main").unwrap() as u16;
+        sim.record(1000);
+
+        sim.run_until(|core| core.pc == main).unwrap();
+        let cycles = sim.cycles();
+        let sp = sim.sp();
+
+        // The return address pushed by the call to main
+        sim.watch(sp + 1..sp + 2, WatchKind::Write);
+        match sim.reverse_until(|_| false) {
+            StopReason::Watchpoint(hit) => assert!(matches!(sim.core.flash[&(hit.pc as usize)], Opcodes::CALL(_))),
+            reason => panic!("Unexpected stop {:?}", reason)
+        }
+        assert_eq!(sim.sp(), sp + 2);
+        assert_eq!(sim.reverse_until(|_| false), StopReason::HistoryStart);
+        assert_eq!((sim.pc(), sim.cycles()), (0, 0));
+
+        // Replaying gets back to the same state
+        sim.run_until(|core| core.pc == main).unwrap();
+        assert_eq!((sim.cycles(), sim.sp()), (cycles, sp));
+        let recorded = sim.recorded();
+        assert_eq!(sim.rewind(3), 3);
+        assert_eq!(sim.recorded(), recorded - 3);
+    }
+
+    #[test]
+    fn resolve_and_probe() {
+        let mut sim = Simulator::builder().image_file("testprogram.bin").build().unwrap();
+        let main = sim.symbol("main").unwrap();
+
+        assert_eq!(sim.resolve_address("main"), Some(main as u16));
+        assert_eq!(sim.resolve_address("0x10"), Some(0x10));
+        assert_eq!(sim.resolve_flash_range("0x10-main"), Some(0x10..main));
+        assert_eq!(sim.resolve_flash_range("main-0x10"), None);
+        assert_eq!(sim.resolve_data_range("0x100:4"), Some(0x100..0x104));
+        assert_eq!(sim.resolve_data_range("0xFFFF:2"), None);
+        assert_eq!(sim.resolve_stack_guard("0x800300"), Some(0x300));
+        assert_eq!(sim.resolve_address("nosuchsymbol"), None);
+        assert_eq!(sim.resolve_address("0x12345"), None);
+        assert_eq!(sim.resolve_flash_range("0xFFFFFFFF"), None);
+        assert_eq!(sim.resolve_data_range("0x100:0x100000000"), None);
+
+        // A guard above the stack stops at the first push
+        sim.probes.coverage = Some(Coverage::new());
+        sim.probes.stack = Some(StackMonitor::new(&sim.core, Some(sim.sp())));
+        assert_eq!(sim.run().unwrap(), StopReason::StackOverflow);
+        assert!(sim.take_stack_overflow().is_some());
+        assert_eq!(sim.probes.coverage.as_ref().unwrap().hits(0), 1);
+    }
+}
@@ -0,0 +1,148 @@
+// Sleep Mode Control Register (I/O address)
+pub const SMCR: usize = 0x33;
+const SMCR_SE: u8 = 0;
+
+// The MCU is halted for four cycles after waking before the ISR is entered
+const WAKEUP_HALT_CYCLES: u64 = 4;
+
+// Standby modes keep the oscillator running and only wait for the clock to settle
+const STANDBY_WAKEUP_CYCLES: u64 = 6;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ClockDomain {
+    Cpu,
+    Flash,
+    Io,
+    Adc,
+    Asy,
+}
+
+// Interrupt sources as classified by the wake-up column of the sleep mode table
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum WakeSource {
+    ExternalLevel,
+    ExternalEdge,
+    PinChange,
+    TwiAddressMatch,
+    Timer2 { asynchronous: bool },
+    SpmEepromReady,
+    Adc,
+    Watchdog,
+    OtherIo,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum SleepMode {
+    Idle,
+    AdcNoiseReduction,
+    PowerDown,
+    PowerSave,
+    Standby,
+    ExtendedStandby,
+}
+
+impl SleepMode {
+    // Decode SMCR. Returns None if sleep is not enabled or SM selects a reserved mode.
+    pub fn from_smcr(smcr: u8) -> Option<SleepMode> {
+        if smcr & (1 << SMCR_SE) == 0 {
+            return None
+        }
+
+        match (smcr >> 1) & 0b111 {
+            0b000 => Some(SleepMode::Idle),
+            0b001 => Some(SleepMode::AdcNoiseReduction),
+            0b010 => Some(SleepMode::PowerDown),
+            0b011 => Some(SleepMode::PowerSave),
+            0b110 => Some(SleepMode::Standby),
+            0b111 => Some(SleepMode::ExtendedStandby),
+            _ => None
+        }
+    }
+
+    // SMCR value that selects this mode with sleep enabled
+    pub fn to_smcr(&self) -> u8 {
+        let sm = match self {
+            SleepMode::Idle => 0b000,
+            SleepMode::AdcNoiseReduction => 0b001,
+            SleepMode::PowerDown => 0b010,
+            SleepMode::PowerSave => 0b011,
+            SleepMode::Standby => 0b110,
+            SleepMode::ExtendedStandby => 0b111,
+        };
+
+        sm << 1 | 1 << SMCR_SE
+    }
+
+    pub fn clock_running(&self, domain: ClockDomain) -> bool {
+        match (self, domain) {
+            (_, ClockDomain::Cpu) | (_, ClockDomain::Flash) => false,
+            (SleepMode::Idle, _) => true,
+            (SleepMode::AdcNoiseReduction, ClockDomain::Adc) => true,
+            (SleepMode::AdcNoiseReduction, ClockDomain::Asy) => true,
+            (SleepMode::PowerSave, ClockDomain::Asy) => true,
+            (SleepMode::ExtendedStandby, ClockDomain::Asy) => true,
+            _ => false
+        }
+    }
+
+    pub fn can_wake(&self, source: WakeSource) -> bool {
+        match source {
+            WakeSource::ExternalLevel
+            | WakeSource::PinChange
+            | WakeSource::TwiAddressMatch
+            | WakeSource::Watchdog => true,
+            WakeSource::Timer2 { asynchronous } => match self {
+                SleepMode::Idle => true,
+                SleepMode::AdcNoiseReduction | SleepMode::PowerSave | SleepMode::ExtendedStandby => asynchronous,
+                _ => false
+            },
+            WakeSource::SpmEepromReady | WakeSource::Adc => {
+                matches!(self, SleepMode::Idle | SleepMode::AdcNoiseReduction)
+            },
+            WakeSource::ExternalEdge | WakeSource::OtherIo => *self == SleepMode::Idle,
+        }
+    }
+
+    // Cycles from the wake-up event until the interrupt routine is entered. Modes
+    // that stop the main oscillator wait for the start-up time selected by the SUT fuses.
+    pub fn wakeup_cycles(&self, oscillator_startup: u64) -> u64 {
+        match self {
+            SleepMode::Idle | SleepMode::AdcNoiseReduction => WAKEUP_HALT_CYCLES,
+            SleepMode::Standby | SleepMode::ExtendedStandby => WAKEUP_HALT_CYCLES + STANDBY_WAKEUP_CYCLES,
+            SleepMode::PowerDown | SleepMode::PowerSave => WAKEUP_HALT_CYCLES + oscillator_startup,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct SleepState {
+    pub mode: SleepMode,
+    pub since: u64,
+}
+
+// Tests
+#[cfg(test)]
+mod tests {
+    use crate::sleep::{SleepMode, WakeSource, ClockDomain};
+
+    #[test]
+    fn decode_smcr() {
+        assert_eq!(SleepMode::from_smcr(0b0000), None);
+        assert_eq!(SleepMode::from_smcr(0b0001), Some(SleepMode::Idle));
+        assert_eq!(SleepMode::from_smcr(0b0101), Some(SleepMode::PowerDown));
+        assert_eq!(SleepMode::from_smcr(0b1001), None);
+        assert_eq!(SleepMode::from_smcr(0b1111), Some(SleepMode::ExtendedStandby));
+    }
+
+    #[test]
+    fn wake_sources() {
+        assert!(SleepMode::Idle.can_wake(WakeSource::ExternalEdge));
+        assert!(!SleepMode::PowerDown.can_wake(WakeSource::ExternalEdge));
+        assert!(SleepMode::PowerDown.can_wake(WakeSource::ExternalLevel));
+        assert!(!SleepMode::PowerSave.can_wake(WakeSource::Timer2 { asynchronous: false }));
+        assert!(SleepMode::PowerSave.can_wake(WakeSource::Timer2 { asynchronous: true }));
+        assert!(SleepMode::AdcNoiseReduction.can_wake(WakeSource::Adc));
+        assert!(!SleepMode::Standby.can_wake(WakeSource::Adc));
+        assert!(!SleepMode::AdcNoiseReduction.clock_running(ClockDomain::Io));
+    }
+}
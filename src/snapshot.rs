@@ -0,0 +1,329 @@
+use crate::avrcore::{Avrcore, EEPROM_SIZE, SREG};
+use crate::disassembler;
+use crate::error::LoadError;
+use crate::fuses::{Fuses, FLASH_WORDS};
+use crate::interrupts::Interrupt;
+use crate::selfprog::{SelfProgramming, PAGE_SIZE};
+use crate::sleep::{SleepMode, SleepState};
+use std::fs;
+use std::io;
+
+// Start of every snapshot file, followed by the format version
+const MAGIC: &[u8; 8] = b"AVRSNAP\0";
+
+// Bumped whenever the layout below changes
+pub const VERSION: u16 = 2;
+
+// Complete machine state: registers, all memories, peripheral state, pending
+// and scheduled interrupts and the cycle counter. Restoring it and running
+// gives exactly the same execution as continuing from where it was captured.
+// Debugger state such as watchpoints is not part of it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Snapshot {
+    pub pc: u16,
+    pub sp: u16,
+    pub sreg: u8,
+    pub general: [u8; 32],
+    pub io: [u8; 64],
+    pub extio: [u8; 160],
+    pub sram: Vec<u8>,
+    pub progmem: Vec<u8>,
+    pub eeprom: Vec<u8>,
+    pub spm: SelfProgramming,
+    pub fuses: Fuses,
+    pub external_clock_hz: u32,
+    pub cycles: u64,
+    pub pending_interrupts: u32,
+    pub interrupt_inhibit: bool,
+    pub scheduled: Vec<(u64, Interrupt)>,
+    pub sleep: Option<SleepState>,
+    pub uart_tx: Vec<u8>, // Transmitted bytes not yet collected
+}
+
+// Reads the little endian fields of a snapshot or binary trace
+pub(crate) struct Reader<'a> {
+    bytes: &'a [u8],
+    offset: usize,
+    kind: &'static str, // What is being read, for error messages
+}
+
+impl<'a> Reader<'a> {
+    pub(crate) fn new(bytes: &'a [u8], kind: &'static str) -> Reader<'a> {
+        Reader { bytes, offset: 0, kind }
+    }
+
+    pub(crate) fn position(&self) -> usize {
+        self.offset
+    }
+
+    pub(crate) fn at_end(&self) -> bool {
+        self.offset >= self.bytes.len()
+    }
+
+    pub(crate) fn take(&mut self, len: usize) -> Result<&'a [u8], LoadError> {
+        let end = self.offset + len;
+        let field = self.bytes.get(self.offset..end)
+            .ok_or(LoadError::Format(format!("{} is truncated", self.kind)))?;
+
+        self.offset = end;
+        Ok(field)
+    }
+
+    pub(crate) fn array<const N: usize>(&mut self) -> Result<[u8; N], LoadError> {
+        let mut array = [0u8; N];
+        array.copy_from_slice(self.take(N)?);
+        Ok(array)
+    }
+
+    pub(crate) fn u8(&mut self) -> Result<u8, LoadError> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub(crate) fn u16(&mut self) -> Result<u16, LoadError> {
+        Ok(u16::from_le_bytes(self.array()?))
+    }
+
+    pub(crate) fn u32(&mut self) -> Result<u32, LoadError> {
+        Ok(u32::from_le_bytes(self.array()?))
+    }
+
+    pub(crate) fn u64(&mut self) -> Result<u64, LoadError> {
+        Ok(u64::from_le_bytes(self.array()?))
+    }
+
+    // Length prefixed bytes, which must have the expected length if one is given
+    fn bytes(&mut self, expected: Option<usize>, name: &str) -> Result<Vec<u8>, LoadError> {
+        let len = self.u32()? as usize;
+        if expected.is_some_and(|expected| expected != len) {
+            return Err(LoadError::Format(format!("{} {} has the wrong size", self.kind, name)))
+        }
+
+        Ok(self.take(len)?.to_vec())
+    }
+}
+
+fn put_bytes(out: &mut Vec<u8>, bytes: &[u8]) {
+    out.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+    out.extend_from_slice(bytes);
+}
+
+impl Snapshot {
+    pub fn capture(core: &Avrcore) -> Snapshot {
+        Snapshot {
+            pc: core.pc,
+            sp: core.sp.current_addr(),
+            sreg: core.sreg.to_byte(),
+            general: core.general,
+            io: core.io,
+            extio: core.extio,
+            sram: core.sram.to_vec(),
+            progmem: core.progmem.clone(),
+            eeprom: core.eeprom.clone(),
+            spm: core.spm.clone(),
+            fuses: core.fuses,
+            external_clock_hz: core.external_clock_hz,
+            cycles: core.cycles,
+            pending_interrupts: core.pending_interrupts,
+            interrupt_inhibit: core.interrupt_inhibit,
+            scheduled: core.scheduled.clone(),
+            sleep: core.sleep,
+            uart_tx: core.uart_tx.clone(),
+        }
+    }
+
+    pub fn restore(&self, core: &mut Avrcore) {
+        core.pc = self.pc;
+        core.set_sp(self.sp);
+        core.sreg = SREG::from_byte(self.sreg);
+        core.general = self.general;
+        core.io = self.io;
+        core.extio = self.extio;
+        core.sram.copy_from_slice(&self.sram);
+        core.eeprom.clone_from(&self.eeprom);
+        core.spm = self.spm.clone();
+        core.fuses = self.fuses;
+        core.external_clock_hz = self.external_clock_hz;
+        core.cycles = self.cycles;
+        core.pending_interrupts = self.pending_interrupts;
+        core.interrupt_inhibit = self.interrupt_inhibit;
+        core.scheduled.clone_from(&self.scheduled);
+        core.sleep = self.sleep;
+        core.uart_tx.clone_from(&self.uart_tx);
+
+        if core.progmem != self.progmem {
+            core.progmem.clone_from(&self.progmem);
+
+            // Erased flash at the end is left undecoded, as when loading an image
+            let end = core.progmem.iter().rposition(|&byte| byte != 0xFF).map_or(0, |idx| (idx + 2) & !1);
+            let (dissasm, flash_idx) = disassembler::dissasm_bytes(&core.progmem[..end]);
+            core.flash = flash_idx.into_iter().zip(dissasm).collect();
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = MAGIC.to_vec();
+        out.extend_from_slice(&VERSION.to_le_bytes());
+
+        out.extend_from_slice(&self.pc.to_le_bytes());
+        out.extend_from_slice(&self.sp.to_le_bytes());
+        out.push(self.sreg);
+        out.extend_from_slice(&self.general);
+        out.extend_from_slice(&self.io);
+        out.extend_from_slice(&self.extio);
+        put_bytes(&mut out, &self.sram);
+        put_bytes(&mut out, &self.progmem);
+        put_bytes(&mut out, &self.eeprom);
+
+        out.extend_from_slice(&self.spm.buffer);
+        out.extend_from_slice(&self.spm.armed_at.to_le_bytes());
+        out.extend_from_slice(&self.spm.busy_until.unwrap_or(u64::MAX).to_le_bytes());
+
+        out.extend_from_slice(&[self.fuses.low, self.fuses.high, self.fuses.extended, self.fuses.lock]);
+        out.extend_from_slice(&self.external_clock_hz.to_le_bytes());
+        out.extend_from_slice(&self.cycles.to_le_bytes());
+        out.extend_from_slice(&self.pending_interrupts.to_le_bytes());
+        out.push(self.interrupt_inhibit as u8);
+
+        out.extend_from_slice(&(self.scheduled.len() as u32).to_le_bytes());
+        for (at, irq) in self.scheduled.iter() {
+            out.extend_from_slice(&at.to_le_bytes());
+            out.push(irq.vector());
+        }
+
+        // SMCR value selecting the mode, 0 while awake
+        match self.sleep {
+            Some(state) => {
+                out.push(state.mode.to_smcr());
+                out.extend_from_slice(&state.since.to_le_bytes());
+            },
+            None => out.push(0)
+        }
+
+        put_bytes(&mut out, &self.uart_tx);
+        out
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Snapshot, LoadError> {
+        let mut reader = Reader::new(bytes, "Snapshot");
+
+        if reader.take(MAGIC.len()).ok() != Some(&MAGIC[..]) {
+            return Err(LoadError::Format(String::from("Not an avrsim snapshot")))
+        }
+        let version = reader.u16()?;
+        if version != VERSION {
+            return Err(LoadError::Format(format!("Unsupported snapshot version {}, expected {}", version, VERSION)))
+        }
+
+        let pc = reader.u16()?;
+        let sp = reader.u16()?;
+        let sreg = reader.u8()?;
+        let general = reader.array()?;
+        let io = reader.array()?;
+        let extio = reader.array()?;
+        let sram = reader.bytes(Some(2048), "SRAM")?;
+        let progmem = reader.bytes(Some(FLASH_WORDS as usize * 2), "flash")?;
+        let eeprom = reader.bytes(Some(EEPROM_SIZE), "EEPROM")?;
+
+        let spm = SelfProgramming {
+            buffer: reader.array::<PAGE_SIZE>()?,
+            armed_at: reader.u64()?,
+            busy_until: Some(reader.u64()?).filter(|&until| until != u64::MAX),
+        };
+
+        let [low, high, extended, lock] = reader.array()?;
+        let fuses = Fuses { low, high, extended, lock };
+        let external_clock_hz = reader.u32()?;
+        let cycles = reader.u64()?;
+        let pending_interrupts = reader.u32()?;
+        let interrupt_inhibit = reader.u8()? != 0;
+
+        let mut scheduled = Vec::new();
+        for _ in 0..reader.u32()? {
+            let at = reader.u64()?;
+            let vector = reader.u8()?;
+            let irq = Interrupt::from_vector(vector)
+                .ok_or(LoadError::Format(format!("Invalid interrupt vector {} in snapshot", vector)))?;
+            scheduled.push((at, irq));
+        }
+
+        let sleep = match reader.u8()? {
+            0 => None,
+            smcr => {
+                let mode = SleepMode::from_smcr(smcr)
+                    .ok_or(LoadError::Format(format!("Invalid sleep mode {:#04x} in snapshot", smcr)))?;
+                Some(SleepState { mode, since: reader.u64()? })
+            }
+        };
+
+        let uart_tx = reader.bytes(None, "USART output")?;
+
+        Ok(Snapshot {
+            pc, sp, sreg, general, io, extio, sram, progmem, eeprom, spm, fuses, external_clock_hz,
+            cycles, pending_interrupts, interrupt_inhibit, scheduled, sleep, uart_tx,
+        })
+    }
+
+    pub fn save(&self, path: &str) -> io::Result<()> {
+        fs::write(path, self.to_bytes())
+    }
+
+    pub fn load(path: &str) -> Result<Snapshot, LoadError> {
+        let bytes = fs::read(path).map_err(|err| LoadError::io(path, err))?;
+        Snapshot::from_bytes(&bytes)
+    }
+}
+
+// Tests
+#[cfg(test)]
+mod tests {
+    use crate::interrupts::Interrupt;
+    use crate::memimage::Program;
+    use crate::simulator::Simulator;
+    use crate::snapshot::{Snapshot, VERSION};
+
+    // pc, cycles, SREG, SP, the register file and GPIOR0 after each instruction
+    fn trace(sim: &mut Simulator, steps: usize) -> Vec<(u16, u64, u8, u16, [u8; 32], u8)> {
+        (0..steps)
+            .map(|_| {
+                sim.step().unwrap();
+                (sim.pc(), sim.cycles(), sim.sreg().to_byte(), sim.sp(), sim.core.general, sim.read_data(0x3E))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn exact_resume() {
+        let mut image = vec![0xFF; 0x76];
+        // jmp 0x64 at reset, reti at the USART TX vector
+        image[0x00..0x04].copy_from_slice(&[0x0C, 0x94, 0x30, 0x00]);
+        image[0x50..0x52].copy_from_slice(&[0x18, 0x95]);
+        // ldi r16, 0x5a; sei; loop: eor r17, r16; in r18, 0x3f; out 0x1e, r17;
+        // in r19, 0x1e; eor r20, r17; push r17; jmp loop
+        image[0x60..0x74].copy_from_slice(&[
+            0x0A, 0xE5, 0x78, 0x94, 0x10, 0x27, 0x2F, 0xB7, 0x1E, 0xBB, 0x3E, 0xB3,
+            0x41, 0x27, 0x1F, 0x93, 0x0C, 0x94, 0x32, 0x00,
+        ]);
+
+        let mut sim = Simulator::builder().program(Program::from_bytes(&image, 0).unwrap()).build().unwrap();
+        trace(&mut sim, 20);
+        let at = sim.cycles() + 37;
+        sim.core.schedule_interrupt(at, Interrupt::UsartTx);
+
+        let snapshot = sim.snapshot();
+        let bytes = snapshot.to_bytes();
+        let expected = trace(&mut sim, 200);
+        assert!(sim.core.scheduled.is_empty() && sim.core.pending_interrupts == 0);
+
+        // Restoring into a simulator without a program brings the flash along
+        let mut restored = Simulator::builder().build().unwrap();
+        let loaded = Snapshot::from_bytes(&bytes).unwrap();
+        assert_eq!(loaded, snapshot);
+        restored.restore(&loaded);
+        assert_eq!(trace(&mut restored, 200), expected);
+
+        let mut future = bytes.clone();
+        future[8] = VERSION as u8 + 1;
+        assert!(Snapshot::from_bytes(&future).is_err());
+        assert!(Snapshot::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+    }
+}
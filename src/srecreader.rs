@@ -0,0 +1,129 @@
+use crate::error::LoadError;
+use crate::memimage::MemoryImage;
+use std::fs;
+
+#[derive(Debug)]
+struct SrecLine {
+    record_type: u8,
+    address: u32,
+    data: Vec<u8>,
+}
+
+// Bytes of address field per record type
+fn address_len(record_type: u8) -> Option<usize> {
+    match record_type {
+        0 | 1 | 5 | 9 => Some(2),
+        2 | 6 | 8 => Some(3),
+        3 | 7 => Some(4),
+        _ => None
+    }
+}
+
+fn split_srec_line(line: &str, line_number: usize) -> Result<SrecLine, LoadError> {
+    let bytes = line.as_bytes();
+    if bytes.len() < 4 || bytes[0] != b'S' || !bytes[1].is_ascii_digit() {
+        return Err(LoadError::record(line_number, format!("Encountered {}, but line does not start with an S record type", line)));
+    }
+
+    let record_type = bytes[1] - b'0';
+    let address_len = match address_len(record_type) {
+        Some(len) => len,
+        None => return Err(LoadError::record(line_number, format!("Unknown record type S{}", record_type)))
+    };
+
+    let hex = &line[2..];
+    if !hex.len().is_multiple_of(2) || !hex.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+        return Err(LoadError::record(line_number, format!("Malformed record {}", line)));
+    }
+    let fields: Vec<u8> = (0..hex.len()).step_by(2)
+        .map(|idx| u8::from_str_radix(&hex[idx..idx + 2], 16).unwrap())
+        .collect();
+
+    // The count covers address, data and checksum
+    let count = fields[0] as usize;
+    if count != fields.len() - 1 || count < address_len + 1 {
+        return Err(LoadError::record(line_number, format!("Byte count mismatch: record says {} but holds {} bytes",
+                                                          count, fields.len() - 1)));
+    }
+
+    // Ones' complement of the sum of count, address and data
+    let sum = fields[..fields.len() - 1].iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+    let checksum = fields[fields.len() - 1];
+    if !sum != checksum {
+        return Err(LoadError::record(line_number, format!("Checksum mismatch: expected {:#04x}, found {:#04x}", !sum, checksum)));
+    }
+
+    let address = fields[1..1 + address_len].iter().fold(0u32, |address, byte| address << 8 | *byte as u32);
+
+    Ok(SrecLine {
+        record_type,
+        address,
+        data: fields[1 + address_len..fields.len() - 1].to_vec(),
+    })
+}
+
+// S19, S28 and S37 files. Header and record count lines are validated but otherwise ignored.
+pub fn parse_srec(text: &str) -> Result<MemoryImage, LoadError> {
+    let mut image = MemoryImage::default();
+    let mut data_records = 0;
+
+    for (idx, line) in text.lines().enumerate() {
+        let line = line.trim_end();
+        if line.is_empty() {
+            continue
+        }
+
+        let line_number = idx + 1;
+        let srec = split_srec_line(line, line_number)?;
+
+        match srec.record_type {
+            0 => (),
+            1..=3 => {
+                image.add(srec.address, &srec.data).map_err(|err| LoadError::record(line_number, err.to_string()))?;
+                data_records += 1;
+            },
+            5 | 6 => {
+                if srec.address != data_records {
+                    return Err(LoadError::record(line_number, format!("Record count is {}, but {} data records were read",
+                                                                      srec.address, data_records)));
+                }
+            },
+            _ => {
+                image.start_address = Some(srec.address);
+                break
+            }
+        }
+    }
+
+    Ok(image)
+}
+
+pub fn srec_to_image(path: &str) -> Result<MemoryImage, LoadError> {
+    let data = fs::read_to_string(path).map_err(|err| LoadError::io(path, err))?;
+
+    parse_srec(&data)
+}
+
+// Tests
+#[cfg(test)]
+mod tests {
+    use crate::srecreader::parse_srec;
+    use crate::error::LoadError;
+    use crate::hexreader;
+
+    #[test]
+    fn srec_records() {
+        // The first bytes of testprogram.hex as S1, S2 and S3 records
+        let image = parse_srec("S00600004844521B\nS10700000C94340024\nS2080000040C943E0015\nS309000000080C943E0010\nS5030003F9\nS9030000FC\n").unwrap();
+
+        assert_eq!(image.to_bytes().unwrap()[0..12], hexreader::ihex_to_bytes("testprogram.hex").unwrap()[0..12]);
+        assert_eq!(image.segments.len(), 1);
+        assert_eq!(image.start_address, Some(0));
+    }
+
+    #[test]
+    fn bad_checksum() {
+        let err = parse_srec("S00600004844521B\nS10700000C94340000\n").unwrap_err();
+        assert!(matches!(err, LoadError::Record { line: 2, .. }));
+    }
+}
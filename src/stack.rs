@@ -0,0 +1,203 @@
+use crate::avrcore::{Avrcore, RAMEND};
+use crate::callstack::{CallFrame, CallStack, CallState};
+use crate::listing::SymbolMap;
+use std::collections::HashMap;
+
+// ELF symbols marking the end of the variables, in order of preference.
+// avr-libc leaves __heap_end at 0 unless the heap is given a fixed size.
+pub const GUARD_SYMBOLS: [&str; 3] = ["__heap_end", "__bss_end", "_end"];
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct StackUsage {
+    pub depth: u16, // Most bytes below the entry SP while on top, return address included
+    pub lowest_sp: u16,
+}
+
+// The stack grew into the guard region
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StackOverflow {
+    pub pc: u16, // Instruction that moved SP
+    pub sp: u16,
+    pub guard: u16,
+    pub frames: Vec<CallFrame>, // Innermost last
+}
+
+// `name` or `name+0x12` for a flash address, the bare address without symbols
+fn location(symbols: &SymbolMap, addr: u32) -> String {
+    match symbols.lookup(addr) {
+        Some((name, 0)) => name.to_string(),
+        Some((name, offset)) => format!("{}+{:#x}", name, offset),
+        None => format!("{:#x}", addr)
+    }
+}
+
+impl StackOverflow {
+    // Diagnostic with a backtrace, innermost frame first
+    pub fn describe(&self, symbols: &SymbolMap) -> String {
+        let mut text = format!("Stack overflow: SP {:#06x} set by the instruction at {:#x} is below the guard at {:#06x}\n",
+                               self.sp, self.pc, self.guard);
+
+        let mut pc = self.pc as u32;
+        for (level, frame) in self.frames.iter().rev().enumerate() {
+            text += &format!("  #{:<3} {:#06x} in {}", level, pc, location(symbols, pc));
+            if let Some(irq) = frame.interrupt {
+                text += &format!(", {} handler", irq.name());
+            }
+            text += "\n";
+
+            match frame.call_site {
+                Some(call_site) => pc = call_site,
+                None => break
+            }
+        }
+
+        text
+    }
+}
+
+// Follows the call stack to record how deep the stack gets, overall and per
+// function, and stops the program once the stack grows below the guard
+// address into the variables.
+pub struct StackMonitor {
+    pub guard: Option<u16>, // Lowest data space address the stack may occupy
+    min_sp: u16,
+    calls: CallStack,
+    usage: HashMap<u32, StackUsage>, // By function entry address
+}
+
+impl StackMonitor {
+    pub fn new(core: &Avrcore, guard: Option<u16>) -> StackMonitor {
+        StackMonitor {
+            guard,
+            min_sp: core.sp.current_addr(),
+            calls: CallStack::new(core),
+            usage: HashMap::new(),
+        }
+    }
+
+    pub fn before(&self, core: &Avrcore) -> Option<CallState> {
+        CallState::capture(core)
+    }
+
+    // Account for the step executed since `before`
+    pub fn after(&mut self, state: Option<CallState>, core: &Avrcore) -> Result<(), StackOverflow> {
+        let state = match state {
+            Some(state) => state,
+            None => return Ok(())
+        };
+        let sp = core.sp.current_addr();
+        self.calls.after(&state, core);
+
+        self.min_sp = self.min_sp.min(sp);
+        if let Some(frame) = self.calls.top() {
+            let depth = frame.sp.unwrap_or(RAMEND).saturating_sub(sp);
+            let usage = self.usage.entry(frame.function).or_insert(StackUsage { depth, lowest_sp: sp });
+            usage.depth = usage.depth.max(depth);
+            usage.lowest_sp = usage.lowest_sp.min(sp);
+        }
+
+        // The last byte pushed is just above SP
+        match self.guard {
+            Some(guard) if (sp as u32) + 1 < guard as u32 => Err(StackOverflow { pc: state.pc, sp, guard, frames: self.calls.frames().to_vec() }),
+            _ => Ok(())
+        }
+    }
+
+    pub fn min_sp(&self) -> u16 {
+        self.min_sp
+    }
+
+    pub fn frames(&self) -> &[CallFrame] {
+        self.calls.frames()
+    }
+
+    pub fn usage(&self, function: u32) -> Option<StackUsage> {
+        self.usage.get(&function).copied()
+    }
+
+    // Lowest SP and the stack used by each function, deepest first
+    pub fn report(&self, symbols: &SymbolMap) -> String {
+        let mut report = format!("Stack: lowest SP {:#06x}, {} bytes used", self.min_sp, RAMEND.saturating_sub(self.min_sp));
+        if let Some(guard) = self.guard {
+            report += &format!(", {} bytes above the guard at {:#06x}", self.min_sp.saturating_add(1).saturating_sub(guard), guard);
+        }
+        report += &format!("\n\n{:>6} {:>10}  function\n", "depth", "lowest SP");
+
+        let mut functions: Vec<(&u32, &StackUsage)> = self.usage.iter().collect();
+        functions.sort_by_key(|(function, usage)| (std::cmp::Reverse(usage.depth), **function));
+        for (function, usage) in functions {
+            report += &format!("{:>6} {:>10}  {}\n", usage.depth, format!("{:#06x}", usage.lowest_sp), location(symbols, *function));
+        }
+
+        report
+    }
+}
+
+// Tests
+#[cfg(test)]
+mod tests {
+    use crate::avrcore::{Avrcore, RAMEND};
+    use crate::listing::SymbolMap;
+    use crate::stack::StackMonitor;
+
+    #[test]
+    fn recursion_overflows() {
+        // main: ldi r16, 0x55; f: push r16; call f
+        let progmem = [0x05, 0xE5, 0x0F, 0x93, 0x0E, 0x94, 0x01, 0x00];
+        let mut core = Avrcore::from_bytes(&progmem);
+        assert_eq!(core.sp.current_addr(), RAMEND);
+        let mut monitor = StackMonitor::new(&core, Some(RAMEND - 0x0F));
+
+        // Each level takes 3 bytes, the sixth call pushes into the guard
+        let overflow = loop {
+            let state = monitor.before(&core);
+            core.execute().unwrap();
+            if let Err(overflow) = monitor.after(state, &core) {
+                break overflow
+            }
+        };
+        assert_eq!((overflow.pc, overflow.sp, overflow.frames.len()), (4, RAMEND - 18, 7));
+        assert_eq!(monitor.min_sp(), RAMEND - 18);
+        assert_eq!(monitor.usage(2).unwrap().depth, 3);
+
+        let symbols = SymbolMap::parse_nm("00000000 T main\n00000002 T f\n");
+        let text = overflow.describe(&symbols);
+        assert!(text.starts_with("Stack overflow: SP 0x08ed set by the instruction at 0x4 is below the guard at 0x08f0\n  #0   0x0004 in f+0x2\n"));
+        assert!(text.ends_with("  #6   0x0004 in f+0x2\n"));
+        assert!(monitor.report(&symbols).starts_with("Stack: lowest SP 0x08ed, 18 bytes used, 0 bytes above the guard at 0x08f0\n"));
+    }
+
+    #[test]
+    fn call_return_call() {
+        // main: rcall f; call g; rjmp .-2; f: push r16; pop r16; ret; g: ret
+        let progmem = [0x03, 0xD0, 0x0E, 0x94, 0x07, 0x00, 0xFF, 0xCF, 0x0F, 0x93, 0x0F, 0x91, 0x08, 0x95, 0x08, 0x95];
+        let mut core = Avrcore::from_bytes(&progmem);
+        let mut monitor = StackMonitor::new(&core, None);
+
+        let mut depths = Vec::new();
+        for _ in 0..7 {
+            let state = monitor.before(&core);
+            core.execute().unwrap();
+            monitor.after(state, &core).unwrap();
+            depths.push(monitor.frames().len());
+        }
+        assert_eq!(depths, vec![2, 2, 2, 1, 2, 1, 1]);
+        assert_eq!((monitor.usage(0x08).unwrap().depth, monitor.usage(0x0E).unwrap().depth), (3, 2));
+        assert_eq!(core.sp.current_addr(), RAMEND);
+
+        // SP wrapping at the 16 bit edge
+        core.pc = 2;
+        core.sp.SPH = 0x00;
+        core.sp.SPL = 0x01;
+        let mut monitor = StackMonitor::new(&core, Some(0));
+        let state = monitor.before(&core);
+        core.execute().unwrap();
+        assert!(monitor.after(state, &core).is_ok());
+        assert_eq!(monitor.frames()[1].sp, Some(0x0001));
+
+        core.sp.SPH = 0xFF;
+        core.sp.SPL = 0xFF;
+        let monitor = StackMonitor::new(&core, Some(0));
+        assert!(monitor.report(&SymbolMap::default()).starts_with("Stack: lowest SP 0xffff"));
+    }
+}
@@ -0,0 +1,341 @@
+use crate::avrcore::Avrcore;
+use crate::disassembler;
+use crate::error::LoadError;
+use crate::history::Access;
+use crate::instructions::Instruction;
+use crate::interrupts::Interrupt;
+use crate::snapshot::Reader;
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+use std::ops::Range;
+
+// Start of a binary trace, followed by the format version
+const MAGIC: &[u8; 8] = b"AVRTRACE";
+
+pub const VERSION: u16 = 1;
+
+// Data space addresses of SPL, SPH and SREG, reported as their own effects
+const SP_SREG: Range<u16> = 0x5D..0x60;
+
+// Registers and I/O space, which peripherals also change without going
+// through the journal. Changes to SRAM are taken from the journal.
+const IO_END: u16 = 0x100;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum TraceFormat {
+    Text,
+    Binary,
+}
+
+impl TraceFormat {
+    pub fn from_name(name: &str) -> Option<TraceFormat> {
+        match name {
+            "text" => Some(TraceFormat::Text),
+            "binary" => Some(TraceFormat::Binary),
+            _ => None
+        }
+    }
+}
+
+// A state change made by one instruction
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Effect {
+    Register { index: u8, old: u8, new: u8 },
+    Sreg { old: u8, new: u8 },
+    Sp { old: u16, new: u16 },
+    Memory { address: u16, old: u8, new: u8 }, // Data space above the register file
+}
+
+impl Effect {
+    // Kind, location, old and new value as stored in a binary trace
+    fn to_fields(self) -> (u8, u16, u16, u16) {
+        match self {
+            Effect::Register { index, old, new } => (0, index as u16, old as u16, new as u16),
+            Effect::Sreg { old, new } => (1, 0, old as u16, new as u16),
+            Effect::Sp { old, new } => (2, 0, old, new),
+            Effect::Memory { address, old, new } => (3, address, old as u16, new as u16),
+        }
+    }
+
+    fn from_fields(kind: u8, location: u16, old: u16, new: u16) -> Option<Effect> {
+        match kind {
+            0 => Some(Effect::Register { index: location as u8, old: old as u8, new: new as u8 }),
+            1 => Some(Effect::Sreg { old: old as u8, new: new as u8 }),
+            2 => Some(Effect::Sp { old, new }),
+            3 => Some(Effect::Memory { address: location, old: old as u8, new: new as u8 }),
+            _ => None
+        }
+    }
+}
+
+// One executed instruction
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceRecord {
+    pub cycles: u64, // Cycle counter before the instruction
+    pub pc: u16,
+    pub words: Vec<u16>, // Raw instruction, one or two words
+    pub interrupt: Option<Interrupt>, // Serviced right before the instruction
+    pub effects: Vec<Effect>,
+}
+
+impl TraceRecord {
+    // `cycles pc: words mnemonic operands ; effects`
+    pub fn to_text(&self) -> String {
+        let bytes: Vec<u8> = self.words.iter().flat_map(|word| word.to_le_bytes()).collect();
+        let (mnemonic, operands) = match disassembler::decode_at(&bytes, 0) {
+            Some(opcode) => {
+                let (mnemonic, operands, _) = opcode.objdump();
+                (mnemonic, operands.trim_end().to_string())
+            },
+            None => (String::from(".word"), format!("{:#06x}", self.words[0]))
+        };
+        let words: Vec<String> = self.words.iter().map(|word| format!("{:04x}", word)).collect();
+
+        let mut effects: Vec<String> = self.interrupt.iter().map(|irq| format!("{} interrupt", irq.name())).collect();
+        effects.extend(self.effects.iter().map(|effect| match effect {
+            Effect::Register { index, old, new } => format!("r{} {:02x}->{:02x}", index, old, new),
+            Effect::Sreg { old, new } => format!("SREG {:02x}->{:02x}", old, new),
+            Effect::Sp { old, new } => format!("SP {:04x}->{:04x}", old, new),
+            Effect::Memory { address, old, new } => format!("[{:04x}] {:02x}->{:02x}", address, old, new),
+        }));
+
+        let mut line = format!("{:>8} {:>5x}:\t{:<9}\t{}\t{}", self.cycles, self.pc, words.join(" "), mnemonic, operands);
+        if !effects.is_empty() {
+            line += &format!("\t; {}", effects.join(", "));
+        }
+        line
+    }
+
+    // u64 cycles, u16 pc, u8 flags (bit 0: two words, bit 1: interrupt), u8 vector
+    // if an interrupt was serviced, the words, u8 effect count and per effect
+    // u8 kind, u16 location, u16 old and u16 new value. All little endian.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let flags = (self.words.len() == 2) as u8 | (self.interrupt.is_some() as u8) << 1;
+
+        let mut out = self.cycles.to_le_bytes().to_vec();
+        out.extend_from_slice(&self.pc.to_le_bytes());
+        out.push(flags);
+        if let Some(irq) = self.interrupt {
+            out.push(irq.vector());
+        }
+        for word in self.words.iter() {
+            out.extend_from_slice(&word.to_le_bytes());
+        }
+
+        out.push(self.effects.len() as u8);
+        for effect in self.effects.iter() {
+            let (kind, location, old, new) = effect.to_fields();
+            out.push(kind);
+            out.extend_from_slice(&location.to_le_bytes());
+            out.extend_from_slice(&old.to_le_bytes());
+            out.extend_from_slice(&new.to_le_bytes());
+        }
+
+        out
+    }
+}
+
+// Parse a binary trace as written by a `Tracer`
+pub fn read_binary(bytes: &[u8]) -> Result<Vec<TraceRecord>, LoadError> {
+    let mut reader = Reader::new(bytes, "Trace");
+
+    if reader.take(MAGIC.len())? != MAGIC {
+        return Err(LoadError::Format(String::from("Not an avrsim trace")))
+    }
+    let version = reader.u16()?;
+    if version != VERSION {
+        return Err(LoadError::Format(format!("Unsupported trace version {}, expected {}", version, VERSION)))
+    }
+
+    let mut records = Vec::new();
+    while !reader.at_end() {
+        let cycles = reader.u64()?;
+        let pc = reader.u16()?;
+        let flags = reader.u8()?;
+
+        let interrupt = match flags & 0b10 {
+            0 => None,
+            _ => {
+                let vector = reader.u8()?;
+                Some(Interrupt::from_vector(vector).ok_or(LoadError::Format(format!("Invalid interrupt vector {}", vector)))?)
+            }
+        };
+
+        let mut words = vec![reader.u16()?];
+        if flags & 1 != 0 {
+            words.push(reader.u16()?);
+        }
+
+        let mut effects = Vec::new();
+        for _ in 0..reader.u8()? {
+            let kind = reader.u8()?;
+            let (location, old, new) = (reader.u16()?, reader.u16()?, reader.u16()?);
+            effects.push(Effect::from_fields(kind, location, old, new)
+                .ok_or(LoadError::Format(format!("Invalid effect kind {}", kind)))?);
+        }
+
+        records.push(TraceRecord { cycles, pc, words, interrupt, effects });
+    }
+
+    Ok(records)
+}
+
+// State an instruction can change, captured before it executes
+pub struct TraceState {
+    cycles: u64,
+    pc: u16,
+    interrupt: Option<Interrupt>,
+    sreg: u8,
+    sp: u16,
+    io: Vec<u8>, // Data space below IO_END
+}
+
+impl TraceState {
+    // Effects of the instruction executed since the capture, given the data
+    // space writes it journaled
+    fn record(self, core: &Avrcore, journal: &[Access]) -> TraceRecord {
+        let mut before: BTreeMap<u16, u8> = (0..IO_END).map(|address| (address, self.io[address as usize])).collect();
+        for access in journal {
+            if let Access::Write(address, old) = *access {
+                before.entry(address).or_insert(old);
+            }
+        }
+
+        let mut effects = Vec::new();
+        for (address, old) in before {
+            let new = core.read_data(address);
+            if old == new || SP_SREG.contains(&address) {
+                continue
+            }
+
+            effects.push(if address < 32 {
+                Effect::Register { index: address as u8, old, new }
+            } else {
+                Effect::Memory { address, old, new }
+            });
+        }
+        if self.sreg != core.sreg.to_byte() {
+            effects.push(Effect::Sreg { old: self.sreg, new: core.sreg.to_byte() });
+        }
+        if self.sp != core.sp.current_addr() {
+            effects.push(Effect::Sp { old: self.sp, new: core.sp.current_addr() });
+        }
+
+        let size = core.flash.get(&(self.pc as usize)).map_or(2, |opcode| opcode.size() as usize);
+        let words = (0..size / 2)
+            .map(|idx| {
+                let addr = self.pc as usize + idx * 2;
+                let byte = |addr: usize| core.progmem.get(addr).copied().unwrap_or(0xFF) as u16;
+                byte(addr + 1) << 8 | byte(addr)
+            })
+            .collect();
+
+        TraceRecord { cycles: self.cycles, pc: self.pc, words, interrupt: self.interrupt, effects }
+    }
+}
+
+// Writes a record for every executed instruction inside the filter ranges
+pub struct Tracer {
+    format: TraceFormat,
+    filters: Vec<Range<u32>>, // Byte addresses in flash, empty traces everything
+    out: Box<dyn Write>,
+}
+
+impl Tracer {
+    pub fn new(mut out: Box<dyn Write>, format: TraceFormat) -> io::Result<Tracer> {
+        if format == TraceFormat::Binary {
+            out.write_all(MAGIC)?;
+            out.write_all(&VERSION.to_le_bytes())?;
+        }
+
+        Ok(Tracer { format, filters: Vec::new(), out })
+    }
+
+    // Only trace instructions in `range`. Can be given several times.
+    pub fn filter(&mut self, range: Range<u32>) {
+        self.filters.push(range);
+    }
+
+    // Capture the state before the next instruction. None while the core sleeps
+    // or when the instruction is filtered out.
+    pub fn before(&self, core: &mut Avrcore) -> Option<TraceState> {
+        if core.sleep.is_some() {
+            return None
+        }
+
+        let pc = core.next_pc();
+        if !self.filters.is_empty() && !self.filters.iter().any(|range| range.contains(&(pc as u32))) {
+            return None
+        }
+
+        // Memory writes are journaled until `after`
+        core.journal = Some(Vec::new());
+
+        Some(TraceState {
+            cycles: core.cycles,
+            pc,
+            interrupt: core.next_interrupt(),
+            sreg: core.sreg.to_byte(),
+            sp: core.sp.current_addr(),
+            io: (0..IO_END).map(|address| core.read_data(address)).collect(),
+        })
+    }
+
+    // Write the record of the instruction executed since `before`
+    pub fn after(&mut self, state: TraceState, core: &mut Avrcore) -> io::Result<()> {
+        let journal = core.journal.take().unwrap_or_default();
+        let record = state.record(core, &journal);
+
+        match self.format {
+            TraceFormat::Text => writeln!(self.out, "{}", record.to_text()),
+            TraceFormat::Binary => self.out.write_all(&record.to_bytes()),
+        }
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+// Tests
+#[cfg(test)]
+mod tests {
+    use crate::simulator::Simulator;
+    use crate::testutil::Shared;
+    use crate::trace::{read_binary, Effect, TraceFormat, Tracer};
+
+    fn run(format: TraceFormat, filter: Option<std::ops::Range<u32>>) -> Vec<u8> {
+        let mut sim = Simulator::builder().image_file("testprogram.bin").build().unwrap();
+        let main = sim.symbol("main").unwrap() as u16;
+        let output = Shared::default();
+        let mut tracer = Tracer::new(Box::new(output.clone()), format).unwrap();
+        if let Some(range) = filter {
+            tracer.filter(range);
+        }
+
+        while sim.pc() != main + 4 {
+            let state = tracer.before(&mut sim.core);
+            sim.step().unwrap();
+            if let Some(state) = state {
+                tracer.after(state, &mut sim.core).unwrap();
+            }
+        }
+
+        output.0.take()
+    }
+
+    #[test]
+    fn text_and_binary() {
+        let text = String::from_utf8(run(TraceFormat::Text, None)).unwrap();
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines[0], "       0     0:\t940c 0034\tjmp\t0x68");
+        assert!(lines.last().unwrap().contains("push\tr29\t; [08fc] 00->08, SP 08fc->08fb"));
+
+        let records = read_binary(&run(TraceFormat::Binary, Some(0x80..0x100))).unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].pc, 0x80);
+        assert_eq!(records[0].words, vec![0x93CF]);
+        assert_eq!(records[0].effects, vec![Effect::Memory { address: 0x08FD, old: 0, new: 0xFF }, Effect::Sp { old: 0x08FD, new: 0x08FC }]);
+        assert_eq!(read_binary(b"AVRTRACE\x01\x00\x00").map_err(|err| err.to_string()).unwrap_err(), "Trace is truncated");
+    }
+}
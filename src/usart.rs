@@ -0,0 +1,43 @@
+use crate::avrcore::Avrcore;
+
+// USART0 registers (memory mapped)
+pub const UCSR0A: u16 = 0xC0;
+pub const UDR0: u16 = 0xC6;
+const UCSR0A_TXC0: u8 = 6;
+const UCSR0A_UDRE0: u8 = 5;
+
+impl Avrcore {
+    // Writing UDR0 transmits a byte. Transmission completes at once, so the
+    // data register is empty again and TXC0 is set.
+    pub fn write_udr0(&mut self, value: u8) {
+        self.extio[(UDR0 - 0x60) as usize] = value;
+        self.extio[(UCSR0A - 0x60) as usize] |= 1 << UCSR0A_TXC0 | 1 << UCSR0A_UDRE0;
+
+        self.uart_tx.push(value);
+    }
+
+    // Bytes transmitted since the last call
+    pub fn take_uart_output(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.uart_tx)
+    }
+}
+
+// Tests
+#[cfg(test)]
+mod tests {
+    use crate::avrcore::Avrcore;
+    use crate::usart::{UCSR0A, UDR0};
+    use std::collections::HashMap;
+
+    #[test]
+    fn transmit() {
+        let mut core = Avrcore::new(HashMap::new());
+
+        core.write_data(UDR0, b'H');
+        core.write_data(UDR0, b'i');
+
+        assert_eq!(core.take_uart_output(), b"Hi");
+        assert_eq!(core.read_data(UCSR0A), 0x60);
+        assert!(core.take_uart_output().is_empty());
+    }
+}
@@ -0,0 +1,268 @@
+use crate::avrcore::Avrcore;
+use crate::interrupts::Interrupt;
+use std::io::{self, Write};
+
+// Data space addresses of the ATmega328P I/O registers that can be recorded by name
+const REGISTERS: [(&str, u16); 72] = [
+    ("PINB", 0x23), ("DDRB", 0x24), ("PORTB", 0x25),
+    ("PINC", 0x26), ("DDRC", 0x27), ("PORTC", 0x28),
+    ("PIND", 0x29), ("DDRD", 0x2A), ("PORTD", 0x2B),
+    ("TIFR0", 0x35), ("TIFR1", 0x36), ("TIFR2", 0x37),
+    ("PCIFR", 0x3B), ("EIFR", 0x3C), ("EIMSK", 0x3D), ("GPIOR0", 0x3E),
+    ("EECR", 0x3F), ("EEDR", 0x40), ("EEARL", 0x41), ("EEARH", 0x42),
+    ("GTCCR", 0x43), ("TCCR0A", 0x44), ("TCCR0B", 0x45), ("TCNT0", 0x46),
+    ("OCR0A", 0x47), ("OCR0B", 0x48), ("GPIOR1", 0x4A), ("GPIOR2", 0x4B),
+    ("SPCR", 0x4C), ("SPSR", 0x4D), ("SPDR", 0x4E), ("ACSR", 0x50),
+    ("SMCR", 0x53), ("MCUSR", 0x54), ("MCUCR", 0x55), ("SPMCSR", 0x57),
+    ("SPL", 0x5D), ("SPH", 0x5E), ("SREG", 0x5F),
+    ("WDTCSR", 0x60), ("CLKPR", 0x61), ("PRR", 0x64), ("OSCCAL", 0x66),
+    ("PCICR", 0x68), ("EICRA", 0x69), ("PCMSK0", 0x6B), ("PCMSK1", 0x6C), ("PCMSK2", 0x6D),
+    ("TIMSK0", 0x6E), ("TIMSK1", 0x6F), ("TIMSK2", 0x70),
+    ("ADCL", 0x78), ("ADCH", 0x79), ("ADCSRA", 0x7A), ("ADCSRB", 0x7B), ("ADMUX", 0x7C),
+    ("TCCR1A", 0x80), ("TCCR1B", 0x81), ("TCNT1L", 0x84), ("TCNT1H", 0x85),
+    ("OCR1AL", 0x88), ("OCR1AH", 0x89),
+    ("TCCR2A", 0xB0), ("TCCR2B", 0xB1), ("TCNT2", 0xB2), ("OCR2A", 0xB3), ("OCR2B", 0xB4),
+    ("UCSR0A", 0xC0), ("UCSR0B", 0xC1), ("UCSR0C", 0xC2), ("UBRR0L", 0xC4), ("UDR0", 0xC6),
+];
+
+// Data space addresses of PORTx for ports B, C and D. DDRx is one below.
+const PORTS: [(char, u16, u8); 3] = [('B', 0x25, 8), ('C', 0x28, 7), ('D', 0x2B, 8)];
+
+const SREG_FLAGS: &str = "CZNVSHTI";
+
+// Something recorded in a VCD file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Signal {
+    Pin { port: char, bit: u8 }, // Driven level of e.g. PB5
+    Register { name: String, address: u16 }, // Any byte of the data space
+    Flag(u8), // SREG bit
+    Interrupt(Interrupt), // High while the interrupt is pending
+}
+
+impl Signal {
+    // `PB5`, `SREG.I`, a register name such as `UDR0`, an interrupt such as
+    // `TIMER0_OVF` or a data space address
+    pub fn parse(spec: &str) -> Option<Signal> {
+        let upper = spec.to_ascii_uppercase();
+
+        if let Some(flag) = upper.strip_prefix("SREG.") {
+            let bit = SREG_FLAGS.find(flag).filter(|_| flag.len() == 1)?;
+            return Some(Signal::Flag(bit as u8))
+        }
+
+        let mut chars = upper.chars();
+        if let (Some('P'), Some(port), Some(bit), None) = (chars.next(), chars.next(), chars.next(), chars.next()) {
+            if let (Some((_, _, pins)), Some(bit)) = (PORTS.iter().find(|(name, _, _)| *name == port), bit.to_digit(10)) {
+                return if bit < *pins as u32 { Some(Signal::Pin { port, bit: bit as u8 }) } else { None }
+            }
+        }
+
+        if let Some((name, address)) = REGISTERS.iter().find(|(name, _)| *name == upper) {
+            return Some(Signal::Register { name: name.to_string(), address: *address })
+        }
+        if let Some(irq) = Interrupt::from_name(&upper) {
+            return Some(Signal::Interrupt(irq))
+        }
+
+        let address = match spec.strip_prefix("0x").or_else(|| spec.strip_prefix("0X")) {
+            Some(digits) => u16::from_str_radix(digits, 16).ok()?,
+            None => spec.parse().ok()?
+        };
+        Some(Signal::Register { name: format!("mem_{:04x}", address), address })
+    }
+
+    // Every pin of ports B, C and D followed by the SREG flags
+    pub fn defaults() -> Vec<Signal> {
+        let pins = PORTS.iter().flat_map(|&(port, _, pins)| (0..pins).map(move |bit| Signal::Pin { port, bit }));
+        pins.chain((0..8).rev().map(Signal::Flag)).collect()
+    }
+
+    fn name(&self) -> String {
+        match self {
+            Signal::Pin { port, bit } => format!("P{}{}", port, bit),
+            Signal::Register { name, .. } => name.clone(),
+            Signal::Flag(bit) => format!("SREG_{}", &SREG_FLAGS[*bit as usize..*bit as usize + 1]),
+            Signal::Interrupt(irq) => irq.name().to_string(),
+        }
+    }
+
+    fn scope(&self) -> &'static str {
+        match self {
+            Signal::Pin { .. } => "pins",
+            Signal::Register { .. } => "registers",
+            Signal::Flag(_) => "sreg",
+            Signal::Interrupt(_) => "interrupts",
+        }
+    }
+
+    fn width(&self) -> u8 {
+        match self {
+            Signal::Register { .. } => 8,
+            _ => 1
+        }
+    }
+
+    // Current value, None for an undriven pin
+    fn value(&self, core: &Avrcore) -> Option<u8> {
+        match self {
+            Signal::Pin { port, bit } => {
+                let (_, portx, _) = PORTS.iter().find(|(name, _, _)| name == port)?;
+                let (ddr, level) = (core.read_data(portx - 1) >> bit & 1, core.read_data(*portx) >> bit & 1);
+
+                // An input with the pull-up enabled reads high, without it floats
+                match (ddr, level) {
+                    (1, level) => Some(level),
+                    (_, 1) => Some(1),
+                    _ => None
+                }
+            },
+            Signal::Register { address, .. } => Some(core.read_data(*address)),
+            Signal::Flag(bit) => Some(core.sreg.to_byte() >> bit & 1),
+            Signal::Interrupt(irq) => Some((core.pending_interrupts >> irq.vector() & 1) as u8),
+        }
+    }
+}
+
+// Short identifier VCD uses for the signal at `idx`
+fn identifier(mut idx: usize) -> String {
+    let mut id = String::new();
+    loop {
+        id.push((b'!' + (idx % 94) as u8) as char);
+        idx /= 94;
+        if idx == 0 {
+            return id
+        }
+        idx -= 1;
+    }
+}
+
+fn format_value(width: u8, value: Option<u8>, id: &str) -> String {
+    match (width, value) {
+        (1, Some(value)) => format!("{}{}", value, id),
+        (1, None) => format!("z{}", id),
+        (_, Some(value)) => format!("b{:b} {}", value, id),
+        (_, None) => format!("bz {}", id),
+    }
+}
+
+// Writes the chosen signals as a Value Change Dump against simulated time,
+// for viewing in e.g. GTKWave
+pub struct VcdWriter {
+    out: Box<dyn Write>,
+    signals: Vec<Signal>,
+    values: Vec<Option<u8>>, // Last written value of every signal
+    cycles: u64, // Cycle counter at the last sample
+    time_ps: u64,
+    remainder: u128, // Fraction of a picosecond carried between samples
+}
+
+impl VcdWriter {
+    // Write the header and the values at time 0
+    pub fn new(mut out: Box<dyn Write>, signals: Vec<Signal>, core: &Avrcore) -> io::Result<VcdWriter> {
+        writeln!(out, "$version avrsim {} $end", env!("CARGO_PKG_VERSION"))?;
+        writeln!(out, "$timescale 1ps $end")?;
+        writeln!(out, "$scope module atmega328p $end")?;
+
+        for scope in ["pins", "registers", "sreg", "interrupts"] {
+            let members: Vec<usize> = (0..signals.len()).filter(|&idx| signals[idx].scope() == scope).collect();
+            if members.is_empty() {
+                continue
+            }
+
+            writeln!(out, "$scope module {} $end", scope)?;
+            for idx in members {
+                let kind = if signals[idx].width() == 1 { "wire" } else { "reg" };
+                writeln!(out, "$var {} {} {} {} $end", kind, signals[idx].width(), identifier(idx), signals[idx].name())?;
+            }
+            writeln!(out, "$upscope $end")?;
+        }
+        writeln!(out, "$upscope $end")?;
+        writeln!(out, "$enddefinitions $end")?;
+
+        let values: Vec<Option<u8>> = signals.iter().map(|signal| signal.value(core)).collect();
+        writeln!(out, "#0")?;
+        writeln!(out, "$dumpvars")?;
+        for (idx, signal) in signals.iter().enumerate() {
+            writeln!(out, "{}", format_value(signal.width(), values[idx], &identifier(idx)))?;
+        }
+        writeln!(out, "$end")?;
+
+        Ok(VcdWriter { out, signals, values, cycles: core.cycles, time_ps: 0, remainder: 0 })
+    }
+
+    // Advance the time by the cycles executed since the last sample, at the
+    // current system clock, and write the signals that changed
+    pub fn sample(&mut self, core: &Avrcore) -> io::Result<()> {
+        let elapsed = (core.cycles - self.cycles) as u128 * 1_000_000_000_000 + self.remainder;
+        let hz = core.clock_hz() as u128;
+        self.time_ps += (elapsed / hz) as u64;
+        self.remainder = elapsed % hz;
+        self.cycles = core.cycles;
+
+        let mut stamped = false;
+        for (idx, signal) in self.signals.iter().enumerate() {
+            let value = signal.value(core);
+            if value == self.values[idx] {
+                continue
+            }
+
+            if !stamped {
+                writeln!(self.out, "#{}", self.time_ps)?;
+                stamped = true;
+            }
+            writeln!(self.out, "{}", format_value(signal.width(), value, &identifier(idx)))?;
+            self.values[idx] = value;
+        }
+
+        Ok(())
+    }
+
+    // Mark the end of the simulation so viewers show the final values
+    pub fn finish(&mut self, core: &Avrcore) -> io::Result<()> {
+        self.sample(core)?;
+        writeln!(self.out, "#{}", self.time_ps)?;
+        self.out.flush()
+    }
+}
+
+// Tests
+#[cfg(test)]
+mod tests {
+    use crate::avrcore::Avrcore;
+    use crate::interrupts::Interrupt;
+    use crate::testutil::Shared;
+    use crate::vcd::{identifier, Signal, VcdWriter};
+    use std::collections::HashMap;
+
+    #[test]
+    fn signals_and_changes() {
+        assert_eq!(Signal::parse("pb5"), Some(Signal::Pin { port: 'B', bit: 5 }));
+        assert_eq!(Signal::parse("PC7"), None);
+        assert_eq!(Signal::parse("SREG.I"), Some(Signal::Flag(7)));
+        assert_eq!(Signal::parse("udr0"), Some(Signal::Register { name: String::from("UDR0"), address: 0xC6 }));
+        assert_eq!(Signal::parse("TIMER0_OVF"), Some(Signal::Interrupt(Interrupt::Timer0Ovf)));
+        assert_eq!(Signal::parse("0x100"), Some(Signal::Register { name: String::from("mem_0100"), address: 0x100 }));
+        assert_eq!((identifier(0), identifier(93), identifier(94)), (String::from("!"), String::from("~"), String::from("!!")));
+
+        // 16 MHz external clock without the CKDIV8 prescaler, 62.5 ns per cycle
+        let mut core = Avrcore::new(HashMap::new());
+        core.fuses.low = 0xFF;
+        core.extio[0x01] = 0x00;
+        assert_eq!(core.clock_hz(), 16_000_000);
+        let output = Shared::default();
+        let signals = vec![Signal::parse("PB5").unwrap(), Signal::parse("PORTB").unwrap(), Signal::parse("INT0").unwrap()];
+        let mut vcd = VcdWriter::new(Box::new(output.clone()), signals, &core).unwrap();
+
+        core.write_data(0x24, 0x20);
+        core.cycles += 3;
+        vcd.sample(&core).unwrap();
+        core.write_data(0x25, 0x20);
+        core.raise_interrupt(Interrupt::Int0);
+        core.cycles += 1;
+        vcd.finish(&core).unwrap();
+
+        let text = String::from_utf8(output.0.take()).unwrap();
+        assert!(text.contains("$scope module pins $end\n$var wire 1 ! PB5 $end\n$upscope $end"));
+        assert!(text.contains("$dumpvars\nz!\nb0 \"\n0#\n$end\n#187500\n0!\n#250000\n1!\nb100000 \"\n1#\n#250000\n"));
+    }
+}
@@ -0,0 +1,114 @@
+use crate::avrcore::Avrcore;
+use crate::history::Access;
+use std::ops::Range;
+
+// Accesses a watchpoint reacts to
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum WatchKind {
+    Read,
+    Write,
+    Access, // Reads and writes
+}
+
+impl WatchKind {
+    fn matches(&self, write: bool) -> bool {
+        match self {
+            WatchKind::Read => !write,
+            WatchKind::Write => write,
+            WatchKind::Access => true,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Watchpoint {
+    pub id: u32,
+    pub range: Range<u16>, // Data space addresses
+    pub kind: WatchKind,
+}
+
+// An instruction touched a watched address
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct WatchHit {
+    pub id: u32, // Watchpoint that matched
+    pub kind: WatchKind, // Kind of that watchpoint
+    pub pc: u16, // Instruction making the access, or the interrupted one for a vector push
+    pub address: u16,
+    pub write: bool,
+    pub old: u8,
+    pub new: u8, // Same as `old` for reads
+}
+
+impl Avrcore {
+    pub fn add_watchpoint(&mut self, range: Range<u16>, kind: WatchKind) -> u32 {
+        let id = self.watchpoints.iter().map(|watch| watch.id + 1).max().unwrap_or(1);
+
+        self.watchpoints.push(Watchpoint { id, range, kind });
+        id
+    }
+
+    pub fn remove_watchpoint(&mut self, id: u32) -> bool {
+        let before = self.watchpoints.len();
+        self.watchpoints.retain(|watch| watch.id != id);
+
+        self.watchpoints.len() != before
+    }
+
+    // Data space read made by the program, checked against the watchpoints
+    pub fn load(&mut self, addr: u16) -> u8 {
+        let value = self.read_data(addr);
+        self.journal(Access::Read(addr));
+        self.check_watchpoints(addr, false, value, value);
+
+        value
+    }
+
+    // Data space write made by the program, checked against the watchpoints
+    pub fn store(&mut self, addr: u16, value: u8) {
+        let old = self.read_data(addr);
+        self.write_data(addr, value);
+        self.check_watchpoints(addr, true, old, value);
+    }
+
+    // Collected hits are handled by the caller after the instruction completes
+    fn check_watchpoints(&mut self, address: u16, write: bool, old: u8, new: u8) {
+        let hits = self.match_watchpoints(self.pc, address, write, old, new);
+        self.watch_hits.extend(hits);
+    }
+
+    // Hits an access made by the instruction at `pc` causes
+    pub fn match_watchpoints(&self, pc: u16, address: u16, write: bool, old: u8, new: u8) -> Vec<WatchHit> {
+        self.watchpoints.iter()
+            .filter(|watch| watch.range.contains(&address) && watch.kind.matches(write))
+            .map(|watch| WatchHit { id: watch.id, kind: watch.kind, pc, address, write, old, new })
+            .collect()
+    }
+}
+
+// Tests
+#[cfg(test)]
+mod tests {
+    use crate::avrcore::Avrcore;
+    use crate::watch::{WatchHit, WatchKind};
+
+    #[test]
+    fn stack_writes() {
+        // ldi r16, 0x42; push r16; in r17, 0x3d
+        let progmem = [0x02, 0xE4, 0x0F, 0x93, 0x1D, 0xB7];
+        let mut core = Avrcore::from_bytes(&progmem);
+
+        let sp = core.sp.current_addr();
+        let stack = core.add_watchpoint(sp..sp + 1, WatchKind::Write);
+        let spl = core.add_watchpoint(0x5D..0x5E, WatchKind::Read);
+
+        core.execute().unwrap();
+        core.execute().unwrap();
+        assert_eq!(core.watch_hits, vec![WatchHit { id: stack, kind: WatchKind::Write, pc: 2, address: sp, write: true, old: 0, new: 0x42 }]);
+
+        core.watch_hits.clear();
+        assert!(core.remove_watchpoint(stack));
+        core.execute().unwrap();
+        assert_eq!(core.watch_hits.len(), 1);
+        assert_eq!(core.watch_hits[0].id, spl);
+    }
+}